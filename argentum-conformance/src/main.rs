@@ -0,0 +1,82 @@
+//! Headless runner for serial based conformance test ROMs.
+//!
+//! Blargg's tests print `Passed` or `Failed` over the serial port,
+//! while mooneye-gb's tests send the bytes `3, 5, 8, 13, 21, 34`
+//! on success and six `0x42` bytes on failure.
+//!
+//! Exit codes,
+//! 0 - The test passed.
+//! 1 - The test failed.
+//! 2 - No verdict was reached within the frame limit.
+
+use std::{cell::RefCell, path::PathBuf, process::exit, rc::Rc};
+
+use argentum::Argentum;
+use clap::Clap;
+
+const PKG_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Serial output sent by passing mooneye-gb tests.
+const MOONEYE_PASS: [u8; 6] = [3, 5, 8, 13, 21, 34];
+
+/// Serial output sent by failing mooneye-gb tests.
+const MOONEYE_FAIL: [u8; 6] = [0x42; 6];
+
+#[derive(Clap)]
+#[clap(name = "Argentum Conformance")]
+#[clap(version = PKG_VERSION, about = "Run a test ROM headless and report the result.")]
+struct Opt {
+    #[clap(parse(from_os_str))]
+    rom_file: PathBuf,
+
+    /// The maximum amount of frames to run the ROM for.
+    #[clap(short, long, default_value = "3600")]
+    frames: u32,
+}
+
+/// Check the serial output for a verdict.
+fn verdict(output: &[u8]) -> Option<bool> {
+    let text = String::from_utf8_lossy(output);
+
+    if text.contains("Passed") || output.ends_with(&MOONEYE_PASS) {
+        Some(true)
+    } else if text.contains("Failed") || output.ends_with(&MOONEYE_FAIL) {
+        Some(false)
+    } else {
+        None
+    }
+}
+
+fn main() {
+    let opt: Opt = Opt::parse();
+    let rom = std::fs::read(&opt.rom_file).expect("failed to read the ROM file");
+
+    let mut argentum = Argentum::new(&rom, Box::new(|_| {}), None);
+    argentum.skip_bootrom();
+
+    // Collect everything the ROM sends over the serial port.
+    let output = Rc::new(RefCell::new(Vec::new()));
+    let sink = Rc::clone(&output);
+
+    argentum.set_serial_callback(Box::new(move |byte| sink.borrow_mut().push(byte)));
+
+    for _ in 0..opt.frames {
+        argentum.execute_frame();
+
+        if let Some(passed) = verdict(&output.borrow()) {
+            print!("{}", String::from_utf8_lossy(&output.borrow()));
+
+            if passed {
+                println!("PASS");
+                exit(0);
+            } else {
+                println!("FAIL");
+                exit(1);
+            }
+        }
+    }
+
+    print!("{}", String::from_utf8_lossy(&output.borrow()));
+    println!("TIMEOUT");
+    exit(2);
+}
@@ -0,0 +1,72 @@
+//! Run the conformance runner against tiny test ROMs which
+//! report a known result over the serial port.
+
+use std::{path::PathBuf, process::Command};
+
+/// Build a ROM which sends `output` over the serial port and then loops forever.
+fn serial_rom(output: &[u8]) -> Vec<u8> {
+    let mut rom = vec![0; 0x8000];
+    let mut program = Vec::new();
+
+    for &byte in output {
+        // LD A, byte; LDH (SB), A; LD A, 0x81; LDH (SC), A
+        program.extend_from_slice(&[0x3E, byte, 0xE0, 0x01, 0x3E, 0x81, 0xE0, 0x02]);
+    }
+
+    // JR -2
+    program.extend_from_slice(&[0x18, 0xFE]);
+
+    rom[0x100..0x100 + program.len()].copy_from_slice(&program);
+    rom
+}
+
+/// Write the ROM to a temporary file, run the conformance runner
+/// on it and return its exit code.
+fn run(name: &str, rom: &[u8], frames: u32) -> Option<i32> {
+    let path: PathBuf = std::env::temp_dir().join(format!("argentum-conformance-{}.gb", name));
+    std::fs::write(&path, rom).expect("failed to write the test ROM");
+
+    let status = Command::new(env!("CARGO_BIN_EXE_conformance"))
+        .arg("--frames")
+        .arg(frames.to_string())
+        .arg(&path)
+        .status()
+        .expect("failed to run the conformance runner");
+
+    std::fs::remove_file(&path).ok();
+    status.code()
+}
+
+#[test]
+fn blargg_pass() {
+    assert_eq!(
+        run("blargg-pass", &serial_rom(b"cpu_instrs\n\nPassed\n"), 60),
+        Some(0)
+    );
+}
+
+#[test]
+fn blargg_fail() {
+    assert_eq!(
+        run("blargg-fail", &serial_rom(b"cpu_instrs\n\nFailed\n"), 60),
+        Some(1)
+    );
+}
+
+#[test]
+fn mooneye_pass() {
+    assert_eq!(
+        run("mooneye-pass", &serial_rom(&[3, 5, 8, 13, 21, 34]), 60),
+        Some(0)
+    );
+}
+
+#[test]
+fn mooneye_fail() {
+    assert_eq!(run("mooneye-fail", &serial_rom(&[0x42; 6]), 60), Some(1));
+}
+
+#[test]
+fn no_verdict() {
+    assert_eq!(run("no-verdict", &serial_rom(b"Running"), 10), Some(2));
+}
@@ -0,0 +1,244 @@
+//! Per-game settings, stored next to the ROM in a file named
+//! after the ROM's hash so that they survive renaming it.
+//!
+//! The file holds one `name = value` setting per line, settings
+//! which are missing or invalid fall back to their defaults.
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use argentum::{Argentum, ArgentumKey};
+
+/// The Game Boy keys in the order they are stored in `GameConfig::keys`,
+/// along with the names used for them in the config file.
+const KEY_NAMES: [&str; 8] = ["up", "down", "left", "right", "a", "b", "start", "select"];
+
+/// The largest window scale accepted from the config file.
+const MAX_SCALE: u32 = 10;
+
+/// How closely the hardware is emulated.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Accuracy {
+    /// The defaults of the core, which work for most games.
+    Fast,
+
+    /// Turn on all the accuracy options of the core.
+    Accurate,
+}
+
+/// Settings for a single game.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GameConfig {
+    /// The DMG mode palette, lightest first, each given as 0xRRGGBB.
+    /// The default palette of the core is used if this is `None`.
+    pub palette: Option<[u32; 4]>,
+
+    /// The initial size of the window, in multiples of the screen size,
+    /// from 1 to `MAX_SCALE`.
+    pub scale: u32,
+
+    /// The SDL scancode names the keys are mapped to.
+    pub keys: [String; 8],
+
+    /// How closely the hardware is emulated.
+    pub accuracy: Accuracy,
+}
+
+impl Default for GameConfig {
+    fn default() -> Self {
+        Self {
+            palette: None,
+            scale: 3,
+            keys: [
+                String::from("W"),
+                String::from("S"),
+                String::from("A"),
+                String::from("D"),
+                String::from("Z"),
+                String::from("X"),
+                String::from("Return"),
+                String::from("Space"),
+            ],
+            accuracy: Accuracy::Fast,
+        }
+    }
+}
+
+impl GameConfig {
+    /// Get the path of the config file for the ROM at `rom_path`.
+    pub fn path(rom_path: &Path, rom_hash: u32) -> PathBuf {
+        rom_path.with_file_name(format!("{:08X}.cfg", rom_hash))
+    }
+
+    /// Load the config file at `path`, or the defaults if there is none.
+    pub fn load(path: &Path) -> Self {
+        std::fs::read_to_string(path)
+            .map(|contents| Self::parse(&contents))
+            .unwrap_or_default()
+    }
+
+    /// Write the config to the file at `path`.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        std::fs::write(path, self.serialize())
+    }
+
+    /// Parse the contents of a config file.
+    pub fn parse(contents: &str) -> Self {
+        let mut config = Self::default();
+
+        for line in contents.lines() {
+            let (name, value) = match line.split_once('=') {
+                Some((name, value)) => (name.trim(), value.trim()),
+                None => continue,
+            };
+
+            match name {
+                "palette" => {
+                    let colours = value
+                        .split(',')
+                        .map(|colour| u32::from_str_radix(colour.trim(), 16).ok())
+                        .collect::<Option<Vec<u32>>>();
+
+                    if let Some(&[c0, c1, c2, c3]) = colours.as_deref() {
+                        config.palette = Some([c0, c1, c2, c3]);
+                    }
+                }
+
+                "scale" => match value.parse::<u32>() {
+                    Ok(scale) if (1..=MAX_SCALE).contains(&scale) => config.scale = scale,
+                    _ => {}
+                },
+
+                "accuracy" => match value {
+                    "fast" => config.accuracy = Accuracy::Fast,
+                    "accurate" => config.accuracy = Accuracy::Accurate,
+                    _ => {}
+                },
+
+                _ => {
+                    let key = name
+                        .strip_prefix("key.")
+                        .and_then(|key| KEY_NAMES.iter().position(|&n| n == key));
+
+                    if let Some(index) = key {
+                        config.keys[index] = value.to_string();
+                    }
+                }
+            }
+        }
+
+        config
+    }
+
+    /// Serialize the config into the format of the config file.
+    pub fn serialize(&self) -> String {
+        let mut contents = String::new();
+
+        if let Some(palette) = self.palette {
+            contents += &format!(
+                "palette = {:06X}, {:06X}, {:06X}, {:06X}\n",
+                palette[0], palette[1], palette[2], palette[3]
+            );
+        }
+
+        contents += &format!("scale = {}\n", self.scale);
+
+        for (name, scancode) in KEY_NAMES.iter().zip(self.keys.iter()) {
+            contents += &format!("key.{} = {}\n", name, scancode);
+        }
+
+        contents += match self.accuracy {
+            Accuracy::Fast => "accuracy = fast\n",
+            Accuracy::Accurate => "accuracy = accurate\n",
+        };
+
+        contents
+    }
+
+    /// Apply the palette and accuracy settings to the emulator.
+    pub fn apply(&self, argentum: &mut Argentum) {
+        if let Some(palette) = self.palette {
+            argentum.set_dmg_palette(palette);
+        }
+
+        let accurate = self.accuracy == Accuracy::Accurate;
+
+        argentum.set_oam_dma_accuracy(accurate);
+        argentum.set_unusable_reads_accuracy(accurate);
+        argentum.set_line_153_quirk(accurate);
+        argentum.set_joypad_latency(accurate);
+    }
+
+    /// Get the Game Boy key mapped to the scancode with the given name.
+    pub fn key(&self, scancode: &str) -> Option<ArgentumKey> {
+        let index = self.keys.iter().position(|key| key == scancode)?;
+
+        Some(match index {
+            0 => ArgentumKey::Up,
+            1 => ArgentumKey::Down,
+            2 => ArgentumKey::Left,
+            3 => ArgentumKey::Right,
+            4 => ArgentumKey::ButtonA,
+            5 => ArgentumKey::ButtonB,
+            6 => ArgentumKey::Start,
+            _ => ArgentumKey::Select,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_round_trips_through_a_file() {
+        let config = GameConfig {
+            palette: Some([0xE0F8D0, 0x88C070, 0x346856, 0x081820]),
+            scale: 5,
+            accuracy: Accuracy::Accurate,
+            ..GameConfig::default()
+        };
+
+        assert_eq!(GameConfig::parse(&config.serialize()), config);
+        assert_eq!(
+            GameConfig::parse(&GameConfig::default().serialize()),
+            GameConfig::default()
+        );
+
+        let rom_path = std::env::temp_dir().join("argentum-config-test.gb");
+        let path = GameConfig::path(&rom_path, 0xAB);
+
+        assert_eq!(path, std::env::temp_dir().join("000000AB.cfg"));
+
+        config.save(&path).unwrap();
+        let loaded = GameConfig::load(&path);
+        std::fs::remove_file(&path).ok();
+
+        assert_eq!(loaded, config);
+    }
+
+    #[test]
+    fn invalid_settings_fall_back_to_the_defaults() {
+        let config = GameConfig::parse("scale = 0\npalette = 1, 2\nbogus\nkey.start = P\n");
+
+        assert_eq!(config.scale, 3);
+
+        for &scale in ["11", "4294967295", "-1"].iter() {
+            let config = GameConfig::parse(&format!("scale = {}\n", scale));
+            assert_eq!(config.scale, 3);
+        }
+
+        assert_eq!(GameConfig::parse("scale = 10\n").scale, 10);
+        assert_eq!(config.palette, None);
+        assert_eq!(config.keys[6], "P");
+        assert!(matches!(config.key("P"), Some(ArgentumKey::Start)));
+        assert!(config.key("Return").is_none());
+
+        assert_eq!(
+            GameConfig::load(Path::new("/nonexistent/000000AB.cfg")),
+            GameConfig::default()
+        );
+    }
+}
@@ -1,10 +1,14 @@
+mod config;
+
 use std::{path::PathBuf, time::Duration};
 
 use argentum::{Argentum, ArgentumKey};
 use clap::Clap;
+use config::GameConfig;
 use pixels::{PixelsBuilder, SurfaceTexture};
 use sdl2::{
     audio::{AudioQueue, AudioSpecDesired},
+    controller::{Axis, Button},
     event::{Event, WindowEvent},
     keyboard::Scancode,
 };
@@ -20,24 +24,98 @@ struct Opt {
 
     #[clap(short, long)]
     skip_bootrom: bool,
+
+    /// Maximum amount of queued audio in milliseconds. Lower values reduce
+    /// audio lag but may cause crackling. The core hands over 512 stereo
+    /// samples (~10.7 ms) at a time, so values below that have no effect.
+    /// At most 1000 ms can be queued.
+    #[clap(long, default_value = "21", validator = validate_latency)]
+    audio_latency: u32,
+
+    /// Dead zone of the gamepad's analog stick, as a percentage of its
+    /// range. Movements inside it don't press any direction.
+    #[clap(long, default_value = "30", validator = validate_dead_zone)]
+    dead_zone: u8,
+
+    /// How many seconds of gameplay can be rewound, by holding Backspace.
+    /// Rewinding is disabled when this is 0.
+    #[clap(long, default_value = "0")]
+    rewind: u32,
+
+    /// Write the game's settings to its config file on exit, creating
+    /// it if there is none. The file is stored next to the ROM and
+    /// named after the ROM's hash.
+    #[clap(long)]
+    save_config: bool,
+}
+
+/// Check that the audio latency is between 1 and 1000 milliseconds.
+fn validate_latency(value: &str) -> Result<(), String> {
+    match value.parse::<u32>() {
+        Ok(latency) if (1..=1000).contains(&latency) => Ok(()),
+        _ => Err(String::from("audio latency must be between 1 and 1000 ms")),
+    }
+}
+
+/// Check that the dead zone is a percentage below 100.
+fn validate_dead_zone(value: &str) -> Result<(), String> {
+    match value.parse::<u8>() {
+        Ok(dead_zone) if dead_zone < 100 => Ok(()),
+        _ => Err(String::from("dead zone must be a percentage below 100")),
+    }
+}
+
+/// Map a SDL game controller button to an Argentum Key.
+fn map_controller_button(button: Button) -> Option<ArgentumKey> {
+    match button {
+        Button::DPadUp => Some(ArgentumKey::Up),
+        Button::DPadLeft => Some(ArgentumKey::Left),
+        Button::DPadDown => Some(ArgentumKey::Down),
+        Button::DPadRight => Some(ArgentumKey::Right),
+        Button::A => Some(ArgentumKey::ButtonA),
+        Button::B => Some(ArgentumKey::ButtonB),
+        Button::Start => Some(ArgentumKey::Start),
+        Button::Back => Some(ArgentumKey::Select),
+
+        _ => None,
+    }
 }
 
-/// Map a SDL_Scancode to an Argentum Key.
-fn map_scancode_key(code: Scancode) -> Option<ArgentumKey> {
-    match code {
-        Scancode::W => Some(ArgentumKey::Up),
-        Scancode::A => Some(ArgentumKey::Left),
-        Scancode::S => Some(ArgentumKey::Down),
-        Scancode::D => Some(ArgentumKey::Right),
-        Scancode::Z => Some(ArgentumKey::ButtonA),
-        Scancode::X => Some(ArgentumKey::ButtonB),
-        Scancode::Return => Some(ArgentumKey::Start),
-        Scancode::Space => Some(ArgentumKey::Select),
+/// Map the position of an analog axis to a direction, -1 for negative,
+/// 1 for positive and 0 if the axis is inside the dead zone.
+fn axis_direction(value: i16, dead_zone: u8) -> i8 {
+    let threshold = i16::MAX as i32 * dead_zone as i32 / 100;
+
+    // The negative side reaches one unit further, clamp it so that
+    // the dead zone is the same on both sides.
+    let value = value.max(-i16::MAX) as i32;
+
+    if value < -threshold {
+        -1
+    } else if value > threshold {
+        1
+    } else {
+        0
+    }
+}
+
+/// Get the DPAD key corresponding to a direction on the given axis.
+fn axis_key(axis: Axis, direction: i8) -> Option<ArgentumKey> {
+    match (axis, direction) {
+        (Axis::LeftX, -1) => Some(ArgentumKey::Left),
+        (Axis::LeftX, 1) => Some(ArgentumKey::Right),
+        (Axis::LeftY, -1) => Some(ArgentumKey::Up),
+        (Axis::LeftY, 1) => Some(ArgentumKey::Down),
 
         _ => None,
     }
 }
 
+/// Map a SDL_Scancode to an Argentum Key, using the key mappings of the game.
+fn map_scancode_key(code: Scancode, config: &GameConfig) -> Option<ArgentumKey> {
+    config.key(code.name())
+}
+
 fn main() {
     // Parse CLI options, and initialize SDL
     let opt: Opt = Opt::parse();
@@ -52,24 +130,15 @@ fn main() {
         .video()
         .expect("failed to initialize SDL video subsystem");
 
-    // Create a SDL window
-    let window = video_subsystem
-        .window("Argentum", 480, 432)
-        .position_centered()
-        .resizable()
-        .build()
-        .expect("failed to create a window");
+    let controller_subsystem = sdl
+        .game_controller()
+        .expect("failed to initialize SDL game controller subsystem");
 
-    // Create a Pixels instance for rendering
-    let mut pixels = {
-        let window_size = window.drawable_size();
-        let texture = SurfaceTexture::new(window_size.0, window_size.1, &window);
+    // Opened game controllers, they are closed when dropped
+    let mut controllers = Vec::new();
 
-        PixelsBuilder::new(160, 144, texture)
-            .enable_vsync(false)
-            .build()
-            .expect("failed to create a Pixels instance")
-    };
+    // The directions the analog stick is held in, horizontally and vertically
+    let mut stick_directions = [0i8; 2];
 
     // Create an audio queue
     let desired_spec = AudioSpecDesired {
@@ -92,11 +161,14 @@ fn main() {
     rom_path.set_extension("sav");
     let save_file = std::fs::read(&rom_path).ok();
 
+    // The maximum size of the audio queue in bytes, two f32 channels at 48 kHz
+    let max_queue_size = opt.audio_latency * 48 * 2 * 4;
+
     // Create an Argentum instance
     let mut argentum = Argentum::new(
         &rom,
         Box::new(move |buffer| {
-            while audio_queue.size() > 1024 * 4 * 2 {
+            while audio_queue.size() > max_queue_size {
                 std::thread::sleep(Duration::from_millis(1));
             }
 
@@ -105,6 +177,49 @@ fn main() {
         save_file,
     );
 
+    // Warn about bad dumps, the missing banks read as 0xFF
+    if let Some((declared, actual)) = argentum.truncated_rom() {
+        eprintln!(
+            "warning: the ROM is {} bytes, but its header declares {} bytes",
+            actual, declared
+        );
+    }
+
+    // Load the settings of the game, falling back to the defaults
+    let config_path = GameConfig::path(&rom_path, argentum.rom_hash());
+    let config = GameConfig::load(&config_path);
+    config.apply(&mut argentum);
+
+    // Create a SDL window
+    let window = video_subsystem
+        .window("Argentum", 160 * config.scale, 144 * config.scale)
+        .position_centered()
+        .resizable()
+        .build()
+        .expect("failed to create a window");
+
+    // Create a Pixels instance for rendering
+    let mut pixels = {
+        let window_size = window.drawable_size();
+        let texture = SurfaceTexture::new(window_size.0, window_size.1, &window);
+
+        let width = Argentum::SCREEN_WIDTH as u32;
+        let height = Argentum::SCREEN_HEIGHT as u32;
+
+        PixelsBuilder::new(width, height, texture)
+            .enable_vsync(false)
+            .build()
+            .expect("failed to create a Pixels instance")
+    };
+
+    // Keep a save state for every frame that can be rewound
+    if opt.rewind > 0 {
+        argentum.enable_rewind(opt.rewind as usize * 60);
+    }
+
+    // Whether the rewind key is held
+    let mut rewinding = false;
+
     // Create an event pump for window events
     let mut event_pump = sdl.event_pump().unwrap();
 
@@ -112,11 +227,25 @@ fn main() {
         // Handle window events if any
         for event in event_pump.poll_iter() {
             match event {
+                Event::KeyDown {
+                    scancode: Some(Scancode::Backspace),
+                    ..
+                } => {
+                    rewinding = true;
+                }
+
+                Event::KeyUp {
+                    scancode: Some(Scancode::Backspace),
+                    ..
+                } => {
+                    rewinding = false;
+                }
+
                 Event::KeyDown {
                     scancode: Some(code),
                     ..
                 } => {
-                    if let Some(key) = map_scancode_key(code) {
+                    if let Some(key) = map_scancode_key(code, &config) {
                         argentum.key_down(key);
                     }
                 }
@@ -125,11 +254,52 @@ fn main() {
                     scancode: Some(code),
                     ..
                 } => {
-                    if let Some(key) = map_scancode_key(code) {
+                    if let Some(key) = map_scancode_key(code, &config) {
+                        argentum.key_up(key);
+                    }
+                }
+
+                Event::ControllerDeviceAdded { which, .. } => {
+                    if let Ok(controller) = controller_subsystem.open(which) {
+                        controllers.push(controller);
+                    }
+                }
+
+                Event::ControllerButtonDown { button, .. } => {
+                    if let Some(key) = map_controller_button(button) {
+                        argentum.key_down(key);
+                    }
+                }
+
+                Event::ControllerButtonUp { button, .. } => {
+                    if let Some(key) = map_controller_button(button) {
                         argentum.key_up(key);
                     }
                 }
 
+                Event::ControllerAxisMotion { axis, value, .. } => {
+                    let index = match axis {
+                        Axis::LeftX => 0,
+                        Axis::LeftY => 1,
+
+                        _ => continue,
+                    };
+
+                    let direction = axis_direction(value, opt.dead_zone);
+
+                    if direction != stick_directions[index] {
+                        if let Some(key) = axis_key(axis, stick_directions[index]) {
+                            argentum.key_up(key);
+                        }
+
+                        if let Some(key) = axis_key(axis, direction) {
+                            argentum.key_down(key);
+                        }
+
+                        stick_directions[index] = direction;
+                    }
+                }
+
                 Event::Quit { .. } => {
                     break 'main;
                 }
@@ -145,8 +315,14 @@ fn main() {
             }
         }
 
-        // Execute a frames worth of instructions
-        argentum.execute_frame();
+        // Step back a frame while rewinding, no audio is generated so
+        // wait for a frame's time instead. Otherwise execute a frames
+        // worth of instructions.
+        if rewinding && argentum.rewind_step() {
+            std::thread::sleep(Duration::from_micros(16_743));
+        } else {
+            argentum.execute_frame();
+        }
 
         // Update the pixels framebuffer
         pixels
@@ -157,8 +333,47 @@ fn main() {
         pixels.render().expect("failed to render framebuffer");
     }
 
+    if opt.save_config {
+        config
+            .save(&config_path)
+            .expect("failed to write config file");
+    }
+
     // Save RAM dump
-    if let Some(ram_save) = argentum.get_ram_dump() {
+    if let Some(ram_save) = argentum.close() {
         std::fs::write(&rom_path, &ram_save).expect("failed to write save file");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn axis_direction_applies_the_dead_zone() {
+        assert_eq!(axis_direction(0, 25), 0);
+        assert_eq!(axis_direction(8000, 25), 0);
+        assert_eq!(axis_direction(-8000, 25), 0);
+        assert_eq!(axis_direction(9000, 25), 1);
+        assert_eq!(axis_direction(-9000, 25), -1);
+        assert_eq!(axis_direction(i16::MAX, 25), 1);
+        assert_eq!(axis_direction(i16::MIN, 25), -1);
+
+        // Without a dead zone any deflection counts, with a full
+        // one the stick never leaves the center.
+        assert_eq!(axis_direction(1, 0), 1);
+        assert_eq!(axis_direction(-1, 0), -1);
+        assert_eq!(axis_direction(i16::MAX, 100), 0);
+        assert_eq!(axis_direction(i16::MIN, 100), 0);
+    }
+
+    #[test]
+    fn axis_directions_map_to_the_dpad() {
+        assert!(matches!(axis_key(Axis::LeftX, -1), Some(ArgentumKey::Left)));
+        assert!(matches!(axis_key(Axis::LeftX, 1), Some(ArgentumKey::Right)));
+        assert!(matches!(axis_key(Axis::LeftY, -1), Some(ArgentumKey::Up)));
+        assert!(matches!(axis_key(Axis::LeftY, 1), Some(ArgentumKey::Down)));
+        assert!(axis_key(Axis::LeftX, 0).is_none());
+        assert!(axis_key(Axis::RightX, 1).is_none());
+    }
+}
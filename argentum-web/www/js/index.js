@@ -1,4 +1,5 @@
 import init, { ArgentumHandle, AudioHandle } from "../wasm/argentum_web.js"
+import { FramePacer } from "./pacing.js"
 
 const canvas = document.getElementById("canvas");
 const ctx = canvas.getContext("2d");
@@ -7,6 +8,9 @@ const rom_input = document.getElementById("rom_input");
 const start = document.getElementById("start");
 const stop = document.getElementById("stop");
 
+/* frames are skipped while this many audio buffers are queued */
+const MAX_AUDIO_QUEUE = 15;
+
 ctx.fillStyle = "black";
 ctx.fillRect(0.0, 0.0, canvas.width, canvas.height);
 
@@ -34,13 +38,14 @@ async function main() {
             running = true;
 
             let audio = AudioHandle.new();
-            let intervalID = null;
+            let pacer = new FramePacer();
 
             argentum = ArgentumHandle.new(rom, (buffer) => {
                 audio.append(buffer);
             });
 
-            function main_loop() {
+            /* the loop is driven by requestAnimationFrame, see pacing.js */
+            function main_loop(timestamp) {
                 if (!running) {
                     argentum.drop_handle();
                     audio.drop_handle();
@@ -48,14 +53,21 @@ async function main() {
                     argentum = null;
                     audio = null;
 
-                    clearInterval(intervalID);
                     return;
                 }
 
-                if (audio.length() < 15) {
+                let due = pacer.frames_due(timestamp);
+                let frames = 0;
+
+                /* don't let the audio queue grow without bound */
+                while (frames < due && audio.length() < MAX_AUDIO_QUEUE) {
                     /* execute frame's worth of instructions */
                     argentum.execute_frame();
 
+                    frames += 1;
+                }
+
+                if (frames > 0) {
                     /* paint the frame */
                     let framebuffer = argentum.get_framebuffer();
                     let image_data = new ImageData(framebuffer, 160, 144);
@@ -68,9 +80,11 @@ async function main() {
                         ctx.drawImage(bitmap, 0.0, 0.0);
                     });
                 }
+
+                requestAnimationFrame(main_loop);
             }
 
-            intervalID = setInterval(main_loop, 1);
+            requestAnimationFrame(main_loop);
         };
     };
 
@@ -0,0 +1,37 @@
+/* duration of one Game Boy frame in milliseconds (70224 cycles at 4.194304 MHz) */
+export const FRAME_DURATION = (70224 / 4194304) * 1000;
+
+/* upper bound on frames executed for a single animation frame */
+export const MAX_FRAMES_PER_TICK = 4;
+
+/*
+    Converts the timestamps passed to requestAnimationFrame, which fire at the
+    display's refresh rate, into whole Game Boy frames to execute. Elapsed time
+    is accumulated so that the emulator keeps to ~59.73 Hz on 60 Hz, 144 Hz or
+    throttled displays alike.
+*/
+export class FramePacer {
+    constructor() {
+        this.last_timestamp = null;
+        this.accumulator = 0.0;
+    }
+
+    /* get the number of frames due at the given timestamp, in milliseconds */
+    frames_due(timestamp) {
+        if (this.last_timestamp !== null) {
+            this.accumulator += timestamp - this.last_timestamp;
+        }
+
+        this.last_timestamp = timestamp;
+
+        /* don't try to catch up after the tab was in the background */
+        if (this.accumulator > FRAME_DURATION * MAX_FRAMES_PER_TICK) {
+            this.accumulator = FRAME_DURATION * MAX_FRAMES_PER_TICK;
+        }
+
+        let frames = Math.floor(this.accumulator / FRAME_DURATION);
+        this.accumulator -= frames * FRAME_DURATION;
+
+        return frames;
+    }
+}
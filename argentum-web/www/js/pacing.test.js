@@ -0,0 +1,33 @@
+import test from "node:test";
+import assert from "node:assert";
+
+import { FramePacer, MAX_FRAMES_PER_TICK } from "./pacing.js";
+
+/* count the frames executed over one second of animation frames at the given rate */
+function frames_over_a_second(refresh_rate) {
+    let pacer = new FramePacer();
+    let frames = 0;
+
+    for (let i = 0; i <= refresh_rate; i++) {
+        frames += pacer.frames_due((i * 1000) / refresh_rate);
+    }
+
+    return frames;
+}
+
+test("runs at the Game Boy refresh rate", () => {
+    for (let refresh_rate of [30, 60, 75, 144, 240]) {
+        let frames = frames_over_a_second(refresh_rate);
+
+        assert.ok(frames >= 59 && frames <= 60, `${frames} frames at ${refresh_rate} Hz`);
+    }
+});
+
+test("doesn't catch up after a pause", () => {
+    let pacer = new FramePacer();
+
+    pacer.frames_due(0.0);
+
+    assert.strictEqual(pacer.frames_due(10000.0), MAX_FRAMES_PER_TICK);
+    assert.strictEqual(pacer.frames_due(10001.0), 0);
+});
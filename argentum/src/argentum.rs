@@ -1,31 +1,479 @@
 //! Wrapper struct to conviniently abstract the inner workings.
 
-use crate::{bus::Bus, cpu::Cpu, joypad::ArgentumKey};
+use std::{
+    io::{self, Read},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    audio::{AudioCallback, AudioSync, CPU_CLOCK},
+    bus::Bus,
+    cartridge::{decode_ram_size, decode_rom_size},
+    cheats::{self, Cheat, CheatError},
+    cpu::{self, Cpu, CpuState},
+    joypad::{ArgentumKey, SocdMode},
+    ppu::{FrameTiming, PixelOrder, TileInfo, VBlankCallback},
+    rewind::RewindBuffer,
+    savestate::{self, FullStateImage, SaveStateError, StateReader, StateWriter},
+};
 
 /// T-cycles to execute per frame.
 const CYCLES_PER_FRAME: u32 = 70224;
 
+/// Called once per frame with the framebuffer and the
+/// interleaved stereo samples generated during the frame.
+pub type AvCallback = Box<dyn FnMut(&[u8], &[f32])>;
+
+/// The reason `execute_frame` returned.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FrameResult {
+    /// A frame's worth of instructions was executed.
+    Completed,
+
+    /// A write landed in a watched memory range.
+    WriteBreakpoint { addr: u16, value: u8 },
+}
+
+/// The output of a single frame advanced with `Argentum::advance`.
+pub struct FrameOutput {
+    /// Whether the whole frame was executed, or a breakpoint
+    /// stopped it early.
+    pub result: FrameResult,
+
+    /// The RGBA framebuffer at the end of the frame.
+    pub framebuffer: Vec<u8>,
+
+    /// The interleaved stereo samples generated during the frame.
+    pub audio: Vec<f32>,
+}
+
+/// The contents of WRAM, VRAM and OAM on power up.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PowerOnPattern {
+    /// All bytes are zero.
+    Zero,
+
+    /// All bytes are 0xFF.
+    Ones,
+
+    /// Pseudo-random bytes, reproducible for the same seed.
+    Random { seed: u64 },
+}
+
+/// What happens to audio while fast-forwarding with `execute_frames`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TurboAudio {
+    /// Audio is generated as usual, and plays sped up.
+    Pitch,
+
+    /// No audio is generated, the audio callback isn't called.
+    Drop,
+}
+
 pub struct Argentum {
     bus: Bus,
     cpu: Cpu,
+
+    /// T-cycles executed since power on, at normal speed.
+    total_cycles: u64,
+
+    /// Run through the boot ROM instantly instead of in real time.
+    fast_boot: bool,
+
+    /// Frames completed since power on.
+    frame_count: u64,
+
+    /// What happens to audio while fast-forwarding.
+    turbo_audio: TurboAudio,
+
+    /// Called after every frame with its framebuffer and audio samples.
+    av_callback: Option<AvCallback>,
+
+    /// Save states of the most recent frames, if rewinding is enabled.
+    rewind: Option<RewindBuffer>,
 }
 
 impl Argentum {
+    /// Width of the screen in pixels.
+    pub const SCREEN_WIDTH: usize = 160;
+
+    /// Height of the screen in pixels.
+    pub const SCREEN_HEIGHT: usize = 144;
+
     /// Create a new `Argentum` instance.
-    pub fn new(rom: &[u8], callback: Box<dyn Fn(&[f32])>, save_file: Option<Vec<u8>>) -> Self {
+    pub fn new(rom: &[u8], callback: AudioCallback, save_file: Option<Vec<u8>>) -> Self {
         Self {
             bus: Bus::new(rom, callback, save_file),
             cpu: Cpu::new(),
+            total_cycles: 0,
+            fast_boot: false,
+            frame_count: 0,
+            turbo_audio: TurboAudio::Pitch,
+            av_callback: None,
+            rewind: None,
         }
     }
 
+    /// Create a new `Argentum` instance, with the ROM read from the given
+    /// reader. Fails if reading fails, or if the ROM is too short to even
+    /// hold a cartridge header.
+    pub fn from_reader<R: Read>(
+        mut reader: R,
+        callback: AudioCallback,
+        save_file: Option<Vec<u8>>,
+    ) -> io::Result<Self> {
+        let mut rom = Vec::new();
+
+        // Read up to the end of the header first, so that short
+        // inputs are rejected before reading the rest.
+        reader.by_ref().take(0x150).read_to_end(&mut rom)?;
+
+        if rom.len() < 0x150 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "ROM is too short to hold a cartridge header",
+            ));
+        }
+
+        reader.read_to_end(&mut rom)?;
+
+        Ok(Self::new(&rom, callback, save_file))
+    }
+
+    /// Create a new `Argentum` instance which starts in the given state,
+    /// with the boot ROM skipped. IO registers not in the image have
+    /// their post boot ROM values.
+    pub fn from_image(
+        rom: &[u8],
+        image: &FullStateImage,
+        callback: AudioCallback,
+    ) -> Result<Self, SaveStateError> {
+        image.validate()?;
+
+        let mut argentum = Self::new(rom, callback, None);
+        argentum.skip_bootrom();
+
+        let reg = &mut argentum.cpu.reg;
+
+        reg.set_af(image.af);
+        reg.set_bc(image.bc);
+        reg.set_de(image.de);
+        reg.set_hl(image.hl);
+        reg.sp = image.sp;
+        reg.pc = image.pc;
+
+        argentum.cpu.ime = image.ime;
+
+        let bus = &mut argentum.bus;
+
+        bus.ie_reg = image.ie;
+        *bus.if_reg.borrow_mut() = image.interrupt_flags;
+        bus.work_ram.copy_from_slice(&image.wram);
+        bus.high_ram.copy_from_slice(&image.hram);
+
+        let mut memory = image.vram.iter().chain(&image.oam).copied();
+        bus.ppu.fill_memory(&mut || memory.next().unwrap_or(0));
+
+        Ok(argentum)
+    }
+
     /// Execute a frame's worth of instructions.
-    pub fn execute_frame(&mut self) {
+    /// Execution stops early if a breakpoint is hit.
+    pub fn execute_frame(&mut self) -> FrameResult {
+        if self.fast_boot && self.bus.boot_reg == 0 {
+            self.finish_bootrom();
+        }
+
+        if let Some(mut rewind) = self.rewind.take() {
+            rewind.push(self.save_state());
+            self.rewind = Some(rewind);
+        }
+
+        self.bus.apply_ram_cheats();
+
+        // Forget about breakpoints hit while stepping manually.
+        self.bus.write_breakpoint_hit = None;
+
+        // Collect the frame's samples for the A/V callback, unless
+        // someone else is already collecting them.
+        let capturing = self.bus.apu.capture_samples;
+        self.bus.apu.capture_samples |= self.av_callback.is_some();
+
         let mut cycles = 0;
 
         while cycles <= CYCLES_PER_FRAME {
-            cycles += self.cpu.execute_next(&mut self.bus);
+            cycles += self.step_instruction();
+
+            if let Some((addr, value)) = self.bus.write_breakpoint_hit.take() {
+                self.bus.apu.capture_samples = capturing;
+
+                return FrameResult::WriteBreakpoint { addr, value };
+            }
+        }
+
+        if let Some(callback) = &mut self.av_callback {
+            callback(
+                self.bus.ppu.front_framebuffer.as_ref(),
+                &self.bus.apu.captured_samples,
+            );
+
+            if !capturing {
+                self.bus.apu.captured_samples.clear();
+            }
+        }
+
+        self.bus.apu.capture_samples = capturing;
+        self.frame_count += 1;
+
+        FrameResult::Completed
+    }
+
+    /// Get the number of frames completed since power on.
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    /// Fast-forward by executing `n` frames at once.
+    /// Execution stops early if a breakpoint is hit.
+    pub fn execute_frames(&mut self, n: u32) -> FrameResult {
+        self.bus.apu.output_enabled = self.turbo_audio == TurboAudio::Pitch;
+
+        let mut result = FrameResult::Completed;
+
+        for _ in 0..n {
+            result = self.execute_frame();
+
+            if result != FrameResult::Completed {
+                break;
+            }
+        }
+
+        self.bus.apu.output_enabled = true;
+
+        result
+    }
+
+    /// Choose what happens to audio while fast-forwarding.
+    /// Defaults to `TurboAudio::Pitch`.
+    pub fn set_turbo_audio(&mut self, mode: TurboAudio) {
+        self.turbo_audio = mode;
+    }
+
+    /// Run `n` frames without rendering or generating audio, to measure
+    /// the throughput of the rest of the system. The audio callback is
+    /// not called meanwhile.
+    pub fn bench_frames(&mut self, n: u32) {
+        self.bus.ppu.rendering_enabled = false;
+        self.bus.apu.output_enabled = false;
+
+        for _ in 0..n {
+            while self.execute_frame() != FrameResult::Completed {}
+        }
+
+        self.bus.ppu.rendering_enabled = true;
+        self.bus.apu.output_enabled = true;
+    }
+
+    /// Set a callback which receives the framebuffer and the audio
+    /// samples generated during the frame, after every frame.
+    pub fn set_av_callback(&mut self, callback: AvCallback) {
+        self.av_callback = Some(callback);
+    }
+
+    /// Freeze or unfreeze the PPU, the CPU keeps running.
+    pub fn set_ppu_paused(&mut self, paused: bool) {
+        self.bus.ppu_paused = paused;
+    }
+
+    /// Freeze or unfreeze the APU, the CPU keeps running.
+    pub fn set_apu_paused(&mut self, paused: bool) {
+        self.bus.apu_paused = paused;
+    }
+
+    /// Apply the given input and execute exactly one frame, returning
+    /// the video and audio it produced. The input is a bitmask of
+    /// `ArgentumKey` values. Two instances fed the same inputs produce
+    /// the same output, which makes this suitable for lockstep netplay.
+    /// If a breakpoint stops the frame early, the output only covers
+    /// the part of the frame which was executed.
+    pub fn advance(&mut self, input: u8) -> FrameOutput {
+        self.bus.joypad.set_state(input);
+
+        // Samples captured before this frame are left alone, and so
+        // is capturing if someone else enabled it.
+        let capturing = self.bus.apu.capture_samples;
+        let start = self.bus.apu.captured_samples.len();
+
+        self.bus.apu.capture_samples = true;
+        let result = self.execute_frame();
+        self.bus.apu.capture_samples = capturing;
+
+        let audio = if capturing {
+            self.bus.apu.captured_samples[start..].to_vec()
+        } else {
+            self.bus.apu.captured_samples.split_off(start)
+        };
+
+        FrameOutput {
+            result,
+            framebuffer: self.get_framebuffer().to_vec(),
+            audio,
+        }
+    }
+
+    /// Get the most recently filled audio buffer, as interleaved
+    /// stereo samples. Empty until the first buffer has been filled.
+    pub fn last_audio_buffer(&self) -> &[f32] {
+        &self.bus.apu.last_buffer
+    }
+
+    /// Execute a single instruction and return the T-cycles it took.
+    pub fn step_instruction(&mut self) -> u32 {
+        let cycles = self.cpu.execute_next(&mut self.bus);
+        self.total_cycles += cycles as u64;
+
+        cycles
+    }
+
+    /// Get the T-cycles executed since power on, at normal speed.
+    pub fn total_cycles(&self) -> u64 {
+        self.total_cycles
+    }
+
+    /// Get the emulated time elapsed since power on.
+    pub fn emulated_time(&self) -> Duration {
+        Duration::from_nanos(self.total_cycles * 1_000_000_000 / CPU_CLOCK as u64)
+    }
+
+    /// Execute the next instruction, if it is a CALL or RST run until
+    /// the subroutine returns, or until `max_cycles` T-cycles have been
+    /// executed. Return the T-cycles it took.
+    /// Execution stops early if a write breakpoint is hit, or if the
+    /// CPU locks up.
+    pub fn step_over(&mut self, max_cycles: u64) -> u32 {
+        let pc = self.cpu.reg.pc;
+        let sp = self.cpu.reg.sp;
+        let opcode = self.bus.read_byte(pc, false);
+
+        let is_call = matches!(
+            opcode,
+            0xC4 | 0xCC
+                | 0xCD
+                | 0xD4
+                | 0xDC
+                | 0xC7
+                | 0xCF
+                | 0xD7
+                | 0xDF
+                | 0xE7
+                | 0xEF
+                | 0xF7
+                | 0xFF
+        );
+
+        let mut cycles = self.step_instruction();
+
+        if is_call && self.bus.write_breakpoint_hit.take().is_none() {
+            let return_address = pc.wrapping_add(self.instruction_length(opcode, false) as u16);
+
+            while !(self.cpu.reg.pc == return_address && self.cpu.reg.sp == sp) {
+                if cycles as u64 >= max_cycles || self.cpu.state == CpuState::Locked {
+                    break;
+                }
+
+                cycles += self.step_instruction();
+
+                if self.bus.write_breakpoint_hit.take().is_some() {
+                    break;
+                }
+            }
+        }
+
+        cycles
+    }
+
+    /// Run until the PPU finishes drawing the next line, and return it.
+    /// Returns `None` if no line was drawn within a frame's worth of
+    /// cycles, for example because the LCD is off.
+    pub fn step_scanline(&mut self) -> Option<&[u8]> {
+        let mut cycles = 0;
+
+        self.bus.ppu.line_drawn = false;
+
+        while !self.bus.ppu.line_drawn {
+            if cycles > CYCLES_PER_FRAME {
+                return None;
+            }
+
+            cycles += self.step_instruction();
+        }
+
+        Some(self.bus.ppu.current_line())
+    }
+
+    /// Run until the current subroutine returns to its caller, or until
+    /// `max_cycles` T-cycles have been executed. Return the T-cycles it took.
+    /// Execution stops early if a write breakpoint is hit, or if the
+    /// CPU locks up.
+    pub fn step_out(&mut self, max_cycles: u64) -> u32 {
+        let sp = self.cpu.reg.sp;
+        let mut cycles = 0;
+
+        while (cycles as u64) < max_cycles && self.cpu.state != CpuState::Locked {
+            let opcode = self.bus.read_byte(self.cpu.reg.pc, false);
+            let is_return = matches!(opcode, 0xC0 | 0xC8 | 0xC9 | 0xD0 | 0xD8 | 0xD9);
+
+            cycles += self.step_instruction();
+
+            if self.bus.write_breakpoint_hit.take().is_some() {
+                break;
+            }
+
+            // Interrupts push onto the stack before their handler runs, so
+            // returning from one never unwinds past the current frame.
+            if is_return && self.cpu.reg.sp > sp {
+                break;
+            }
+        }
+
+        cycles
+    }
+
+    /// Run until PC reaches `target`, or until `max_cycles` T-cycles
+    /// have been executed. Return whether `target` was reached.
+    /// Execution stops early if a write breakpoint is hit.
+    pub fn run_until_pc(&mut self, target: u16, max_cycles: u64) -> bool {
+        let mut cycles = 0;
+
+        while self.cpu.reg.pc != target {
+            if cycles >= max_cycles {
+                return false;
+            }
+
+            cycles += self.step_instruction() as u64;
+
+            if self.bus.write_breakpoint_hit.take().is_some() {
+                return self.cpu.reg.pc == target;
+            }
         }
+
+        true
+    }
+
+    /// Break execution whenever the CPU writes a byte in `start..=end`.
+    pub fn add_write_breakpoint(&mut self, start: u16, end: u16) {
+        self.bus.write_breakpoints.push((start, end));
+    }
+
+    /// Remove all the write breakpoints.
+    pub fn clear_write_breakpoints(&mut self) {
+        self.bus.write_breakpoints.clear();
+    }
+
+    /// Get the length in bytes of the framebuffer, which
+    /// holds 4 bytes (RGBA) for every pixel.
+    pub const fn framebuffer_len() -> usize {
+        Self::SCREEN_WIDTH * Self::SCREEN_HEIGHT * 4
     }
 
     /// Get a reference to the framebuffer.
@@ -33,6 +481,183 @@ impl Argentum {
         self.bus.ppu.front_framebuffer.as_ref()
     }
 
+    /// Set a callback which receives the framebuffer every time
+    /// the PPU completes a frame.
+    pub fn set_vblank_callback(&mut self, callback: VBlankCallback) {
+        self.bus.ppu.vblank_callback = Some(callback);
+    }
+
+    /// Darken every other row of the framebuffer to mimic a CRT.
+    /// The intensity ranges from 0.0 (off) to 1.0.
+    pub fn set_scanline_overlay(&mut self, intensity: f32) {
+        self.bus.ppu.set_scanline_overlay(intensity);
+    }
+
+    /// Set the order of the colour bytes of each pixel in the framebuffer.
+    /// Defaults to `PixelOrder::Rgb`.
+    pub fn set_pixel_order(&mut self, order: PixelOrder) {
+        self.bus.ppu.set_pixel_order(order);
+    }
+
+    /// Show or hide the background, window and sprite layers, which
+    /// helps find out what layer a graphical glitch comes from.
+    /// Hidden layers are left blank.
+    pub fn set_layer_mask(&mut self, bg: bool, window: bool, sprites: bool) {
+        self.bus.ppu.set_layer_mask(bg, window, sprites);
+    }
+
+    /// Set the colours used for the four shades in DMG mode, lightest
+    /// first, each given as 0xRRGGBB.
+    pub fn set_dmg_palette(&mut self, colours: [u32; 4]) {
+        self.bus.ppu.set_dmg_palette(colours);
+    }
+
+    /// Blur the output with a 3x3 tent filter, which blends dithered
+    /// patterns into flat colours without shifting the image.
+    pub fn set_blur_filter(&mut self, enabled: bool) {
+        self.bus.ppu.set_blur_filter(enabled);
+    }
+
+    /// Enable or disable the hardware limit of 10 sprites per line.
+    /// Disabling it reduces flicker but is not accurate.
+    pub fn set_sprite_limit(&mut self, enabled: bool) {
+        self.bus.ppu.set_sprite_limit(enabled);
+    }
+
+    /// Get the number of sprites selected on the last rendered line.
+    pub fn last_line_sprite_count(&self) -> u8 {
+        self.bus.ppu.last_line_sprite_count()
+    }
+
+    /// Fill WRAM, VRAM and OAM according to the given pattern.
+    /// This should be called before execution starts.
+    pub fn set_power_on_pattern(&mut self, pattern: PowerOnPattern) {
+        let mut fill: Box<dyn FnMut() -> u8> = match pattern {
+            PowerOnPattern::Zero => Box::new(|| 0x00),
+            PowerOnPattern::Ones => Box::new(|| 0xFF),
+
+            PowerOnPattern::Random { seed } => {
+                // Xorshift64, the state must never be zero.
+                let mut state = seed.max(1);
+
+                Box::new(move || {
+                    state ^= state << 13;
+                    state ^= state >> 7;
+                    state ^= state << 17;
+
+                    (state >> 56) as u8
+                })
+            }
+        };
+
+        self.bus.work_ram.iter_mut().for_each(|byte| *byte = fill());
+        self.bus.ppu.fill_memory(&mut fill);
+    }
+
+    /// Get the length in bytes of the given opcode, without executing it.
+    /// Set `cb` if the opcode follows a 0xCB prefix.
+    pub fn instruction_length(&self, opcode: u8, cb: bool) -> u8 {
+        cpu::instruction_length(opcode, cb)
+    }
+
+    /// Get the entire work RAM.
+    ///
+    /// It is laid out as 8 banks of 4 KB, bank 0 is always mapped to
+    /// 0xC000 and the bank selected by SVBK (1-7) is mapped to 0xD000.
+    /// In DMG mode only banks 0 and 1 are used.
+    pub fn wram(&self) -> &[u8] {
+        self.bus.work_ram.as_ref()
+    }
+
+    /// Get the high RAM, mapped to 0xFF80 through 0xFFFE.
+    pub fn hram(&self) -> &[u8] {
+        self.bus.high_ram.as_ref()
+    }
+
+    /// Dump the whole address space as the CPU sees it right now, with
+    /// the currently selected banks. IO registers, VRAM and OAM read
+    /// as they would from the CPU in the current PPU mode.
+    /// This does not advance the emulation.
+    pub fn memory_dump(&mut self) -> [u8; 0x10000] {
+        let mut dump = [0u8; 0x10000];
+
+        for (addr, byte) in dump.iter_mut().enumerate() {
+            *byte = self.bus.read_byte(addr as u16, false);
+        }
+
+        dump
+    }
+
+    /// Get the T-cycles the PPU spent in each mode during the last frame.
+    pub fn frame_timing_stats(&self) -> FrameTiming {
+        self.bus.ppu.last_frame_timing
+    }
+
+    /// Get the full 16-bit internal divider counter.
+    /// Only its upper 8 bits are visible in DIV.
+    pub fn internal_div(&self) -> u16 {
+        self.bus.timer.internal_div()
+    }
+
+    /// Get the tile at the given coordinates of a tile map.
+    /// Map `0` is the one at 0x9800 and map `1` the one at 0x9C00.
+    pub fn tile_at(&self, map: u8, tx: u8, ty: u8) -> TileInfo {
+        self.bus.ppu.tile_at(map, tx, ty)
+    }
+
+    /// Render all the tiles of a VRAM bank (0 or 1) into a 128x192 image,
+    /// in the same format as the framebuffer. For debugging.
+    /// Returns `None` for banks other than 0 and 1.
+    pub fn get_tile_atlas(&self, bank: usize) -> Option<Box<[u8; 128 * 192 * 4]>> {
+        self.bus.ppu.dump_tile_atlas(bank)
+    }
+
+    /// Find the VRAM addresses of all tiles matching the given pattern.
+    pub fn find_tile(&self, pattern: &[u8; 16]) -> Vec<u16> {
+        self.bus.ppu.find_tile(pattern)
+    }
+
+    /// Find the OAM indices of all sprites using the given tile.
+    pub fn find_sprite_using_tile(&self, index: u8) -> Vec<usize> {
+        self.bus.ppu.find_sprite_using_tile(index)
+    }
+
+    /// Export the current DMG and CGB palettes as JSON.
+    pub fn export_palettes(&self) -> String {
+        self.bus.ppu.export_palettes()
+    }
+
+    /// Import palettes previously exported with `export_palettes`.
+    pub fn import_palettes(&mut self, json: &str) -> Result<(), String> {
+        self.bus.ppu.import_palettes(json)
+    }
+
+    /// Check whether the boot ROM is mapped over 0x0000..=0x00FF.
+    pub fn boot_rom_mapped(&self) -> bool {
+        self.bus.boot_reg == 0
+    }
+
+    /// Run the boot ROM to completion within a single frame, instead of
+    /// waiting for the logo animation. Unlike `skip_bootrom`, the boot ROM
+    /// still initializes the hardware.
+    pub fn set_fast_boot(&mut self, enabled: bool) {
+        self.fast_boot = enabled;
+    }
+
+    /// Fast-forward until the boot ROM unmaps itself.
+    fn finish_bootrom(&mut self) {
+        // The APU is paused so that the frontend is not flooded with
+        // the boot sound.
+        let apu_paused = self.bus.apu_paused;
+        self.bus.apu_paused = true;
+
+        while self.bus.boot_reg == 0 {
+            self.step_instruction();
+        }
+
+        self.bus.apu_paused = apu_paused;
+    }
+
     pub fn skip_bootrom(&mut self) {
         self.cpu.skip_bootrom(self.bus.cgb_mode);
         self.bus.skip_bootrom();
@@ -48,12 +673,1263 @@ impl Argentum {
         self.bus.joypad.key_up(key);
     }
 
-    /// Dump the SRAM and get a copy.
-    pub fn get_ram_dump(&self) -> Option<Vec<u8>> {
-        if !([0x03, 0x0F, 0x10, 0x13, 0x1B, 0x1E].contains(&self.bus.cartridge.read_byte(0x0147))) {
-            return None;
+    /// Choose how opposing directions held at the same time are
+    /// resolved. Defaults to `SocdMode::Allow`, like the hardware.
+    pub fn set_socd_mode(&mut self, mode: SocdMode) {
+        self.bus.joypad.socd_mode = mode;
+    }
+
+    /// Delay the joypad interrupt after a key press, like the hardware.
+    pub fn set_joypad_latency(&mut self, enabled: bool) {
+        self.bus.joypad.emulate_latency = enabled;
+    }
+
+    /// Make LY read 0 early on in line 153 like the hardware, which
+    /// moves LYC=0 coincidences from line 0 to line 153.
+    pub fn set_line_153_quirk(&mut self, enabled: bool) {
+        self.bus.ppu.line_153_quirk = enabled;
+    }
+
+    /// Make reads from the unusable region at 0xFEA0 - 0xFEFF return
+    /// what the hardware does, instead of always 0xFF.
+    pub fn set_unusable_reads_accuracy(&mut self, enabled: bool) {
+        self.bus.unusable_reads_accurate = enabled;
+    }
+
+    /// Transfer OAM DMA one byte per M-cycle like the hardware,
+    /// instead of copying all 160 bytes at once. The CPU can only
+    /// access the IO registers and HRAM while a transfer is running.
+    pub fn set_oam_dma_accuracy(&mut self, enabled: bool) {
+        self.bus.oam_dma_accurate = enabled;
+    }
+
+    /// Pan a channel between -1.0 (left) and 1.0 (right).
+    /// Channels are indexed from zero, so `0` is channel one.
+    /// Channels other than `0 - 3` are ignored.
+    pub fn set_channel_pan(&mut self, channel: usize, pan: f32) {
+        self.bus.apu.set_channel_pan(channel, pan);
+    }
+
+    /// Set the volume of the audio output, from 0.0 (silent) to 1.0 (full).
+    pub fn set_master_volume(&mut self, volume: f32) {
+        self.bus.apu.set_master_volume(volume);
+    }
+
+    /// Drop or repeat a sample in every audio buffer, to keep the audio
+    /// in sync with a sink running slightly fast or slow. Frontends can
+    /// switch this depending on how much audio they have queued up.
+    pub fn set_audio_sync(&mut self, mode: AudioSync) {
+        self.bus.apu.set_audio_sync(mode);
+    }
+
+    /// Mute or unmute a sound channel (0 - 3), for debugging audio.
+    /// Muted channels keep running, they are just left out of the mix.
+    /// Channels other than `0 - 3` are ignored.
+    pub fn set_channel_enabled(&mut self, channel: usize, enabled: bool) {
+        self.bus.apu.set_channel_enabled(channel, enabled);
+    }
+
+    /// Enable or disable the high-pass filter which removes the DC
+    /// offset from the audio output, like on the hardware.
+    pub fn set_highpass_enabled(&mut self, enabled: bool) {
+        self.bus.apu.set_highpass_enabled(enabled);
+    }
+
+    /// Get the pitch of a channel in Hz, for visualizers and such.
+    /// For channel four this is the rate at which the noise is clocked.
+    /// Channels are indexed from zero, so `0` is channel one.
+    /// Returns `None` for channels other than `0 - 3`.
+    pub fn channel_frequency_hz(&self, channel: u8) -> Option<f32> {
+        self.bus.apu.channel_frequency_hz(channel)
+    }
+
+    /// Set a callback which is called with the address and the opcode
+    /// whenever an illegal instruction is executed, which usually
+    /// means the game has crashed.
+    pub fn set_illegal_opcode_callback(&mut self, callback: Box<dyn Fn(u16, u8)>) {
+        self.bus.illegal_opcode_callback = Some(callback);
+    }
+
+    /// Lock up the CPU when an illegal instruction is executed, like
+    /// the hardware. Otherwise such instructions do nothing.
+    pub fn set_illegal_opcode_lockup(&mut self, enabled: bool) {
+        self.bus.lock_on_illegal_opcode = enabled;
+    }
+
+    /// Set a callback which receives every byte sent over the serial port.
+    pub fn set_serial_callback(&mut self, callback: Box<dyn Fn(u8)>) {
+        self.bus.serial.set_callback(callback);
+    }
+
+    /// Keep a save state for each of the last `capacity_frames` frames,
+    /// which can be returned to with `rewind_step`.
+    pub fn enable_rewind(&mut self, capacity_frames: usize) {
+        self.rewind = Some(RewindBuffer::new(capacity_frames));
+    }
+
+    /// Stop keeping save states, and drop the ones already kept.
+    pub fn disable_rewind(&mut self) {
+        self.rewind = None;
+    }
+
+    /// Return to the start of the last frame executed, going back
+    /// a frame further each call. Returns false once there are no
+    /// frames left to rewind, or if rewinding is disabled.
+    pub fn rewind_step(&mut self) -> bool {
+        let state = match self.rewind.as_mut().and_then(|rewind| rewind.pop()) {
+            Some(state) => state,
+            None => return false,
+        };
+
+        self.load_state(&state).is_ok()
+    }
+
+    /// Load the cheats in the given cheat file, in addition to the
+    /// ones already loaded. Returns the number of enabled cheats loaded.
+    pub fn load_cheat_file(&mut self, contents: &str) -> Result<usize, CheatError> {
+        let cheats = cheats::parse_cheat_file(contents)?;
+        let enabled = cheats.iter().filter(|cheat| cheat.enabled).count();
+
+        self.bus.cheats.extend(cheats);
+
+        Ok(enabled)
+    }
+
+    /// Get the loaded cheats.
+    pub fn cheats(&self) -> &[Cheat] {
+        &self.bus.cheats
+    }
+
+    /// Enable or disable one of the loaded cheats.
+    /// Returns `false` if there is no cheat at `index`.
+    pub fn set_cheat_enabled(&mut self, index: usize, enabled: bool) -> bool {
+        match self.bus.cheats.get_mut(index) {
+            Some(cheat) => {
+                cheat.enabled = enabled;
+                true
+            }
+
+            None => false,
         }
+    }
 
-        self.bus.cartridge.dump_ram()
+    /// Remove all the loaded cheats.
+    pub fn clear_cheats(&mut self) {
+        self.bus.cheats.clear();
+    }
+
+    /// Get the CRC-32 checksum of the ROM.
+    /// Useful for associating save files with a game regardless of its file name.
+    pub fn rom_hash(&self) -> u32 {
+        self.bus.rom_hash
+    }
+
+    /// Get the banking mode of a MBC1 cartridge, true for the advanced
+    /// mode. Returns `None` for other cartridges.
+    pub fn mbc1_banking_mode(&self) -> Option<bool> {
+        self.bus.cartridge.banking_mode()
+    }
+
+    /// Set the banking mode of a MBC1 cartridge, for debugging.
+    /// Other cartridges ignore this.
+    pub fn set_mbc1_banking_mode(&mut self, advanced: bool) {
+        self.bus.cartridge.set_banking_mode(advanced);
+    }
+
+    /// Get the ROM size declared in the cartridge header, in bytes.
+    /// Unknown size codes are reported as 0.
+    pub fn declared_rom_size(&self) -> usize {
+        decode_rom_size(self.bus.cartridge.read_byte(0x0148)).unwrap_or(0)
+    }
+
+    /// Check whether the ROM is smaller than its cartridge header declares,
+    /// which means the dump is bad. Returns the declared and the actual size
+    /// in bytes if it is. The missing banks read as 0xFF.
+    pub fn truncated_rom(&self) -> Option<(usize, usize)> {
+        let declared = self.declared_rom_size();
+
+        if self.bus.rom_size < declared {
+            Some((declared, self.bus.rom_size))
+        } else {
+            None
+        }
+    }
+
+    /// Get the external RAM size declared in the cartridge header, in bytes.
+    pub fn declared_ram_size(&self) -> usize {
+        decode_ram_size(self.bus.cartridge.read_byte(0x0149))
+    }
+
+    /// Get the number of 16 KiB ROM banks in the cartridge.
+    pub fn rom_bank_count(&self) -> usize {
+        self.bus.cartridge.rom_bank_count()
+    }
+
+    /// Get the number of 8 KiB external RAM banks in the cartridge.
+    pub fn ram_bank_count(&self) -> usize {
+        self.bus.cartridge.ram_bank_count()
+    }
+
+    /// Check if the emulator is running in CGB mode.
+    pub fn is_cgb(&self) -> bool {
+        self.bus.cgb_mode
+    }
+
+    /// Check if the game refuses to run on a DMG.
+    /// Such games should never be run in DMG mode.
+    pub fn is_cgb_only(&self) -> bool {
+        self.bus.cartridge.is_cgb_only()
+    }
+
+    /// Check that the given save state can be loaded by this version.
+    pub fn validate_save_state(data: &[u8]) -> Result<(), SaveStateError> {
+        savestate::read_header(data).map(|_| ())
+    }
+
+    /// Snapshot the whole machine. The ROM itself is not included,
+    /// only its checksum, so the state can only be loaded with the same ROM.
+    /// No host timestamps are stored either, so the same machine always
+    /// gives the same state. A cartridge clock resumes counting once loaded.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::new();
+
+        state.u64(self.total_cycles);
+        state.u64(self.frame_count);
+        self.cpu.save_state(&mut state);
+        self.bus.save_state(&mut state);
+
+        state.finish()
+    }
+
+    /// Restore a snapshot made by `save_state`.
+    ///
+    /// If the state turns out to be truncated or corrupted, the machine
+    /// is left as it was before the call.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), SaveStateError> {
+        let mut state = StateReader::new(data)?;
+
+        // The bus is decoded in place, so keep a snapshot of it to go
+        // back to if decoding fails halfway through.
+        let backup = self.save_state();
+
+        let result = self.decode_state(&mut state);
+
+        if result.is_err() {
+            let mut backup = StateReader::new(&backup).expect("invalid backup header");
+
+            self.decode_state(&mut backup)
+                .expect("failed to restore the backup state");
+        }
+
+        result
+    }
+
+    /// Decode the machine from a state, after its header.
+    fn decode_state(&mut self, state: &mut StateReader) -> Result<(), SaveStateError> {
+        let total_cycles = state.u64()?;
+        let frame_count = state.u64()?;
+        let mut cpu = Cpu::new();
+        cpu.load_state(state)?;
+
+        self.bus.load_state(state)?;
+        self.cpu = cpu;
+        self.total_cycles = total_cycles;
+        self.frame_count = frame_count;
+
+        Ok(())
+    }
+
+    /// Set a callback which is called with the address and value of
+    /// every write to an unmapped region, such as the ROM area of carts
+    /// without a MBC. Useful for catching unintended writes.
+    pub fn set_unmapped_write_callback(&mut self, callback: Box<dyn Fn(u16, u8)>) {
+        self.bus.unmapped_write_callback = Some(callback);
+    }
+
+    /// Set a callback for the CGB infrared port. It is called with the
+    /// state of the emulated IR LED, and should return whether IR light
+    /// is being received from the outside.
+    pub fn set_ir_callback(&mut self, callback: Box<dyn Fn(bool) -> bool>) {
+        self.bus.ir_callback = Some(callback);
+    }
+
+    /// Check if the cartridge has a battery, in which case its
+    /// SRAM should be saved.
+    pub fn has_battery(&self) -> bool {
+        self.bus.cartridge.has_battery()
+    }
+
+    /// Dump the SRAM and get a copy.
+    pub fn get_ram_dump(&self) -> Option<Vec<u8>> {
+        if !self.has_battery() {
+            return None;
+        }
+
+        self.bus.cartridge.dump_ram()
+    }
+
+    /// Set the cartridge's real time clock from the host's clock, in UTC.
+    /// This does nothing for cartridges without a clock.
+    ///
+    /// The host clock is not available on `wasm32-unknown-unknown`,
+    /// frontends there should use `set_rtc_time` instead.
+    pub fn sync_rtc_to_host(&mut self) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+        self.set_rtc_time(now);
+    }
+
+    /// Set the cartridge's real time clock to the given time since
+    /// the Unix epoch. This does nothing for cartridges without a clock.
+    pub fn set_rtc_time(&mut self, time: Duration) {
+        self.bus.cartridge.set_rtc(time);
+    }
+
+    /// Shut down the emulator, returning the SRAM that should be saved
+    /// if the cartridge has a battery. This is the same as calling
+    /// `get_ram_dump` and dropping the instance.
+    pub fn close(self) -> Option<Vec<u8>> {
+        self.get_ram_dump()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::rom_with_program;
+
+    /// Create an emulator running `program`, with the boot ROM skipped.
+    fn emulator(program: &[u8]) -> Argentum {
+        let mut argentum = Argentum::new(&rom_with_program(program), Box::new(|_| {}), None);
+        argentum.skip_bootrom();
+
+        argentum
+    }
+
+    #[test]
+    fn write_breakpoint_stops_the_frame() {
+        // LD A, 0x42; LD (0xC123), A; JR -2
+        let mut argentum = emulator(&[0x3E, 0x42, 0xEA, 0x23, 0xC1, 0x18, 0xFE]);
+
+        argentum.add_write_breakpoint(0xC100, 0xC1FF);
+
+        assert_eq!(
+            argentum.execute_frame(),
+            FrameResult::WriteBreakpoint {
+                addr: 0xC123,
+                value: 0x42
+            }
+        );
+        assert_eq!(argentum.cpu.reg.pc, 0x105);
+        assert_eq!(argentum.execute_frame(), FrameResult::Completed);
+    }
+
+    #[test]
+    fn write_breakpoint_ignores_dma() {
+        // LD A, 0xC0; LDH (0x46), A; JR -2
+        let mut argentum = emulator(&[0x3E, 0xC0, 0xE0, 0x46, 0x18, 0xFE]);
+
+        argentum.add_write_breakpoint(0xFE00, 0xFE9F);
+
+        assert_eq!(argentum.execute_frame(), FrameResult::Completed);
+    }
+
+    #[test]
+    fn stepping_consumes_write_breakpoints() {
+        // CALL 0x0200; JR -2, the subroutine writes to WRAM and returns.
+        let mut rom = rom_with_program(&[0xCD, 0x00, 0x02, 0x18, 0xFE]);
+        rom[0x200..0x205].copy_from_slice(&[0xEA, 0x00, 0xC0, 0x00, 0xC9]);
+
+        let mut argentum = Argentum::new(&rom, Box::new(|_| {}), None);
+        argentum.skip_bootrom();
+        argentum.add_write_breakpoint(0xC000, 0xC000);
+
+        argentum.step_over(CYCLES_PER_FRAME as u64);
+
+        assert_eq!(argentum.cpu.reg.pc, 0x203);
+        assert_eq!(argentum.execute_frame(), FrameResult::Completed);
+    }
+
+    #[test]
+    fn random_power_on_pattern_is_reproducible() {
+        let wram = |pattern| {
+            let mut argentum = emulator(&[0x18, 0xFE]);
+            argentum.set_power_on_pattern(pattern);
+
+            argentum.wram().to_vec()
+        };
+
+        let random = wram(PowerOnPattern::Random { seed: 1234 });
+
+        assert_eq!(random, wram(PowerOnPattern::Random { seed: 1234 }));
+        assert_ne!(random, wram(PowerOnPattern::Random { seed: 4321 }));
+        assert!(random.iter().any(|&byte| byte != random[0]));
+        assert!(wram(PowerOnPattern::Ones).iter().all(|&byte| byte == 0xFF));
+    }
+
+    #[test]
+    fn paused_ppu_keeps_ly_while_cpu_runs() {
+        // NOP sled
+        let mut argentum = emulator(&[0x00; 0x100]);
+
+        for _ in 0..200 {
+            argentum.step_instruction();
+        }
+
+        argentum.set_ppu_paused(true);
+
+        let ly = argentum.bus.read_byte(0xFF44, false);
+        let pc = argentum.cpu.reg.pc;
+
+        for _ in 0..100 {
+            argentum.step_instruction();
+        }
+
+        assert_eq!(argentum.bus.read_byte(0xFF44, false), ly);
+        assert_eq!(argentum.cpu.reg.pc, pc + 100);
+
+        argentum.set_ppu_paused(false);
+
+        for _ in 0..200 {
+            argentum.step_instruction();
+        }
+
+        assert_ne!(argentum.bus.read_byte(0xFF44, false), ly);
+    }
+
+    #[test]
+    fn stop_skips_its_operand() {
+        // STOP; NOP
+        let mut argentum = emulator(&[0x10, 0x00, 0x00]);
+
+        argentum.step_instruction();
+
+        assert_eq!(argentum.cpu.reg.pc, 0x102);
+    }
+
+    #[test]
+    fn wram_and_hram_offsets() {
+        let mut argentum = emulator(&[0x18, 0xFE]);
+
+        argentum.bus.write_byte(0xC010, 0x12, false);
+        argentum.bus.write_byte(0xD123, 0x34, false);
+        argentum.bus.write_byte(0xFF85, 0x56, false);
+
+        assert_eq!(argentum.wram()[0x0010], 0x12);
+        assert_eq!(argentum.wram()[0x1123], 0x34);
+        assert_eq!(argentum.hram()[0x05], 0x56);
+    }
+
+    /// Create an emulator which calls a subroutine at 0x0200,
+    /// which increments A twice and returns.
+    fn emulator_with_subroutine() -> Argentum {
+        // CALL 0x0200; JR -2
+        let mut rom = rom_with_program(&[0xCD, 0x00, 0x02, 0x18, 0xFE]);
+        // INC A; INC A; RET
+        rom[0x200..0x203].copy_from_slice(&[0x3C, 0x3C, 0xC9]);
+
+        let mut argentum = Argentum::new(&rom, Box::new(|_| {}), None);
+        argentum.skip_bootrom();
+        argentum.cpu.reg.a = 0;
+
+        argentum
+    }
+
+    #[test]
+    fn step_over_runs_the_whole_call() {
+        let mut argentum = emulator_with_subroutine();
+        let sp = argentum.cpu.reg.sp;
+
+        argentum.step_over(CYCLES_PER_FRAME as u64);
+
+        assert_eq!(argentum.cpu.reg.pc, 0x103);
+        assert_eq!(argentum.cpu.reg.sp, sp);
+        assert_eq!(argentum.cpu.reg.a, 2);
+    }
+
+    #[test]
+    fn step_over_gives_up_on_calls_which_never_return() {
+        // CALL 0x0200; JR -2, the subroutine loops forever.
+        let mut rom = rom_with_program(&[0xCD, 0x00, 0x02, 0x18, 0xFE]);
+        rom[0x200..0x202].copy_from_slice(&[0x18, 0xFE]);
+
+        let mut argentum = Argentum::new(&rom, Box::new(|_| {}), None);
+        argentum.skip_bootrom();
+
+        let cycles = argentum.step_over(1000);
+
+        assert!((1000..1100).contains(&cycles));
+        assert_eq!(argentum.cpu.reg.pc, 0x200);
+
+        // Or lock up the CPU.
+        rom[0x200] = 0xD3;
+
+        let mut argentum = Argentum::new(&rom, Box::new(|_| {}), None);
+        argentum.skip_bootrom();
+        argentum.set_illegal_opcode_lockup(true);
+
+        assert!(argentum.step_over(u64::MAX) < 100);
+        assert_eq!(argentum.cpu.reg.pc, 0x201);
+    }
+
+    #[test]
+    fn step_out_returns_to_the_caller() {
+        let mut argentum = emulator_with_subroutine();
+        let sp = argentum.cpu.reg.sp;
+
+        argentum.step_instruction();
+        argentum.step_instruction();
+        assert_eq!(argentum.cpu.reg.pc, 0x201);
+
+        argentum.step_out(CYCLES_PER_FRAME as u64);
+
+        assert_eq!(argentum.cpu.reg.pc, 0x103);
+        assert_eq!(argentum.cpu.reg.sp, sp);
+        assert_eq!(argentum.cpu.reg.a, 2);
+    }
+
+    #[test]
+    fn step_out_gives_up_on_subroutines_which_never_return() {
+        // JR -2
+        let mut argentum = emulator(&[0x18, 0xFE]);
+        let cycles = argentum.step_out(1000);
+
+        assert!((1000..1100).contains(&cycles));
+        assert_eq!(argentum.cpu.reg.pc, 0x100);
+
+        // Illegal 0xD3
+        let mut argentum = emulator(&[0xD3]);
+        argentum.set_illegal_opcode_lockup(true);
+
+        assert!(argentum.step_out(u64::MAX) < 100);
+        assert_eq!(argentum.cpu.reg.pc, 0x101);
+    }
+
+    #[test]
+    fn truncated_roms_are_reported() {
+        // MBC5, 2 MB declared but only 512 KB present.
+        let mut rom = rom_with_program(&[0x18, 0xFE]);
+        rom.resize(0x80000, 0);
+        rom[0x0147] = 0x19;
+        rom[0x0148] = 0x06;
+
+        let mut argentum = Argentum::new(&rom, Box::new(|_| {}), None);
+
+        assert_eq!(argentum.truncated_rom(), Some((0x200000, 0x80000)));
+
+        // The missing banks read as 0xFF.
+        argentum.bus.write_byte(0x2000, 0x7F, false);
+        assert_eq!(argentum.bus.read_byte(0x4000, false), 0xFF);
+
+        rom.resize(0x200000, 0);
+        let argentum = Argentum::new(&rom, Box::new(|_| {}), None);
+
+        assert_eq!(argentum.truncated_rom(), None);
+    }
+
+    #[test]
+    fn rom_hash_follows_the_contents() {
+        let rom = rom_with_program(&[0x18, 0xFE]);
+        let hash = |rom: &[u8]| Argentum::new(rom, Box::new(|_| {}), None).rom_hash();
+
+        assert_eq!(hash(&rom), hash(&rom));
+        assert_eq!(hash(&rom), crate::util::crc32(&rom));
+
+        let mut modified = rom.clone();
+        modified[0x4000] ^= 0xFF;
+
+        assert_ne!(hash(&rom), hash(&modified));
+    }
+
+    #[test]
+    fn one_frame_takes_about_16_74_ms() {
+        let mut argentum = emulator(&[0x18, 0xFE]);
+
+        argentum.execute_frame();
+        let start = argentum.emulated_time();
+
+        argentum.execute_frame();
+        let elapsed = (argentum.emulated_time() - start).as_secs_f64() * 1000.0;
+
+        assert!((elapsed - 16.74).abs() < 0.01, "{} ms", elapsed);
+    }
+
+    #[test]
+    fn pop_af_masks_the_lower_nibble_of_f() {
+        // LD BC, 0x12FF; PUSH BC; POP AF
+        let mut argentum = emulator(&[0x01, 0xFF, 0x12, 0xC5, 0xF1]);
+
+        for _ in 0..3 {
+            argentum.step_instruction();
+        }
+
+        assert_eq!(argentum.cpu.reg.get_af(), 0x12F0);
+    }
+
+    #[test]
+    fn advance_is_deterministic() {
+        // Poll the buttons into WRAM forever:
+        // LD A, 0x10; LDH (0x00), A; LDH A, (0x00); LD (HL+), A; JR -6
+        let program = [0x3E, 0x10, 0xE0, 0x00, 0xF0, 0x00, 0x22, 0x18, 0xFA];
+        let inputs = [0x00, 0x10, 0x10, 0x90, 0x00, 0x01, 0x20, 0x00];
+
+        let run = || {
+            let mut argentum = emulator(&program);
+            argentum.cpu.reg.set_hl(0xC000);
+            argentum.set_joypad_latency(true);
+
+            inputs
+                .iter()
+                .map(|&input| {
+                    let output = argentum.advance(input);
+
+                    let mut frame = output.framebuffer;
+                    frame.extend(output.audio.iter().flat_map(|s| s.to_le_bytes().to_vec()));
+                    frame.extend_from_slice(argentum.wram());
+
+                    crate::util::crc32(&frame)
+                })
+                .collect::<Vec<u32>>()
+        };
+
+        assert_eq!(run(), run());
+    }
+
+    #[test]
+    fn advance_keeps_capturing_and_reports_breakpoints() {
+        // LD A, 0x42; LD (0xC000), A; JR -2
+        let mut argentum = emulator(&[0x3E, 0x42, 0xEA, 0x00, 0xC0, 0x18, 0xFE]);
+
+        argentum.bus.apu.capture_samples = true;
+        argentum.add_write_breakpoint(0xC000, 0xC000);
+
+        let output = argentum.advance(0x00);
+
+        assert_eq!(
+            output.result,
+            FrameResult::WriteBreakpoint {
+                addr: 0xC000,
+                value: 0x42
+            }
+        );
+
+        let output = argentum.advance(0x00);
+        let captured = &argentum.bus.apu.captured_samples;
+
+        assert_eq!(output.result, FrameResult::Completed);
+        assert!(!output.audio.is_empty());
+        assert!(captured.ends_with(&output.audio));
+        assert!(argentum.bus.apu.capture_samples);
+    }
+
+    #[test]
+    fn fast_boot_reaches_the_entry_point_sooner() {
+        let frames_to_boot = |fast_boot| {
+            let mut argentum =
+                Argentum::new(&rom_with_program(&[0x18, 0xFE]), Box::new(|_| {}), None);
+            argentum.set_fast_boot(fast_boot);
+
+            let mut frames = 0;
+
+            while argentum.boot_rom_mapped() {
+                argentum.execute_frame();
+                frames += 1;
+            }
+
+            assert_eq!(argentum.cpu.reg.pc, 0x100);
+            frames
+        };
+
+        let normal = frames_to_boot(false);
+
+        assert_eq!(frames_to_boot(true), 1);
+        assert!(normal > 60, "{} frames", normal);
+    }
+
+    #[test]
+    fn add_hl_boundaries() {
+        // (HL, BC, F before, HL after, F after), Z is left untouched.
+        let cases = [
+            (0x0FFF, 0x0001, 0x00, 0x1000, 0x20),
+            (0x0FFF, 0x0001, 0x80, 0x1000, 0xA0),
+            (0x0FFE, 0x0001, 0x40, 0x0FFF, 0x00),
+            (0xFFFF, 0x0001, 0x80, 0x0000, 0xB0),
+            (0x8000, 0x8000, 0x00, 0x0000, 0x10),
+            (0xF000, 0x1000, 0x00, 0x0000, 0x10),
+        ];
+
+        for &(hl, bc, f, result, flags) in cases.iter() {
+            // ADD HL, BC
+            let mut argentum = emulator(&[0x09]);
+
+            argentum.cpu.reg.set_hl(hl);
+            argentum.cpu.reg.set_bc(bc);
+            argentum.cpu.reg.set_af(f);
+
+            assert_eq!(argentum.step_instruction(), 8);
+            assert_eq!(argentum.cpu.reg.get_hl(), result);
+            assert_eq!(
+                argentum.cpu.reg.get_af() & 0xFF,
+                flags,
+                "{:04X} + {:04X}",
+                hl,
+                bc
+            );
+        }
+    }
+
+    #[test]
+    fn boot_rom_unmaps_on_ff50_write() {
+        let mut argentum = Argentum::new(&rom_with_program(&[]), Box::new(|_| {}), None);
+        assert!(argentum.boot_rom_mapped());
+
+        argentum.bus.write_byte(0xFF50, 0x01, false);
+        assert!(!argentum.boot_rom_mapped());
+    }
+
+    #[test]
+    fn one_vblank_callback_per_frame() {
+        use std::{cell::RefCell, rc::Rc};
+
+        let mut argentum = emulator(&[0x18, 0xFE]);
+        let frames = Rc::new(RefCell::new(0));
+        let counter = frames.clone();
+
+        argentum.set_vblank_callback(Box::new(move |framebuffer| {
+            assert_eq!(framebuffer.len(), 160 * 144 * 4);
+            *counter.borrow_mut() += 1;
+        }));
+
+        argentum.execute_frame();
+        *frames.borrow_mut() = 0;
+
+        for _ in 0..10 {
+            argentum.execute_frame();
+        }
+
+        assert_eq!(*frames.borrow(), 10);
+    }
+
+    #[test]
+    fn close_returns_the_ram_dump() {
+        let mut rom = rom_with_program(&[0x18, 0xFE]);
+        rom[0x0147] = 0x03;
+        rom[0x0149] = 0x02;
+
+        let mut argentum = Argentum::new(&rom, Box::new(|_| {}), None);
+        argentum.skip_bootrom();
+        argentum.bus.write_byte(0x0000, 0x0A, false);
+        argentum.bus.write_byte(0xA000, 0x12, false);
+        argentum.bus.write_byte(0xBFFF, 0x34, false);
+
+        let dump = argentum.get_ram_dump().unwrap();
+        assert_eq!((dump.len(), dump[0], dump[0x1FFF]), (0x2000, 0x12, 0x34));
+        assert_eq!(argentum.close(), Some(dump));
+
+        // Cartridges without a battery have nothing to save.
+        rom[0x0147] = 0x02;
+        assert_eq!(Argentum::new(&rom, Box::new(|_| {}), None).close(), None);
+    }
+
+    #[test]
+    fn av_callback_receives_a_frame_of_audio_and_video() {
+        use std::{cell::RefCell, rc::Rc};
+
+        let mut argentum = emulator(&[0x18, 0xFE]);
+        let frames = Rc::new(RefCell::new(Vec::new()));
+        let log = frames.clone();
+
+        argentum.set_av_callback(Box::new(move |framebuffer, samples| {
+            log.borrow_mut().push((framebuffer.len(), samples.len()));
+        }));
+
+        for _ in 0..3 {
+            argentum.execute_frame();
+        }
+
+        // About 48000 / 59.73 = 803.6 stereo samples per frame.
+        for &(framebuffer, samples) in frames.borrow().iter() {
+            assert_eq!(framebuffer, 160 * 144 * 4);
+            assert_eq!(samples % 2, 0);
+            assert!((1600..=1616).contains(&samples), "{} samples", samples);
+        }
+
+        assert_eq!(frames.borrow().len(), 3);
+    }
+
+    #[test]
+    fn framebuffer_len_matches_the_framebuffer() {
+        let argentum = emulator(&[0x18, 0xFE]);
+
+        assert_eq!(
+            Argentum::framebuffer_len(),
+            argentum.get_framebuffer().len()
+        );
+        assert_eq!(Argentum::framebuffer_len(), 160 * 144 * 4);
+    }
+
+    #[test]
+    fn is_cgb_follows_the_header() {
+        let mut rom = rom_with_program(&[]);
+        assert!(!Argentum::new(&rom, Box::new(|_| {}), None).is_cgb());
+
+        rom[0x0143] = 0x80;
+        assert!(Argentum::new(&rom, Box::new(|_| {}), None).is_cgb());
+
+        rom[0x0143] = 0xC0;
+        assert!(Argentum::new(&rom, Box::new(|_| {}), None).is_cgb());
+    }
+
+    #[test]
+    fn bank_counts_of_a_512_kb_cartridge() {
+        // MBC1 + RAM + BATTERY, 512 KB ROM and 32 KB RAM.
+        let mut rom = rom_with_program(&[]);
+        rom.resize(0x80000, 0);
+        rom[0x0147] = 0x03;
+        rom[0x0148] = 0x04;
+        rom[0x0149] = 0x03;
+
+        let argentum = Argentum::new(&rom, Box::new(|_| {}), None);
+
+        assert_eq!(argentum.rom_bank_count(), 32);
+        assert_eq!(argentum.ram_bank_count(), 4);
+    }
+
+    #[test]
+    fn step_scanline_walks_the_visible_lines() {
+        // JR -2
+        let mut argentum = emulator(&[0x18, 0xFE]);
+
+        for ly in 0..144 {
+            let line = argentum.step_scanline().map(|line| line.len());
+
+            assert_eq!(line, Some(160 * 4));
+            assert_eq!(argentum.bus.read_byte(0xFF44, false), ly);
+        }
+
+        // The next line drawn is the first one of the next frame.
+        assert!(argentum.step_scanline().is_some());
+        assert_eq!(argentum.bus.read_byte(0xFF44, false), 0);
+    }
+
+    #[test]
+    fn bench_frames_advances_the_frame_counter() {
+        use std::{cell::Cell, rc::Rc};
+
+        let calls = Rc::new(Cell::new(0));
+        let counter = calls.clone();
+
+        // JR -2
+        let mut argentum = Argentum::new(
+            &rom_with_program(&[0x18, 0xFE]),
+            Box::new(move |_| counter.set(counter.get() + 1)),
+            None,
+        );
+        argentum.skip_bootrom();
+
+        argentum.bench_frames(5);
+        assert_eq!(argentum.frame_count(), 5);
+        assert_eq!(calls.get(), 0);
+
+        argentum.bench_frames(3);
+        assert_eq!(argentum.frame_count(), 8);
+    }
+
+    #[test]
+    fn one_frame_adds_about_70224_cycles() {
+        // JR -2
+        let mut argentum = emulator(&[0x18, 0xFE]);
+        assert_eq!(argentum.total_cycles(), 0);
+
+        argentum.execute_frame();
+        let start = argentum.total_cycles();
+
+        argentum.execute_frame();
+        let elapsed = argentum.total_cycles() - start;
+
+        // A frame can overshoot by the length of one instruction.
+        assert!((70224..70224 + 24).contains(&elapsed), "{}", elapsed);
+    }
+
+    #[test]
+    fn drop_mode_skips_audio_while_fast_forwarding() {
+        use std::{cell::Cell, rc::Rc};
+
+        let calls = Rc::new(Cell::new(0));
+        let counter = calls.clone();
+
+        // JR -2
+        let mut argentum = Argentum::new(
+            &rom_with_program(&[0x18, 0xFE]),
+            Box::new(move |_| counter.set(counter.get() + 1)),
+            None,
+        );
+        argentum.skip_bootrom();
+
+        argentum.execute_frames(3);
+        assert!(calls.get() > 0);
+
+        calls.set(0);
+        argentum.set_turbo_audio(TurboAudio::Drop);
+        argentum.execute_frames(3);
+        assert_eq!(calls.get(), 0);
+
+        // Audio comes back once the fast-forward is over.
+        argentum.execute_frame();
+        assert!(calls.get() > 0);
+    }
+
+    #[test]
+    fn key1_is_inert_on_a_dmg() {
+        // LD A, 0x01; LDH (KEY1), A; STOP; JR -2
+        let mut rom = rom_with_program(&[0x3E, 0x01, 0xE0, 0x4D, 0x10, 0x00, 0x18, 0xFE]);
+
+        let mut argentum = Argentum::new(&rom, Box::new(|_| {}), None);
+        argentum.skip_bootrom();
+        argentum.execute_frame();
+
+        assert!(!argentum.bus.is_double_speed());
+        assert_eq!(argentum.bus.read_byte(0xFF4D, false), 0xFF);
+
+        // The same program switches speed in CGB mode.
+        rom[0x0143] = 0x80;
+
+        let mut argentum = Argentum::new(&rom, Box::new(|_| {}), None);
+        argentum.skip_bootrom();
+        argentum.execute_frame();
+
+        assert!(argentum.bus.is_double_speed());
+    }
+
+    #[test]
+    fn illegal_opcodes_trigger_the_callback() {
+        use std::{cell::RefCell, rc::Rc};
+
+        // NOP; illegal 0xD3; NOP; JR -2
+        let mut argentum = emulator(&[0x00, 0xD3, 0x00, 0x18, 0xFE]);
+
+        let hits = Rc::new(RefCell::new(Vec::new()));
+        let recorder = hits.clone();
+        argentum.set_illegal_opcode_callback(Box::new(move |pc, opcode| {
+            recorder.borrow_mut().push((pc, opcode))
+        }));
+
+        for _ in 0..3 {
+            argentum.step_instruction();
+        }
+
+        assert_eq!(*hits.borrow(), [(0x0101, 0xD3)]);
+        assert_eq!(argentum.cpu.reg.pc, 0x0103);
+
+        // With lock ups enabled, the CPU stops at the illegal opcode.
+        let mut argentum = emulator(&[0x00, 0xD3, 0x00, 0x18, 0xFE]);
+        argentum.set_illegal_opcode_lockup(true);
+
+        for _ in 0..10 {
+            argentum.step_instruction();
+        }
+
+        assert_eq!(argentum.cpu.reg.pc, 0x0102);
+    }
+
+    #[test]
+    fn memory_dump_offsets() {
+        let mut argentum = emulator(&[]);
+
+        argentum.bus.write_byte(0xC123, 0x42, false);
+        argentum.bus.write_byte(0xFF90, 0x24, false);
+
+        let dump = argentum.memory_dump();
+
+        assert_eq!(dump[0xC123], 0x42);
+        assert_eq!(dump[0xFF90], 0x24);
+        // Echo RAM mirrors WRAM.
+        assert_eq!(dump[0xE123], 0x42);
+    }
+
+    #[test]
+    fn from_image_starts_in_the_given_state() {
+        let mut image = FullStateImage {
+            af: 0x12F0,
+            bc: 0x3456,
+            de: 0x789A,
+            hl: 0xBCDE,
+            sp: 0xDFF0,
+            pc: 0x0150,
+            ime: true,
+            ie: 0x05,
+            interrupt_flags: 0x01,
+            wram: vec![0; 0x8000],
+            vram: vec![0; 0x4000],
+            oam: vec![0; 0xA0],
+            hram: vec![0; 0x7F],
+        };
+
+        image.wram[0x0123] = 0x42;
+        image.hram[0x10] = 0x24;
+        image.oam[0x05] = 0x99;
+
+        let rom = rom_with_program(&[]);
+        let mut argentum = Argentum::from_image(&rom, &image, Box::new(|_| {})).unwrap();
+
+        let reg = &argentum.cpu.reg;
+        assert_eq!(
+            [
+                reg.get_af(),
+                reg.get_bc(),
+                reg.get_de(),
+                reg.get_hl(),
+                reg.sp,
+                reg.pc
+            ],
+            [0x12F0, 0x3456, 0x789A, 0xBCDE, 0xDFF0, 0x0150]
+        );
+        assert!(argentum.cpu.ime);
+        assert_eq!(argentum.bus.ie_reg, 0x05);
+        assert_eq!(*argentum.bus.if_reg.borrow(), 0x01);
+        assert_eq!(argentum.bus.read_byte(0xC123, false), 0x42);
+        assert_eq!(argentum.bus.read_byte(0xFF90, false), 0x24);
+        assert_eq!(argentum.bus.ppu.read_byte(0xFE05), 0x99);
+
+        // Memories of the wrong size are rejected.
+        image.hram.pop();
+        assert_eq!(
+            Argentum::from_image(&rom, &image, Box::new(|_| {})).err(),
+            Some(SaveStateError::Truncated)
+        );
+    }
+
+    /// Create an emulator running a program which keeps moving the
+    /// background around, on a MBC3 cartridge with a clock.
+    fn scrolling_emulator() -> Argentum {
+        // INC A; LDH (SCY), A; LDH (SCX), A; JR -7
+        let mut rom = rom_with_program(&[0x3C, 0xE0, 0x42, 0xE0, 0x43, 0x18, 0xF9]);
+        rom[0x0147] = 0x10;
+        rom[0x0149] = 0x03;
+
+        let mut argentum = Argentum::new(&rom, Box::new(|_| {}), None);
+        argentum.skip_bootrom();
+
+        // Fill the first tile, which the whole tile map refers to.
+        for i in 0..16 {
+            argentum.bus.ppu.write_byte(0x8000 + i, (i * 37) as u8);
+        }
+
+        argentum
+    }
+
+    #[test]
+    fn save_states_are_deterministic() {
+        let mut argentum = scrolling_emulator();
+        argentum.execute_frames(3);
+
+        let state = argentum.save_state();
+        assert_eq!(argentum.save_state(), state);
+
+        argentum.execute_frames(3);
+        let frame = argentum.get_framebuffer().to_vec();
+        let later = argentum.save_state();
+
+        // Running on from the restored state gives the same frames.
+        argentum.load_state(&state).unwrap();
+        argentum.execute_frames(3);
+
+        assert_eq!(argentum.get_framebuffer(), &frame[..]);
+        assert_eq!(argentum.save_state(), later);
+
+        // The same goes for a fresh instance.
+        let mut restored = scrolling_emulator();
+        restored.load_state(&state).unwrap();
+        restored.execute_frames(3);
+
+        assert_eq!(restored.get_framebuffer(), &frame[..]);
+    }
+
+    #[test]
+    fn failed_loads_leave_the_machine_untouched() {
+        let mut argentum = scrolling_emulator();
+        let state = argentum.save_state();
+
+        argentum.execute_frames(2);
+        let current = argentum.save_state();
+
+        for &len in [20, state.len() / 2, state.len() - 1].iter() {
+            assert_eq!(
+                argentum.load_state(&state[..len]),
+                Err(SaveStateError::Truncated)
+            );
+            assert_eq!(argentum.save_state(), current);
+        }
+    }
+
+    #[test]
+    fn cheat_files_load_and_write_to_ram() {
+        // JR -2
+        let mut argentum = emulator(&[0x18, 0xFE]);
+
+        let file = "# Infinite health\n\
+                    019934C1 on Health\n\
+                    \n\
+                    00A-17B off Skip intro\n\
+                    010040FF LCD off\n";
+
+        assert_eq!(argentum.load_cheat_file(file), Ok(2));
+        assert_eq!(argentum.cheats().len(), 3);
+        assert_eq!(argentum.cheats()[0].name, "Health");
+        assert!(!argentum.cheats()[1].enabled);
+        assert_eq!(argentum.cheats()[2].name, "LCD off");
+
+        assert!(argentum.set_cheat_enabled(1, true));
+        assert!(!argentum.set_cheat_enabled(3, true));
+
+        let lcdc = argentum.bus.read_byte(0xFF40, false);
+        argentum.execute_frame();
+
+        // The cheat lands in WRAM, but cheats pointing at IO registers are ignored.
+        assert_eq!(argentum.bus.read_byte(0xC134, false), 0x99);
+        assert_eq!(argentum.bus.read_byte(0xFF40, false), lcdc);
+
+        assert_eq!(
+            argentum.load_cheat_file("019934C1\nnot a code\n"),
+            Err(CheatError::InvalidCode { line: 2 })
+        );
+        assert_eq!(argentum.cheats().len(), 3);
+    }
+
+    #[test]
+    fn rewind_retraces_the_recorded_frames() {
+        // INC A; NOP x5; JR -8
+        let mut argentum = emulator(&[0x3C, 0x00, 0x00, 0x00, 0x00, 0x00, 0x18, 0xF8]);
+        let mut history = Vec::new();
+
+        argentum.enable_rewind(8);
+
+        for _ in 0..12 {
+            history.push((argentum.cpu.reg.pc, argentum.cpu.reg.a));
+            argentum.execute_frame();
+        }
+
+        // Only the start of the last eight frames is kept.
+        for &(pc, a) in history.iter().rev().take(8) {
+            assert!(argentum.rewind_step());
+            assert_eq!(argentum.cpu.reg.pc, pc);
+            assert_eq!(argentum.cpu.reg.a, a);
+        }
+
+        assert!(!argentum.rewind_step());
+
+        argentum.disable_rewind();
+        argentum.execute_frame();
+        assert!(!argentum.rewind_step());
+    }
+
+    #[test]
+    fn from_reader_reads_the_whole_rom() {
+        use std::io::Cursor;
+
+        // LD A, 0x42; JR -2
+        let rom = rom_with_program(&[0x3E, 0x42, 0x18, 0xFE]);
+        let mut argentum =
+            Argentum::from_reader(Cursor::new(&rom), Box::new(|_| {}), None).unwrap();
+
+        argentum.skip_bootrom();
+
+        for addr in 0..0x8000 {
+            assert_eq!(argentum.bus.read_byte(addr, false), rom[addr as usize]);
+        }
+
+        argentum.execute_frame();
+        assert_eq!(argentum.cpu.reg.a, 0x42);
+
+        let error = Argentum::from_reader(Cursor::new(&rom[..0x14F]), Box::new(|_| {}), None)
+            .err()
+            .unwrap();
+
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn battery_saves_round_trip_on_mbc1_and_mbc5() {
+        // LD A, 0x0A; LD (0x0000), A; LD A, 0x42; LD (0xA123), A; JR -2
+        let program = [
+            0x3E, 0x0A, 0xEA, 0x00, 0x00, 0x3E, 0x42, 0xEA, 0x23, 0xA1, 0x18, 0xFE,
+        ];
+
+        for &cartridge_type in [0x03, 0x1B].iter() {
+            // 8 KB of RAM.
+            let mut rom = rom_with_program(&program);
+            rom[0x0147] = cartridge_type;
+            rom[0x0149] = 0x02;
+
+            let mut argentum = Argentum::new(&rom, Box::new(|_| {}), None);
+            argentum.skip_bootrom();
+            argentum.execute_frame();
+
+            let save = argentum.get_ram_dump().unwrap();
+            assert_eq!(save.len(), 0x2000);
+            assert_eq!(save[0x123], 0x42);
+
+            let mut argentum = Argentum::new(&rom, Box::new(|_| {}), Some(save.clone()));
+            argentum.skip_bootrom();
+
+            argentum.bus.write_byte(0x0000, 0x0A, false);
+            assert_eq!(argentum.bus.read_byte(0xA123, false), 0x42);
+            assert_eq!(argentum.get_ram_dump(), Some(save));
+        }
+    }
+
+    #[test]
+    fn declared_sizes_decode_the_size_codes() {
+        let cases = [
+            // ROM only, 32 KB ROM and no RAM.
+            (0x00, 0x00, 0x00, 0x8000, 0),
+            // MBC1 + RAM, 512 KB ROM and 32 KB RAM.
+            (0x02, 0x04, 0x03, 0x80000, 0x8000),
+            // MBC5 + RAM, 1.1 MB ROM and 64 KB RAM.
+            (0x1A, 0x52, 0x05, 0x120000, 0x10000),
+            // Unknown size codes.
+            (0x1A, 0x0F, 0x0F, 0, 0),
+        ];
+
+        for &(cartridge_type, rom_code, ram_code, rom_size, ram_size) in cases.iter() {
+            let mut rom = rom_with_program(&[]);
+            rom.resize(rom_size.max(0x8000), 0);
+            rom[0x0147] = cartridge_type;
+            rom[0x0148] = rom_code;
+            rom[0x0149] = ram_code;
+
+            let argentum = Argentum::new(&rom, Box::new(|_| {}), None);
+
+            assert_eq!(argentum.declared_rom_size(), rom_size);
+            assert_eq!(argentum.declared_ram_size(), ram_size);
+        }
+    }
+
+    #[test]
+    fn run_until_pc_stops_at_the_target() {
+        // LD B, 5; DEC B; JR NZ, -3; LD A, 0x42; LD (0xC000), A; JR -2
+        let mut argentum = emulator(&[
+            0x06, 0x05, 0x05, 0x20, 0xFD, 0x3E, 0x42, 0xEA, 0x00, 0xC0, 0x18, 0xFE,
+        ]);
+
+        assert!(argentum.run_until_pc(0x105, 1000));
+        assert_eq!(argentum.cpu.reg.b, 0);
+        assert_ne!(argentum.cpu.reg.a, 0x42);
+
+        // Already there, so nothing is executed.
+        assert!(argentum.run_until_pc(0x105, 0));
+
+        // The loop at the end never gets to the target.
+        assert!(!argentum.run_until_pc(0x200, 1000));
+        assert_eq!(argentum.cpu.reg.a, 0x42);
+
+        // A write breakpoint stops it early.
+        let mut argentum = emulator(&[0x3E, 0x42, 0xEA, 0x00, 0xC0, 0x18, 0xFE]);
+        argentum.add_write_breakpoint(0xC000, 0xC000);
+
+        assert!(!argentum.run_until_pc(0x200, 1000));
+        assert_eq!(argentum.cpu.reg.pc, 0x105);
     }
 }
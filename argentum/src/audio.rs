@@ -1,3 +1,5 @@
+use crate::savestate::{ensure, SaveStateError, StateReader, StateWriter};
+
 /// The rate at which samples are consumed by the audio
 /// driver.
 pub const SAMPLE_RATE: usize = 48000;
@@ -8,6 +10,12 @@ pub const BUFFER_SIZE: usize = 1024;
 /// The rate at which the CPU is ticked.
 pub const CPU_CLOCK: usize = 4194304;
 
+/// How much charge the high-pass capacitor keeps per T-cycle.
+const CAPACITOR_CHARGE: f32 = 0.999958;
+
+/// Called with the interleaved stereo samples whenever the buffer is full.
+pub type AudioCallback = Box<dyn Fn(&[f32])>;
+
 /// Table for all the defined wave duties.
 const WAVE_DUTY: [[u8; 8]; 4] = [
     [0, 0, 0, 0, 0, 0, 0, 1], // 12.5%
@@ -16,6 +24,40 @@ const WAVE_DUTY: [[u8; 8]; 4] = [
     [0, 1, 1, 1, 1, 1, 1, 0], // 75%
 ];
 
+/// How the number of generated samples is adjusted, to keep the
+/// audio in sync with a sink which runs slightly fast or slow.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AudioSync {
+    /// Samples are generated at the nominal rate.
+    Off,
+
+    /// One sample is dropped from every buffer, for when too
+    /// much audio is queued up.
+    Skip,
+
+    /// One sample is repeated in every buffer, for when the
+    /// queued audio keeps running out.
+    Stretch,
+}
+
+/// Get the new volume of a running channel whose envelope register
+/// (NRx2) was written, which alters the current volume ("zombie" mode).
+fn zombie_volume(volume: u8, period: u8, is_incrementing: bool, value: u8) -> u8 {
+    let mut volume = volume;
+
+    if period == 0 {
+        volume += 1;
+    } else if !is_incrementing {
+        volume += 2;
+    }
+
+    if is_incrementing != ((value & 0x08) != 0) {
+        volume = 16u8.wrapping_sub(volume);
+    }
+
+    volume & 0x0F
+}
+
 pub trait Channel {
     /// Read a byte from the specified address.
     fn read_byte(&self, addr: u16) -> u8;
@@ -29,8 +71,20 @@ pub trait Channel {
     /// Get the current amplitude of the channel.
     fn get_amplitude(&self) -> f32;
 
+    /// Get the current 4-bit digital output of the channel.
+    fn digital_output(&self) -> u8;
+
+    /// Get the pitch of the channel in Hz.
+    fn frequency_hz(&self) -> f32;
+
     /// Step the length timer of the channel.
     fn step_length(&mut self);
+
+    /// Serialize the state of the channel.
+    fn save_state(&self, state: &mut StateWriter);
+
+    /// Restore the state of the channel.
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), SaveStateError>;
 }
 
 pub struct Apu {
@@ -58,7 +112,10 @@ pub struct Apu {
     /// Implementation of the noise wave channel.
     channel_four: ChannelFour,
 
-    /// Used to clock FS and sample generation.
+    /// T-cycles since the frame sequencer was last stepped.
+    frame_sequencer_clock: u16,
+
+    /// T-cycles since the last sample was generated.
     sample_clock: u32,
 
     /// The audio buffer which contains 32-bit float samples.
@@ -71,7 +128,7 @@ pub struct Apu {
     pub is_buffer_full: bool,
 
     /// Audio callback which is called when the sample buffer is full.
-    callback: Box<dyn Fn(&[f32])>,
+    callback: AudioCallback,
 
     /// The position the FS is currently in.
     frame_sequencer_position: u8,
@@ -81,11 +138,49 @@ pub struct Apu {
 
     /// Stub
     right_vin: bool,
+
+    /// Stereo panning of each channel, from -1.0 (left) to 1.0 (right).
+    channel_pan: [f32; 4],
+
+    /// Channels left out of the mix, they keep running regardless.
+    channel_muted: [bool; 4],
+
+    /// Volume of the output, from 0.0 (silent) to 1.0 (full).
+    master_volume: f32,
+
+    /// How the number of generated samples is adjusted.
+    audio_sync: AudioSync,
+
+    /// Whether the current buffer already had a sample dropped or repeated.
+    buffer_synced: bool,
+
+    /// Whether generated samples are also collected in `captured_samples`.
+    pub capture_samples: bool,
+
+    /// Samples generated while capturing was enabled.
+    pub captured_samples: Vec<f32>,
+
+    /// Copy of the most recently filled sample buffer.
+    pub last_buffer: Vec<f32>,
+
+    /// Whether the output goes through a high-pass filter, which removes
+    /// the DC offset like the capacitors on the hardware.
+    highpass_enabled: bool,
+
+    /// The charge of the left and right high-pass capacitors.
+    capacitors: [f32; 2],
+
+    /// How much charge the capacitors keep between samples.
+    capacitor_charge: f32,
+
+    /// Whether samples are generated at all. The channels keep
+    /// running when this is disabled.
+    pub output_enabled: bool,
 }
 
 impl Apu {
     /// Create a new `Apu` instance.
-    pub fn new(callback: Box<dyn Fn(&[f32])>) -> Self {
+    pub fn new(callback: AudioCallback) -> Self {
         Self {
             left_volume: 0,
             right_volume: 0,
@@ -95,6 +190,7 @@ impl Apu {
             channel_two: ChannelTwo::default(),
             channel_three: ChannelThree::default(),
             channel_four: ChannelFour::default(),
+            frame_sequencer_clock: 0,
             sample_clock: 0,
             buffer: Box::new([0.0; 1024]),
             buffer_position: 0,
@@ -103,16 +199,137 @@ impl Apu {
             frame_sequencer_position: 0,
             left_vin: false,
             right_vin: false,
+            channel_pan: [0.0; 4],
+            channel_muted: [false; 4],
+            master_volume: 1.0,
+            audio_sync: AudioSync::Off,
+            buffer_synced: false,
+            capture_samples: false,
+            captured_samples: Vec::new(),
+            last_buffer: Vec::with_capacity(BUFFER_SIZE),
+            highpass_enabled: true,
+            capacitors: [0.0; 2],
+            capacitor_charge: CAPACITOR_CHARGE.powi((CPU_CLOCK / SAMPLE_RATE) as i32),
+            output_enabled: true,
+        }
+    }
+
+    /// Serialize the registers and the state of all the channels.
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.left_volume);
+        state.u8(self.right_volume);
+        state.u8(self.nr51);
+        state.bool(self.apu_enabled);
+        state.bool(self.left_vin);
+        state.bool(self.right_vin);
+        state.u16(self.frame_sequencer_clock);
+        state.u32(self.sample_clock);
+        state.u8(self.frame_sequencer_position);
+
+        self.channel_one.save_state(state);
+        self.channel_two.save_state(state);
+        self.channel_three.save_state(state);
+        self.channel_four.save_state(state);
+    }
+
+    /// Restore the registers and the state of all the channels.
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), SaveStateError> {
+        self.left_volume = state.u8()?;
+        self.right_volume = state.u8()?;
+        self.nr51 = state.u8()?;
+        self.apu_enabled = state.bool()?;
+        self.left_vin = state.bool()?;
+        self.right_vin = state.bool()?;
+        self.frame_sequencer_clock = state.u16()?;
+        self.sample_clock = state.u32()?;
+        self.frame_sequencer_position = state.u8()?;
+
+        // Both clocks count up to their period, and would overflow if
+        // they were already past it.
+        ensure(self.frame_sequencer_clock < 8192)?;
+        ensure(self.sample_clock < (CPU_CLOCK / SAMPLE_RATE) as u32)?;
+        ensure(self.frame_sequencer_position < 8)?;
+
+        self.channel_one.load_state(state)?;
+        self.channel_two.load_state(state)?;
+        self.channel_three.load_state(state)?;
+        self.channel_four.load_state(state)
+    }
+
+    /// Enable or disable the high-pass filter on the output.
+    pub fn set_highpass_enabled(&mut self, enabled: bool) {
+        self.highpass_enabled = enabled;
+        self.capacitors = [0.0; 2];
+    }
+
+    /// Pass a sample through the high-pass filter of the given side.
+    fn highpass(&mut self, side: usize, input: f32) -> f32 {
+        if !self.highpass_enabled {
+            return input;
+        }
+
+        let output = input - self.capacitors[side];
+        self.capacitors[side] = input - output * self.capacitor_charge;
+
+        output
+    }
+
+    /// Set the stereo panning of a channel, applied on top of NR51.
+    pub fn set_channel_pan(&mut self, channel: usize, pan: f32) {
+        if let Some(channel_pan) = self.channel_pan.get_mut(channel) {
+            *channel_pan = pan.clamp(-1.0, 1.0);
+        }
+    }
+
+    /// Set the volume of the output, on top of the volume set by the game.
+    pub fn set_master_volume(&mut self, volume: f32) {
+        self.master_volume = volume.clamp(0.0, 1.0);
+    }
+
+    /// Set how the number of generated samples is adjusted.
+    pub fn set_audio_sync(&mut self, mode: AudioSync) {
+        self.audio_sync = mode;
+    }
+
+    /// Mute or unmute a channel, independent of the game's own settings.
+    pub fn set_channel_enabled(&mut self, channel: usize, enabled: bool) {
+        if let Some(muted) = self.channel_muted.get_mut(channel) {
+            *muted = !enabled;
+        }
+    }
+
+    /// Get the pitch of a channel in Hz, or `None` for an invalid channel.
+    pub fn channel_frequency_hz(&self, channel: u8) -> Option<f32> {
+        match channel {
+            0 => Some(self.channel_one.frequency_hz()),
+            1 => Some(self.channel_two.frequency_hz()),
+            2 => Some(self.channel_three.frequency_hz()),
+            3 => Some(self.channel_four.frequency_hz()),
+
+            _ => None,
+        }
+    }
+
+    /// Get the current 4-bit digital output of a channel, as seen
+    /// through PCM12 and PCM34, or `None` for an invalid channel.
+    pub fn channel_output(&self, channel: u8) -> Option<u8> {
+        match channel {
+            0 => Some(self.channel_one.digital_output()),
+            1 => Some(self.channel_two.digital_output()),
+            2 => Some(self.channel_three.digital_output()),
+            3 => Some(self.channel_four.digital_output()),
+
+            _ => None,
         }
     }
 
     /// Tick the APU by 1 M-cycle.
     pub fn tick(&mut self, cycles: u32) {
         for _ in 0..cycles {
-            // This clock is incremented every T-cycle.
-            // This is used to clock the frame sequencer and
-            // to generate samples.
-            self.sample_clock = self.sample_clock.wrapping_add(1);
+            // These clocks are incremented every T-cycle, and are kept
+            // apart so that resetting one doesn't shift the other.
+            self.frame_sequencer_clock += 1;
+            self.sample_clock += 1;
 
             // Tick all the connected channels.
             self.channel_one.tick_channel();
@@ -122,8 +339,8 @@ impl Apu {
 
             // Tick the frame sequencer. It generates clocks for the length,
             // envelope and sweep functions.
-            if self.sample_clock % 8192 == 0 {
-                self.sample_clock = 0;
+            if self.frame_sequencer_clock == 8192 {
+                self.frame_sequencer_clock = 0;
 
                 match self.frame_sequencer_position {
                     0 => {
@@ -170,54 +387,74 @@ impl Apu {
 
             // Each (CPU CLOCK / SAMPLE RATE) cycles one sample is generated
             // and pushed to the buffer.
-            if self.sample_clock % ((CPU_CLOCK / SAMPLE_RATE) as u32) == 0 {
-                self.buffer[self.buffer_position] = (self.left_volume as f32 / 7.0)
-                    * ((if (self.nr51 & 0x80) != 0 {
-                        self.channel_four.get_amplitude()
-                    } else {
-                        0.0
-                    } + if (self.nr51 & 0x40) != 0 {
-                        self.channel_three.get_amplitude()
-                    } else {
-                        0.0
-                    } + if (self.nr51 & 0x20) != 0 {
-                        self.channel_two.get_amplitude()
-                    } else {
-                        0.0
-                    } + if (self.nr51 & 0x10) != 0 {
-                        self.channel_one.get_amplitude()
-                    } else {
-                        0.0
-                    }) / 4.0);
+            if self.sample_clock == (CPU_CLOCK / SAMPLE_RATE) as u32 {
+                self.sample_clock = 0;
+            }
 
-                self.buffer[self.buffer_position + 1] = (self.right_volume as f32 / 7.0)
-                    * ((if (self.nr51 & 0x08) != 0 {
-                        self.channel_four.get_amplitude()
-                    } else {
-                        0.0
-                    } + if (self.nr51 & 0x04) != 0 {
-                        self.channel_three.get_amplitude()
-                    } else {
-                        0.0
-                    } + if (self.nr51 & 0x02) != 0 {
-                        self.channel_two.get_amplitude()
-                    } else {
-                        0.0
-                    } + if (self.nr51 & 0x01) != 0 {
-                        self.channel_one.get_amplitude()
-                    } else {
-                        0.0
-                    }) / 4.0);
+            if self.output_enabled && self.sample_clock == 0 {
+                let amplitudes = [
+                    self.channel_one.get_amplitude(),
+                    self.channel_two.get_amplitude(),
+                    self.channel_three.get_amplitude(),
+                    self.channel_four.get_amplitude(),
+                ];
+
+                let mut left = 0.0;
+                let mut right = 0.0;
+
+                // NR51 decides which side each channel is routed to, the
+                // panning then attenuates the opposite side.
+                for (i, amplitude) in amplitudes.iter().enumerate() {
+                    if self.channel_muted[i] {
+                        continue;
+                    }
+
+                    let pan = self.channel_pan[i];
+
+                    if (self.nr51 & (0x10 << i)) != 0 {
+                        left += amplitude * (1.0 - pan).min(1.0);
+                    }
 
-                self.buffer_position += 2;
+                    if (self.nr51 & (0x01 << i)) != 0 {
+                        right += amplitude * (1.0 + pan).min(1.0);
+                    }
+                }
+
+                let left = self.highpass(0, (self.left_volume as f32 / 7.0) * (left / 4.0));
+                let right = self.highpass(1, (self.right_volume as f32 / 7.0) * (right / 4.0));
+
+                // Drop or repeat the first sample of the buffer, once.
+                let copies = match (self.audio_sync, self.buffer_synced) {
+                    (AudioSync::Skip, false) => 0,
+                    (AudioSync::Stretch, false) => 2,
+                    _ => 1,
+                };
+
+                self.buffer_synced = true;
+
+                for _ in 0..copies {
+                    self.buffer[self.buffer_position] = left * self.master_volume;
+                    self.buffer[self.buffer_position + 1] = right * self.master_volume;
+
+                    if self.capture_samples {
+                        self.captured_samples
+                            .extend_from_slice(&self.buffer[self.buffer_position..][..2]);
+                    }
+
+                    self.buffer_position += 2;
+                }
             }
 
             // Checks if the buffer is full and calls the provided callback.
             if self.buffer_position >= BUFFER_SIZE {
                 (self.callback)(self.buffer.as_ref());
 
+                self.last_buffer.clear();
+                self.last_buffer.extend_from_slice(self.buffer.as_ref());
+
                 // Reset the buffer position.
                 self.buffer_position = 0;
+                self.buffer_synced = false;
             }
         }
     }
@@ -238,7 +475,9 @@ impl Apu {
             0xFF26 => {
                 let mut nr52 = ((self.apu_enabled as u8) << 7) | 0x70;
 
-                // Set the status bits appropriately.
+                // Set the status bits appropriately, channel one is
+                // reflected in bit 0 through channel four in bit 3.
+                // Bits 4-6 are unused and always read as 1.
                 nr52 |= self.channel_one.channel_enabled as u8;
                 nr52 |= (self.channel_two.channel_enabled as u8) << 1;
                 nr52 |= (self.channel_three.channel_enabled as u8) << 2;
@@ -290,6 +529,7 @@ impl Apu {
 
             0xFF25 => self.nr51 = value,
 
+            // Only bit 7 is writable, the channel status bits are read only.
             0xFF26 => {
                 let enabled = (value >> 7) != 0;
 
@@ -513,6 +753,15 @@ impl Channel for ChannelOne {
                 self.length_counter = 64 - (value & 0b0011_1111);
             }
             0xFF12 => {
+                if self.channel_enabled {
+                    self.current_volume = zombie_volume(
+                        self.current_volume,
+                        self.period,
+                        self.is_incrementing,
+                        value,
+                    );
+                }
+
                 // Update the envelope function parameters.
                 self.is_incrementing = (value & 0x08) != 0;
                 self.initial_volume = value >> 4;
@@ -594,15 +843,26 @@ impl Channel for ChannelOne {
     /// The only possible values of this are 0 or 1.
     fn get_amplitude(&self) -> f32 {
         if self.dac_enabled && self.channel_enabled {
-            let input = WAVE_DUTY[self.duty_pattern as usize][self.wave_position] as f32
-                * self.current_volume as f32;
-
-            (input / 7.5) - 1.0
+            (self.digital_output() as f32 / 7.5) - 1.0
         } else {
             0.0
         }
     }
 
+    fn digital_output(&self) -> u8 {
+        if self.channel_enabled {
+            WAVE_DUTY[self.duty_pattern as usize][self.wave_position] * self.current_volume
+        } else {
+            0
+        }
+    }
+
+    /// The duty cycle is 8 steps long, stepped every `(2048 - frequency) * 4`
+    /// T-cycles, which makes for `131072 / (2048 - frequency)` Hz.
+    fn frequency_hz(&self) -> f32 {
+        131072.0 / (2048 - self.frequency) as f32
+    }
+
     fn step_length(&mut self) {
         if self.length_enabled && self.length_counter > 0 {
             self.length_counter -= 1;
@@ -613,6 +873,56 @@ impl Channel for ChannelOne {
             }
         }
     }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.dac_enabled);
+        state.bool(self.channel_enabled);
+        state.u16(self.frequency_timer);
+        state.u8(self.wave_position as u8);
+        state.u8(self.sweep_period);
+        state.bool(self.sweep_is_decrementing);
+        state.u8(self.sweep_amount);
+        state.u8(self.sweep_period_timer);
+        state.bool(self.sweep_enabled);
+        state.u16(self.shadow_frequency);
+        state.u8(self.duty_pattern);
+        state.u8(self.length_counter);
+        state.u16(self.frequency);
+        state.bool(self.length_enabled);
+        state.u8(self.initial_volume);
+        state.bool(self.is_incrementing);
+        state.u8(self.period);
+        state.u8(self.period_timer);
+        state.u8(self.current_volume);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), SaveStateError> {
+        self.dac_enabled = state.bool()?;
+        self.channel_enabled = state.bool()?;
+        self.frequency_timer = state.u16()?;
+        self.wave_position = state.u8()? as usize & 0x07;
+        self.sweep_period = state.u8()?;
+        self.sweep_is_decrementing = state.bool()?;
+        self.sweep_amount = state.u8()?;
+        self.sweep_period_timer = state.u8()?;
+        self.sweep_enabled = state.bool()?;
+        self.shadow_frequency = state.u16()?;
+        self.duty_pattern = state.u8()?;
+        self.length_counter = state.u8()?;
+        self.frequency = state.u16()?;
+        self.length_enabled = state.bool()?;
+        self.initial_volume = state.u8()?;
+        self.is_incrementing = state.bool()?;
+        self.period = state.u8()?;
+        self.period_timer = state.u8()?;
+        self.current_volume = state.u8()?;
+
+        ensure(self.sweep_period <= 7 && self.sweep_amount <= 7)?;
+        ensure(self.shadow_frequency <= 0x7FF && self.frequency <= 0x7FF)?;
+        ensure(self.duty_pattern <= 3)?;
+
+        Ok(())
+    }
 }
 
 /// Implementation of the square wave channel two with an envelope function.
@@ -730,6 +1040,15 @@ impl Channel for ChannelTwo {
             }
 
             0xFF17 => {
+                if self.channel_enabled {
+                    self.current_volume = zombie_volume(
+                        self.current_volume,
+                        self.period,
+                        self.is_incrementing,
+                        value,
+                    );
+                }
+
                 // Update the envelope function parameters.
                 self.is_incrementing = (value & 0x08) != 0;
                 self.initial_volume = value >> 4;
@@ -795,15 +1114,26 @@ impl Channel for ChannelTwo {
     /// The only possible values of this are 0 or 1.
     fn get_amplitude(&self) -> f32 {
         if self.dac_enabled && self.channel_enabled {
-            let input = WAVE_DUTY[self.duty_pattern as usize][self.wave_position] as f32
-                * self.current_volume as f32;
-
-            (input / 7.5) - 1.0
+            (self.digital_output() as f32 / 7.5) - 1.0
         } else {
             0.0
         }
     }
 
+    fn digital_output(&self) -> u8 {
+        if self.channel_enabled {
+            WAVE_DUTY[self.duty_pattern as usize][self.wave_position] * self.current_volume
+        } else {
+            0
+        }
+    }
+
+    /// The duty cycle is 8 steps long, stepped every `(2048 - frequency) * 4`
+    /// T-cycles, which makes for `131072 / (2048 - frequency)` Hz.
+    fn frequency_hz(&self) -> f32 {
+        131072.0 / (2048 - self.frequency) as f32
+    }
+
     fn step_length(&mut self) {
         if self.length_enabled && self.length_counter > 0 {
             self.length_counter -= 1;
@@ -814,6 +1144,43 @@ impl Channel for ChannelTwo {
             }
         }
     }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.dac_enabled);
+        state.bool(self.channel_enabled);
+        state.u16(self.frequency_timer);
+        state.u8(self.wave_position as u8);
+        state.u8(self.duty_pattern);
+        state.u8(self.length_counter);
+        state.u16(self.frequency);
+        state.bool(self.length_enabled);
+        state.u8(self.initial_volume);
+        state.bool(self.is_incrementing);
+        state.u8(self.period);
+        state.u8(self.period_timer);
+        state.u8(self.current_volume);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), SaveStateError> {
+        self.dac_enabled = state.bool()?;
+        self.channel_enabled = state.bool()?;
+        self.frequency_timer = state.u16()?;
+        self.wave_position = state.u8()? as usize & 0x07;
+        self.duty_pattern = state.u8()?;
+        self.length_counter = state.u8()?;
+        self.frequency = state.u16()?;
+        self.length_enabled = state.bool()?;
+        self.initial_volume = state.u8()?;
+        self.is_incrementing = state.bool()?;
+        self.period = state.u8()?;
+        self.period_timer = state.u8()?;
+        self.current_volume = state.u8()?;
+
+        ensure(self.frequency <= 0x7FF)?;
+        ensure(self.duty_pattern <= 3)?;
+
+        Ok(())
+    }
 }
 
 /// Implementation of the custom wave channel.
@@ -858,6 +1225,17 @@ pub struct ChannelThree {
     wave_ram: Box<[u8; 0x10]>,
 }
 
+impl ChannelThree {
+    /// Get the sample at the current wave position, shifted by the volume.
+    fn current_sample(&self) -> u8 {
+        let sample = ((self.wave_ram[self.wave_position / 2])
+            >> (if (self.wave_position & 1) != 0 { 4 } else { 0 }))
+            & 0x0F;
+
+        sample >> self.volume_shift
+    }
+}
+
 impl Channel for ChannelThree {
     fn read_byte(&self, addr: u16) -> u8 {
         match addr {
@@ -948,16 +1326,26 @@ impl Channel for ChannelThree {
     /// Get the current amplitude of the channel.
     fn get_amplitude(&self) -> f32 {
         if self.dac_enabled {
-            let sample = ((self.wave_ram[self.wave_position / 2])
-                >> (if (self.wave_position & 1) != 0 { 4 } else { 0 }))
-                & 0x0F;
-
-            (((sample >> self.volume_shift) as f32) / 7.5) - 1.0
+            ((self.current_sample() as f32) / 7.5) - 1.0
         } else {
             0.0
         }
     }
 
+    fn digital_output(&self) -> u8 {
+        if self.channel_enabled {
+            self.current_sample()
+        } else {
+            0
+        }
+    }
+
+    /// The wave is 32 samples long, stepped every `(2048 - frequency) * 2`
+    /// T-cycles, which makes for `65536 / (2048 - frequency)` Hz.
+    fn frequency_hz(&self) -> f32 {
+        65536.0 / (2048 - self.frequency) as f32
+    }
+
     fn step_length(&mut self) {
         if self.length_enabled && self.length_counter > 0 {
             self.length_counter -= 1;
@@ -968,6 +1356,37 @@ impl Channel for ChannelThree {
             }
         }
     }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.dac_enabled);
+        state.bool(self.channel_enabled);
+        state.u16(self.frequency_timer);
+        state.u8(self.wave_position as u8);
+        state.u16(self.length_counter);
+        state.u8(self.output_level);
+        state.u8(self.volume_shift);
+        state.u16(self.frequency);
+        state.bool(self.length_enabled);
+        state.bytes(&self.wave_ram[..]);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), SaveStateError> {
+        self.dac_enabled = state.bool()?;
+        self.channel_enabled = state.bool()?;
+        self.frequency_timer = state.u16()?;
+        self.wave_position = state.u8()? as usize & 0x1F;
+        self.length_counter = state.u16()?;
+        self.output_level = state.u8()?;
+        self.volume_shift = state.u8()?;
+        self.frequency = state.u16()?;
+        self.length_enabled = state.bool()?;
+        state.bytes(&mut self.wave_ram[..])?;
+
+        ensure(self.frequency <= 0x7FF)?;
+        ensure(self.output_level <= 3 && self.volume_shift <= 4)?;
+
+        Ok(())
+    }
 }
 
 /// Implementation of the noise channel four.
@@ -1075,6 +1494,15 @@ impl Channel for ChannelFour {
             }
 
             0xFF21 => {
+                if self.channel_enabled {
+                    self.current_volume = zombie_volume(
+                        self.current_volume,
+                        self.period,
+                        self.is_incrementing,
+                        value,
+                    );
+                }
+
                 // Update the envelope function parameters.
                 self.is_incrementing = (value & 0x08) != 0;
                 self.initial_volume = value >> 4;
@@ -1147,14 +1575,33 @@ impl Channel for ChannelFour {
 
     fn get_amplitude(&self) -> f32 {
         if self.dac_enabled && self.channel_enabled {
-            let input = (!self.lfsr & 0b01) as f32 * self.current_volume as f32;
-
-            (input / 7.5) - 1.0
+            (self.digital_output() as f32 / 7.5) - 1.0
         } else {
             0.0
         }
     }
 
+    fn digital_output(&self) -> u8 {
+        if self.channel_enabled {
+            (!self.lfsr & 0b01) as u8 * self.current_volume
+        } else {
+            0
+        }
+    }
+
+    /// The rate at which the LFSR is clocked, as the noise has no pitch
+    /// of its own.
+    fn frequency_hz(&self) -> f32 {
+        let divisor_code = (self.nr43 & 0x07) as u32;
+        let divisor = if divisor_code == 0 {
+            8
+        } else {
+            divisor_code << 4
+        };
+
+        CPU_CLOCK as f32 / (divisor << (self.nr43 >> 4)) as f32
+    }
+
     fn step_length(&mut self) {
         if self.length_enabled && self.length_counter > 0 {
             self.length_counter -= 1;
@@ -1165,4 +1612,358 @@ impl Channel for ChannelFour {
             }
         }
     }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.dac_enabled);
+        state.bool(self.channel_enabled);
+        state.u16(self.frequency_timer);
+        state.u16(self.lfsr);
+        state.u8(self.length_counter);
+        state.u8(self.nr43);
+        state.bool(self.length_enabled);
+        state.u8(self.initial_volume);
+        state.bool(self.is_incrementing);
+        state.u8(self.period);
+        state.u8(self.period_timer);
+        state.u8(self.current_volume);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), SaveStateError> {
+        self.dac_enabled = state.bool()?;
+        self.channel_enabled = state.bool()?;
+        self.frequency_timer = state.u16()?;
+        self.lfsr = state.u16()?;
+        self.length_counter = state.u8()?;
+        self.nr43 = state.u8()?;
+        self.length_enabled = state.bool()?;
+        self.initial_volume = state.u8()?;
+        self.is_incrementing = state.bool()?;
+        self.period = state.u8()?;
+        self.period_timer = state.u8()?;
+        self.current_volume = state.u8()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn envelope_write_while_running_changes_volume() {
+        let mut channel = ChannelTwo::default();
+
+        // Volume 5, decrementing with a period of 0, then trigger.
+        channel.write_byte(0xFF17, 0x50);
+        channel.write_byte(0xFF19, 0x80);
+        assert_eq!(channel.current_volume, 5);
+
+        // A period of 0 increments the volume.
+        channel.write_byte(0xFF17, 0x50);
+        assert_eq!(channel.current_volume, 6);
+
+        // Switching the direction negates it.
+        channel.write_byte(0xFF17, 0x58);
+        assert_eq!(channel.current_volume, 9);
+
+        channel.write_byte(0xFF17, 0x51);
+        assert_eq!(channel.current_volume, 6);
+
+        // A decrementing envelope with a period adds two.
+        channel.write_byte(0xFF17, 0x51);
+        assert_eq!(channel.current_volume, 8);
+    }
+
+    /// Create an `Apu` playing a square wave on channel two, routed
+    /// to both sides, which captures the samples it generates.
+    fn apu_playing_channel_two() -> Apu {
+        let mut apu = Apu::new(Box::new(|_| {}));
+
+        apu.set_highpass_enabled(false);
+        apu.capture_samples = true;
+
+        apu.write_byte(0xFF26, 0x80);
+        apu.write_byte(0xFF24, 0x77);
+        apu.write_byte(0xFF25, 0x22);
+        apu.write_byte(0xFF16, 0x80);
+        apu.write_byte(0xFF17, 0xF0);
+        apu.write_byte(0xFF19, 0x87);
+
+        apu
+    }
+
+    #[test]
+    fn full_left_pan_silences_right_side() {
+        let mut apu = apu_playing_channel_two();
+
+        apu.set_channel_pan(1, -1.0);
+        apu.tick(4096);
+
+        let samples = &apu.captured_samples;
+
+        assert!(samples.chunks_exact(2).any(|sample| sample[0] != 0.0));
+        assert!(samples.chunks_exact(2).all(|sample| sample[1] == 0.0));
+    }
+
+    #[test]
+    fn out_of_range_channels_are_ignored() {
+        let mut apu = apu_playing_channel_two();
+
+        apu.set_channel_pan(4, -1.0);
+        apu.set_channel_enabled(4, false);
+        apu.tick(4096);
+
+        let samples = &apu.captured_samples;
+
+        assert!(samples.chunks_exact(2).any(|sample| sample[1] != 0.0));
+    }
+
+    #[test]
+    fn nr52_reflects_each_channel() {
+        // (DAC register, DAC value, trigger register, expected NR52)
+        let cases = [
+            (0xFF12, 0xF0, 0xFF14, 0xF1),
+            (0xFF17, 0xF0, 0xFF19, 0xF2),
+            (0xFF1A, 0x80, 0xFF1E, 0xF4),
+            (0xFF21, 0xF0, 0xFF23, 0xF8),
+        ];
+
+        for &(dac, value, trigger, expected) in cases.iter() {
+            let mut apu = Apu::new(Box::new(|_| {}));
+
+            apu.write_byte(0xFF26, 0x80);
+            assert_eq!(apu.read_byte(0xFF26), 0xF0);
+
+            apu.write_byte(dac, value);
+            apu.write_byte(trigger, 0x80);
+            assert_eq!(apu.read_byte(0xFF26), expected);
+
+            // Only bit 7 of NR52 is writable.
+            apu.write_byte(0xFF26, 0x8F);
+            assert_eq!(apu.read_byte(0xFF26), expected);
+
+            apu.write_byte(0xFF26, 0x00);
+            assert_eq!(apu.read_byte(0xFF26), 0x70);
+        }
+    }
+
+    #[test]
+    fn last_buffer_is_interleaved_stereo() {
+        let mut apu = apu_playing_channel_two();
+        assert!(apu.last_buffer.is_empty());
+
+        // Only the left side plays.
+        apu.set_channel_pan(1, -1.0);
+        apu.tick(70224);
+
+        let buffer = &apu.last_buffer;
+
+        assert_eq!(buffer.len(), BUFFER_SIZE);
+        assert!(buffer.chunks_exact(2).any(|sample| sample[0] != 0.0));
+        assert!(buffer.chunks_exact(2).all(|sample| sample[1] == 0.0));
+    }
+
+    #[test]
+    fn channel_frequencies_in_hz() {
+        let mut apu = Apu::new(Box::new(|_| {}));
+        apu.write_byte(0xFF26, 0x80);
+
+        // A frequency register of 1750 is about 440 Hz.
+        apu.write_byte(0xFF13, 0xD6);
+        apu.write_byte(0xFF14, 0x06);
+        apu.write_byte(0xFF1D, 0xD6);
+        apu.write_byte(0xFF1E, 0x06);
+        apu.write_byte(0xFF22, 0x21);
+
+        let hz = |channel| apu.channel_frequency_hz(channel).unwrap();
+
+        assert!((hz(0) - 439.83).abs() < 0.01, "{} Hz", hz(0));
+        assert_eq!(hz(1), 64.0);
+        assert!((hz(2) - 219.91).abs() < 0.01, "{} Hz", hz(2));
+        assert_eq!(hz(3), 65536.0);
+
+        assert_eq!(apu.channel_frequency_hz(4), None);
+        assert_eq!(apu.channel_output(4), None);
+    }
+
+    /// Save `saved` and load the state back into `target`.
+    fn round_trip<T: Channel>(saved: &T, target: &mut T) -> Result<(), SaveStateError> {
+        let mut writer = StateWriter::new();
+        saved.save_state(&mut writer);
+        let data = writer.finish();
+
+        target.load_state(&mut StateReader::new(&data).unwrap())
+    }
+
+    #[test]
+    fn load_state_rejects_out_of_range_values() {
+        let mut channel = ChannelOne {
+            duty_pattern: 3,
+            ..Default::default()
+        };
+        assert_eq!(round_trip(&channel, &mut ChannelOne::default()), Ok(()));
+
+        channel.duty_pattern = 4;
+        assert_eq!(
+            round_trip(&channel, &mut ChannelOne::default()),
+            Err(SaveStateError::Corrupted)
+        );
+
+        let channel = ChannelTwo {
+            frequency: 0x800,
+            ..Default::default()
+        };
+        assert_eq!(
+            round_trip(&channel, &mut ChannelTwo::default()),
+            Err(SaveStateError::Corrupted)
+        );
+
+        let channel = ChannelThree {
+            volume_shift: 8,
+            ..Default::default()
+        };
+        assert_eq!(
+            round_trip(&channel, &mut ChannelThree::default()),
+            Err(SaveStateError::Corrupted)
+        );
+
+        // The clocks of the APU can't be past their period.
+        let limits = [(8192, 0), (0, (CPU_CLOCK / SAMPLE_RATE) as u32)];
+
+        for &(frame_sequencer_clock, sample_clock) in limits.iter() {
+            let mut apu = Apu::new(Box::new(|_| {}));
+            apu.frame_sequencer_clock = frame_sequencer_clock;
+            apu.sample_clock = sample_clock;
+
+            let mut writer = StateWriter::new();
+            apu.save_state(&mut writer);
+            let data = writer.finish();
+
+            let mut reader = StateReader::new(&data).unwrap();
+            assert_eq!(
+                Apu::new(Box::new(|_| {})).load_state(&mut reader),
+                Err(SaveStateError::Corrupted)
+            );
+        }
+    }
+
+    #[test]
+    fn samples_are_evenly_spaced_across_sequencer_steps() {
+        let mut apu = apu_playing_channel_two();
+        let mut sample_cycles = Vec::new();
+
+        // Four frame sequencer steps.
+        for cycle in 0..4 * 8192 {
+            let generated = apu.captured_samples.len();
+            apu.tick(1);
+
+            if apu.captured_samples.len() > generated {
+                sample_cycles.push(cycle);
+            }
+        }
+
+        let spacing = (CPU_CLOCK / SAMPLE_RATE) as u32;
+
+        assert_eq!(sample_cycles.len() as u32, 4 * 8192 / spacing);
+        assert!(sample_cycles.windows(2).all(|w| w[1] - w[0] == spacing));
+    }
+
+    #[test]
+    fn highpass_removes_a_constant_level() {
+        let mut apu = Apu::new(Box::new(|_| {}));
+        let mut previous = apu.highpass(0, 0.5);
+
+        assert_eq!(previous, 0.5);
+
+        // A second of samples.
+        for _ in 0..SAMPLE_RATE {
+            let output = apu.highpass(0, 0.5);
+
+            assert!(output >= 0.0 && output <= previous);
+            previous = output;
+        }
+
+        assert!(previous < 0.001);
+
+        // The other side has not been charged yet.
+        assert_eq!(apu.highpass(1, 0.5), 0.5);
+
+        apu.set_highpass_enabled(false);
+        assert_eq!(apu.highpass(0, 0.5), 0.5);
+    }
+
+    #[test]
+    fn muted_channel_three_is_left_out_of_the_mix() {
+        let mut reference = apu_playing_channel_two();
+        reference.tick(4096);
+
+        let mut apu = apu_playing_channel_two();
+
+        // Also play a wave on channel three, routed to both sides.
+        apu.write_byte(0xFF25, 0x66);
+        apu.write_byte(0xFF30, 0x9F);
+        apu.write_byte(0xFF1A, 0x80);
+        apu.write_byte(0xFF1C, 0x20);
+        apu.write_byte(0xFF1E, 0x87);
+
+        apu.set_channel_enabled(2, false);
+        apu.tick(4096);
+
+        assert_eq!(apu.captured_samples, reference.captured_samples);
+
+        // The channel kept running while muted.
+        assert_ne!(apu.channel_three.wave_position, 0);
+
+        apu.set_channel_enabled(2, true);
+        reference.tick(4096);
+        apu.tick(4096);
+
+        assert_ne!(apu.captured_samples, reference.captured_samples);
+    }
+
+    #[test]
+    fn master_volume_scales_the_samples() {
+        let mut reference = apu_playing_channel_two();
+        reference.tick(4096);
+
+        assert!(reference
+            .captured_samples
+            .iter()
+            .any(|&sample| sample != 0.0));
+
+        // Volumes above the full one are clamped.
+        for &(volume, scale) in [(0.5, 0.5), (0.0, 0.0), (2.0, 1.0)].iter() {
+            let mut apu = apu_playing_channel_two();
+
+            apu.set_master_volume(volume);
+            apu.tick(4096);
+
+            assert_eq!(apu.captured_samples.len(), reference.captured_samples.len());
+
+            for (&sample, &full) in apu.captured_samples.iter().zip(&reference.captured_samples) {
+                assert_eq!(sample, full * scale);
+            }
+        }
+    }
+
+    #[test]
+    fn audio_sync_nudges_the_sample_count() {
+        let spacing = CPU_CLOCK / SAMPLE_RATE;
+        let nominal = 20 * BUFFER_SIZE / 2;
+
+        let frames = |mode| {
+            let mut apu = apu_playing_channel_two();
+
+            apu.set_audio_sync(mode);
+            apu.tick((nominal * spacing) as u32);
+
+            apu.captured_samples.len() / 2
+        };
+
+        // One stereo sample is dropped or repeated in each of the 20 buffers.
+        assert_eq!(frames(AudioSync::Off), nominal);
+        assert!((nominal - 20..=nominal - 19).contains(&frames(AudioSync::Skip)));
+        assert!((nominal + 19..=nominal + 21).contains(&frames(AudioSync::Stretch)));
+    }
 }
@@ -1,6 +1,16 @@
 use std::{cell::RefCell, rc::Rc};
 
-use crate::{audio::Apu, cartridge::*, joypad::Joypad, ppu::Ppu, timer::Timer};
+use crate::{
+    audio::{Apu, AudioCallback},
+    cartridge::*,
+    cheats::{self, Cheat, CheatEffect},
+    joypad::Joypad,
+    ppu::Ppu,
+    savestate::{ensure, SaveStateError, StateReader, StateWriter},
+    serial::Serial,
+    timer::Timer,
+    util::crc32,
+};
 
 /// This is a custom bootrom for DMG
 /// made by LIJI.
@@ -15,6 +25,12 @@ pub(crate) struct Bus {
     // The inserted cartridge.
     pub cartridge: Box<dyn Cartridge>,
 
+    /// CRC-32 checksum of the ROM.
+    pub rom_hash: u32,
+
+    /// Size of the ROM as it was loaded, before any padding.
+    pub rom_size: usize,
+
     // 8 KB of Work RAM.
     pub work_ram: Box<[u8; 0x8000]>,
 
@@ -36,6 +52,10 @@ pub(crate) struct Bus {
     /// The Game Boy joypad subsystem.
     pub joypad: Joypad,
 
+    /// The Game Boy serial port.
+    /// SB and SC registers.
+    pub serial: Serial,
+
     /// $FF0F - IF register. (Set bits here to request interrupts).
     pub if_reg: Rc<RefCell<u8>>,
 
@@ -78,18 +98,75 @@ pub(crate) struct Bus {
     /// The HDMA destination where to transfer the next byte.
     pub hdma_dst: u16,
 
+    /// Emulate what reads from 0xFEA0 - 0xFEFF return on hardware,
+    /// instead of always returning 0xFF.
+    pub unusable_reads_accurate: bool,
+
+    /// Transfer OAM DMA one byte per M-cycle instead of all at once.
+    pub oam_dma_accurate: bool,
+
+    /// Source address of the active OAM DMA transfer, if any.
+    pub oam_dma_src: Option<u16>,
+
+    /// Index of the next byte the active OAM DMA transfer copies.
+    pub oam_dma_index: u16,
+
+    /// Source address of a requested OAM DMA transfer, which starts on
+    /// the next M-cycle.
+    pub oam_dma_pending: Option<u16>,
+
+    /// $FF4C - KEY0. Written by the CGB boot ROM to select
+    /// DMG compatibility mode (bit 2), and locked afterwards.
+    pub key0_reg: u8,
+
     /// $FF4D - KEY1.
     pub speed_reg: u8,
+
+    /// $FF56 - RP. Infrared communications port.
+    pub rp_reg: u8,
+
+    /// Called with the state of our IR LED, and returns whether
+    /// IR light is being received from the outside.
+    pub ir_callback: Option<Box<dyn Fn(bool) -> bool>>,
+
+    /// Freezes the PPU, while the rest of the system keeps running.
+    pub ppu_paused: bool,
+
+    /// Freezes the APU, while the rest of the system keeps running.
+    pub apu_paused: bool,
+
+    /// Called with the address and the opcode of illegal instructions.
+    pub illegal_opcode_callback: Option<Box<dyn Fn(u16, u8)>>,
+
+    /// Lock up the CPU on illegal opcodes, like the hardware.
+    pub lock_on_illegal_opcode: bool,
+
+    /// Called with the address and value of writes to unmapped regions.
+    pub unmapped_write_callback: Option<Box<dyn Fn(u16, u8)>>,
+
+    /// Whether the cartridge header declares an unknown RAM size,
+    /// in which case no external RAM was allocated.
+    unknown_ram_size: bool,
+
+    /// Address ranges which break execution when written to.
+    pub write_breakpoints: Vec<(u16, u16)>,
+
+    /// The address and value of the write that hit a breakpoint, if any.
+    pub write_breakpoint_hit: Option<(u16, u8)>,
+
+    /// The loaded cheat codes.
+    pub cheats: Vec<Cheat>,
 }
 
 impl Bus {
     /// Create a new `Bus` instance.
-    pub fn new(rom: &[u8], callback: Box<dyn Fn(&[f32])>, save_file: Option<Vec<u8>>) -> Self {
+    pub fn new(rom: &[u8], callback: AudioCallback, save_file: Option<Vec<u8>>) -> Self {
         let cartridge: Box<dyn Cartridge> = match rom[0x0147] {
             0x00 => Box::new(RomOnly::new(rom)),
-            0x01..=0x03 => Box::new(Mbc1::new(rom)),
+            0x01..=0x03 => Box::new(Mbc1::new(rom, save_file)),
+            0x05..=0x06 => Box::new(Mbc2::new(rom, save_file)),
             0x0F..=0x13 => Box::new(Mbc3::new(rom, save_file)),
-            0x19..=0x1E => Box::new(Mbc5::new(rom)),
+            0x19..=0x1E => Box::new(Mbc5::new(rom, save_file)),
 
             _ => panic!("unsupported cartridge type"),
         };
@@ -99,12 +176,15 @@ impl Bus {
 
         Self {
             cartridge,
+            rom_hash: crc32(rom),
+            rom_size: rom.len(),
             work_ram: Box::new([0; 0x8000]),
             high_ram: Box::new([0; 0x7F]),
             timer: Timer::new(Rc::clone(&if_reg)),
             ppu: Ppu::new(Rc::clone(&if_reg), cgb_mode),
             apu: Apu::new(callback),
             joypad: Joypad::new(Rc::clone(&if_reg)),
+            serial: Serial::new(Rc::clone(&if_reg)),
             ie_reg: 0,
             if_reg,
             boot_reg: 0,
@@ -114,12 +194,184 @@ impl Bus {
             dma_src_low: 0,
             dma_dst_high: 0,
             dma_dst_low: 0,
-            dma_control: 0,
+            dma_control: 0xFF,
             hdma_active: false,
             hdma_len: 0,
             hdma_dst: 0,
             hdma_src: 0,
+            unusable_reads_accurate: false,
+            oam_dma_accurate: false,
+            oam_dma_src: None,
+            oam_dma_index: 0,
+            oam_dma_pending: None,
+            key0_reg: 0,
             speed_reg: 0,
+            rp_reg: 0,
+            ir_callback: None,
+            ppu_paused: false,
+            apu_paused: false,
+            illegal_opcode_callback: None,
+            lock_on_illegal_opcode: false,
+            unmapped_write_callback: None,
+            unknown_ram_size: !is_known_ram_size(rom[0x0149]),
+            write_breakpoints: Vec::new(),
+            write_breakpoint_hit: None,
+            cheats: Vec::new(),
+        }
+    }
+
+    /// Serialize the memories and registers of the bus,
+    /// and the state of all the attached components.
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.u32(self.rom_hash);
+        state.bytes(&self.work_ram[..]);
+        state.bytes(&self.high_ram[..]);
+        state.u8(*self.if_reg.borrow());
+        state.u8(self.ie_reg);
+        state.u8(self.boot_reg);
+        state.bool(self.cgb_mode);
+        state.u8(self.wram_bank as u8);
+
+        for &reg in &[
+            self.dma_src_high,
+            self.dma_src_low,
+            self.dma_dst_high,
+            self.dma_dst_low,
+            self.dma_control,
+        ] {
+            state.u8(reg);
+        }
+
+        state.bool(self.hdma_active);
+        state.u16(self.hdma_len);
+        state.u16(self.hdma_src);
+        state.u16(self.hdma_dst);
+
+        for &dma in &[self.oam_dma_src, self.oam_dma_pending] {
+            state.bool(dma.is_some());
+            state.u16(dma.unwrap_or(0));
+        }
+
+        state.u16(self.oam_dma_index);
+        state.u8(self.key0_reg);
+        state.u8(self.speed_reg);
+        state.u8(self.rp_reg);
+
+        self.cartridge.save_state(state);
+        self.timer.save_state(state);
+        self.ppu.save_state(state);
+        self.apu.save_state(state);
+        self.joypad.save_state(state);
+        self.serial.save_state(state);
+    }
+
+    /// Restore the memories and registers of the bus,
+    /// and the state of all the attached components.
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), SaveStateError> {
+        if state.u32()? != self.rom_hash {
+            return Err(SaveStateError::RomMismatch);
+        }
+
+        state.bytes(&mut self.work_ram[..])?;
+        state.bytes(&mut self.high_ram[..])?;
+        *self.if_reg.borrow_mut() = state.u8()?;
+        self.ie_reg = state.u8()?;
+        self.boot_reg = state.u8()?;
+        self.cgb_mode = state.bool()?;
+        self.wram_bank = (state.u8()? & 0x07).max(1) as usize;
+
+        for reg in [
+            &mut self.dma_src_high,
+            &mut self.dma_src_low,
+            &mut self.dma_dst_high,
+            &mut self.dma_dst_low,
+            &mut self.dma_control,
+        ]
+        .iter_mut()
+        {
+            **reg = state.u8()?;
+        }
+
+        self.hdma_active = state.bool()?;
+        self.hdma_len = state.u16()?;
+        self.hdma_src = state.u16()?;
+        self.hdma_dst = state.u16()?;
+
+        for dma in [&mut self.oam_dma_src, &mut self.oam_dma_pending].iter_mut() {
+            let active = state.bool()?;
+            let src = state.u16()?;
+
+            **dma = if active { Some(src) } else { None };
+        }
+
+        self.oam_dma_index = state.u16()?;
+        self.key0_reg = state.u8()?;
+        self.speed_reg = state.u8()?;
+        self.rp_reg = state.u8()?;
+
+        // HDMA copies whole blocks of 16 bytes, and stops after 0x800.
+        if self.hdma_active {
+            ensure(self.hdma_len & 0x0F == 0 && (0x10..=0x800).contains(&self.hdma_len))?;
+            ensure(self.hdma_src.checked_add(self.hdma_len).is_some())?;
+            ensure(self.hdma_dst.checked_add(self.hdma_len).is_some())?;
+        }
+
+        // OAM DMA copies 160 bytes from the start of a page.
+        for dma in [self.oam_dma_src, self.oam_dma_pending].iter().flatten() {
+            ensure(dma & 0xFF == 0)?;
+        }
+
+        ensure(self.oam_dma_index <= 0xA0)?;
+        ensure(self.oam_dma_index < 0xA0 || self.oam_dma_src.is_none())?;
+
+        self.cartridge.load_state(state)?;
+        self.timer.load_state(state)?;
+        self.ppu.load_state(state)?;
+        self.apu.load_state(state)?;
+        self.joypad.load_state(state)?;
+        self.serial.load_state(state)
+    }
+
+    /// Perform the writes of the enabled GameShark cheats.
+    ///
+    /// The values are stored straight into RAM, without the side effects
+    /// of a write by the CPU, and cheats pointing anywhere else are ignored.
+    pub fn apply_ram_cheats(&mut self) {
+        for index in 0..self.cheats.len() {
+            let cheat = &self.cheats[index];
+
+            if let (
+                true,
+                CheatEffect::RamWrite {
+                    bank,
+                    address,
+                    value,
+                },
+            ) = (cheat.enabled, cheat.effect)
+            {
+                match address {
+                    // External RAM, in the bank currently mapped.
+                    0xA000..=0xBFFF => self.cartridge.write_byte(address, value),
+
+                    0xC000..=0xCFFF => self.work_ram[(address & 0xFFF) as usize] = value,
+
+                    // Codes of type 0x9X write to WRAM bank X, the
+                    // others to the bank currently mapped.
+                    0xD000..=0xDFFF => {
+                        let bank = if bank & 0xF0 == 0x90 {
+                            ((bank & 0x07) as usize).max(1)
+                        } else {
+                            self.wram_bank
+                        };
+
+                        self.work_ram[(address & 0xFFF) as usize + 0x1000 * bank] = value;
+                    }
+
+                    0xFF80..=0xFFFE => self.high_ram[(address - 0xFF80) as usize] = value,
+
+                    _ => {}
+                }
+            }
         }
     }
 
@@ -127,6 +379,8 @@ impl Bus {
     /// Tick the components if specified.
     pub fn read_byte(&mut self, addr: u16, tick: bool) -> u8 {
         let value = match addr {
+            _ if tick && self.oam_dma_blocks(addr) => 0xFF,
+
             0x0000..=0x00FF if self.boot_reg == 0 => {
                 if self.cgb_mode {
                     CGB_BOOT_ROM[addr as usize]
@@ -138,7 +392,9 @@ impl Bus {
             0x0200..=0x08FF if self.boot_reg == 0 && self.cgb_mode => CGB_BOOT_ROM[addr as usize],
 
             // ROM Banks.
-            0x0000..=0x7FFF => self.cartridge.read_byte(addr),
+            0x0000..=0x7FFF => {
+                cheats::patch_rom(&self.cheats, addr, self.cartridge.read_byte(addr))
+            }
 
             // Video RAM, rerouted to PPU.
             0x8000..=0x9FFF => self.ppu.read_byte(addr),
@@ -158,11 +414,15 @@ impl Bus {
             0xFE00..=0xFE9F => self.ppu.read_byte(addr),
 
             // Not Usable
+            0xFEA0..=0xFEFF if self.unusable_reads_accurate => self.read_unusable(addr),
             0xFEA0..=0xFEFF => 0xFF,
 
             // P1 - JOYP register.
             0xFF00 => self.joypad.read_byte(addr),
 
+            // SB and SC registers.
+            0xFF01..=0xFF02 => self.serial.read_byte(addr),
+
             // DIV, TIMA and co.
             0xFF04..=0xFF07 => self.timer.read_byte(addr),
 
@@ -180,7 +440,7 @@ impl Bus {
             // DMA transfer request.
             0xFF46 => 0xFF,
 
-            0xFF4D => self.speed_reg,
+            0xFF4D if self.cgb_mode => self.speed_reg,
 
             0xFF50 => {
                 if self.boot_reg != 0 {
@@ -190,12 +450,33 @@ impl Bus {
                 }
             }
 
-            // HDMA5.
+            // HDMA1-4 are write only and read as 0xFF.
+            // HDMA5 reads 0xFF when no transfer is active.
             0xFF55 if self.cgb_mode => self.dma_control,
 
+            // RP.
+            0xFF56 if self.cgb_mode => {
+                let led_on = (self.rp_reg & 0x01) != 0;
+                let read_enabled = (self.rp_reg & 0xC0) == 0xC0;
+
+                // Bit 1 reads as zero only while receiving IR light.
+                let receiving =
+                    read_enabled && matches!(&self.ir_callback, Some(callback) if callback(led_on));
+
+                (self.rp_reg & 0xC1) | 0x3C | if receiving { 0x00 } else { 0x02 }
+            }
+
             // SVBK.
             0xFF70 if self.cgb_mode => self.wram_bank as u8,
 
+            // PCM12 and PCM34, the digital outputs of the sound channels.
+            0xFF76 | 0xFF77 if self.cgb_mode => {
+                let first = ((addr - 0xFF76) * 2) as u8;
+                let output = |channel| self.apu.channel_output(channel).unwrap_or(0);
+
+                output(first) | (output(first + 1) << 4)
+            }
+
             // High RAM.
             0xFF80..=0xFFFE => self.high_ram[(addr - 0xFF80) as usize],
 
@@ -215,18 +496,44 @@ impl Bus {
     /// Write a byte to the given address.
     /// Tick the components if specified.
     pub fn write_byte(&mut self, addr: u16, value: u8, tick: bool) {
+        let blocked = tick && self.oam_dma_blocks(addr);
+
+        // Only writes made by the CPU can hit a breakpoint, and not
+        // the ones which are dropped by an OAM DMA transfer.
+        if tick
+            && !blocked
+            && self
+                .write_breakpoints
+                .iter()
+                .any(|&(start, end)| (start..=end).contains(&addr))
+        {
+            self.write_breakpoint_hit = Some((addr, value));
+        }
+
         match addr {
+            _ if blocked => {}
+
             // First 256 bytes map to bootrom.
             0x0000..=0x00FF if self.boot_reg == 0 => {}
 
+            // ROM without a MBC, nothing is listening here.
+            0x0000..=0x7FFF if !self.cartridge.has_mbc() => self.unmapped_write(addr, value),
+
             // ROM Banks.
             0x0000..=0x7FFF => self.cartridge.write_byte(addr, value),
 
             // Video RAM, rerouted to PPU.
             0x8000..=0x9FFF => self.ppu.write_byte(addr, value),
 
-            // External RAM
-            0xA000..=0xBFFF => self.cartridge.write_byte(addr, value),
+            // External RAM, writes are reported if the size declared
+            // in the header was unknown and no RAM was allocated.
+            0xA000..=0xBFFF => {
+                if self.unknown_ram_size {
+                    self.unmapped_write(addr, value);
+                }
+
+                self.cartridge.write_byte(addr, value);
+            }
 
             // Work RAM and Echo RAM
             0xC000..=0xCFFF | 0xE000..=0xEFFF => self.work_ram[(addr & 0xFFF) as usize] = value,
@@ -240,11 +547,14 @@ impl Bus {
             0xFE00..=0xFE9F => self.ppu.write_byte(addr, value),
 
             // Not Usable
-            0xFEA0..=0xFEFF => {}
+            0xFEA0..=0xFEFF => self.unmapped_write(addr, value),
 
             // P1 - JOYP register.
             0xFF00 => self.joypad.write_byte(addr, value),
 
+            // SB and SC registers.
+            0xFF01..=0xFF02 => self.serial.write_byte(addr, value),
+
             // DIV, TIMA and co.
             0xFF04..=0xFF07 => self.timer.write_byte(addr, value),
 
@@ -263,19 +573,38 @@ impl Bus {
             0xFF46 => {
                 let source = (value as u16) * 0x100;
 
-                for i in 0..0xA0 {
-                    let byte = self.read_byte(source + i, false);
+                // A write during an active transfer restarts it from the new
+                // source, the old transfer still copies one more byte.
+                if self.oam_dma_accurate {
+                    self.oam_dma_pending = Some(source);
+                } else {
+                    for i in 0..0xA0 {
+                        let byte = self.read_byte(source + i, false);
 
-                    self.write_byte(0xFE00 + i, byte, false);
+                        self.write_byte(0xFE00 + i, byte, false);
+                    }
                 }
             }
 
-            0xFF4D => self.speed_reg = value & 0b0000_0001,
+            // KEY0 is only writable while the CGB boot ROM is mapped.
+            0xFF4C if self.cgb_mode && self.boot_reg == 0 => {
+                self.key0_reg = value;
+                self.ppu.set_dmg_compatibility(self.is_dmg_compatibility());
+            }
+
+            // KEY1 doesn't exist on the DMG, so a speed switch can't be armed.
+            0xFF4D if self.cgb_mode => self.speed_reg = value & 0b0000_0001,
 
             // BOOT register.
             0xFF50 => {
                 if self.boot_reg == 0 {
                     self.boot_reg = value;
+
+                    // The rest of the system leaves CGB mode once the
+                    // boot ROM, which runs in CGB mode, is unmapped.
+                    if self.is_dmg_compatibility() {
+                        self.cgb_mode = false;
+                    }
                 }
             }
 
@@ -310,7 +639,9 @@ impl Bus {
 
                 // Check if the DMA is a GDMA or a HDMA.
                 if (value & 0x80) != 0 {
-                    self.dma_control = value;
+                    // While an HDMA is active bit 7 reads as zero, and the
+                    // lower 7 bits contain the remaining length.
+                    self.dma_control = value & 0x7F;
 
                     self.hdma_len = len;
                     self.hdma_dst = dst;
@@ -319,9 +650,10 @@ impl Bus {
                 } else {
                     // If HDMA was activated earlier and top bit is 0 it means
                     // instead of GDMA the ROM wants to cancel the earlier DMA.
+                    // The remaining length can still be read back.
                     if self.hdma_active {
                         self.hdma_active = false;
-                        self.dma_control = 0xFF;
+                        self.dma_control |= 0x80;
                         return;
                     }
 
@@ -337,6 +669,15 @@ impl Bus {
                 }
             }
 
+            // RP.
+            0xFF56 if self.cgb_mode => {
+                self.rp_reg = value & 0xC1;
+
+                if let Some(callback) = &self.ir_callback {
+                    callback((value & 0x01) != 0);
+                }
+            }
+
             0xFF70 if self.cgb_mode => {
                 let bank = (value & 0b111) as usize;
 
@@ -357,6 +698,58 @@ impl Bus {
         }
     }
 
+    /// Read from the unusable region at 0xFEA0 - 0xFEFF.
+    ///
+    /// This reads 0xFF while the PPU is using OAM. Otherwise the DMG
+    /// reads 0x00, and the CGB (revision E) returns the upper nibble of
+    /// the lower address byte twice, so 0xFEAx reads as 0xAA.
+    fn read_unusable(&self, addr: u16) -> u8 {
+        let lcd_enabled = (self.ppu.read_byte(0xFF40) & 0x80) != 0;
+        let mode = self.ppu.read_byte(0xFF41) & 0x03;
+
+        if lcd_enabled && (mode == 2 || mode == 3) {
+            0xFF
+        } else if self.cgb_mode {
+            (((addr >> 4) & 0x0F) as u8) * 0x11
+        } else {
+            0x00
+        }
+    }
+
+    /// Report a write to a region where nothing is mapped.
+    fn unmapped_write(&self, addr: u16, value: u8) {
+        if let Some(callback) = &self.unmapped_write_callback {
+            callback(addr, value);
+        }
+    }
+
+    /// Whether the CPU is locked out of the given address by an active
+    /// OAM DMA transfer. Only the IO registers and HRAM stay accessible.
+    fn oam_dma_blocks(&self, addr: u16) -> bool {
+        self.oam_dma_accurate && self.oam_dma_src.is_some() && addr < 0xFF00
+    }
+
+    /// Copy one byte of the active OAM DMA transfer, and start
+    /// the requested one if there is any.
+    fn tick_oam_dma(&mut self) {
+        if let Some(source) = self.oam_dma_src {
+            let index = self.oam_dma_index;
+            let byte = self.read_byte(source + index, false);
+
+            self.write_byte(0xFE00 + index, byte, false);
+            self.oam_dma_index += 1;
+
+            if self.oam_dma_index == 0xA0 {
+                self.oam_dma_src = None;
+            }
+        }
+
+        if let Some(source) = self.oam_dma_pending.take() {
+            self.oam_dma_src = Some(source);
+            self.oam_dma_index = 0;
+        }
+    }
+
     /// Skip the bootrom, and initialize all the registers.
     pub fn skip_bootrom(&mut self) {
         self.write_byte(0xFF40, 0x91, false);
@@ -367,6 +760,11 @@ impl Bus {
         self.boot_reg = 1;
     }
 
+    /// Check if the CGB boot ROM selected DMG compatibility mode.
+    fn is_dmg_compatibility(&self) -> bool {
+        (self.key0_reg & 0b0000_0100) != 0
+    }
+
     /// Check if we are in double speed mode.
     pub fn is_double_speed(&self) -> bool {
         (self.speed_reg & 0b1000_0000) != 0
@@ -377,12 +775,23 @@ impl Bus {
         let cycles = 4 >> (self.is_double_speed() as u8);
 
         self.timer.tick();
-        self.apu.tick(cycles);
+        self.joypad.tick();
+        self.tick_oam_dma();
+
+        // Paused components are frozen in place for debugging.
+        if !self.apu_paused {
+            self.apu.tick(cycles);
+        }
 
-        let entered_hblank = self.ppu.tick(cycles);
+        let entered_hblank = !self.ppu_paused && self.ppu.tick(cycles);
 
         // If we entered HBlank and HDMA is active perform
         // a transfer of 0x10 bytes.
+        //
+        // While the LCD is off the PPU never enters HBlank, so an active
+        // transfer is paused with its remaining length intact, and resumes
+        // once the LCD is switched back on. Speed switches are instant and
+        // leave the transfer untouched.
         if entered_hblank && self.hdma_active {
             for i in 0..0x10 {
                 let byte = self.read_byte(self.hdma_src + i, false);
@@ -396,7 +805,7 @@ impl Bus {
             self.hdma_src += 0x10;
             self.hdma_dst += 0x10;
 
-            self.dma_control -= 1;
+            self.dma_control = self.dma_control.wrapping_sub(1);
 
             // Switch off HDMA if all bytes are transferred.
             if self.hdma_len == 0 {
@@ -406,3 +815,332 @@ impl Bus {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::rom_with_program;
+
+    /// Create a bus for a CGB cartridge, with the boot ROM skipped.
+    fn cgb_bus() -> Bus {
+        let mut rom = rom_with_program(&[]);
+        rom[0x0143] = 0x80;
+
+        let mut bus = Bus::new(&rom, Box::new(|_| {}), None);
+        bus.skip_bootrom();
+
+        bus
+    }
+
+    /// Tick the bus until the HDMA has copied another block.
+    fn tick_hdma_block(bus: &mut Bus) {
+        let len = bus.hdma_len;
+
+        while bus.hdma_len == len {
+            bus.tick();
+        }
+    }
+
+    #[test]
+    fn hdma5_reports_status_and_remaining_length() {
+        let mut bus = cgb_bus();
+
+        assert_eq!(bus.read_byte(0xFF55, false), 0xFF);
+
+        bus.write_byte(0xFF51, 0xC0, false);
+        bus.write_byte(0xFF52, 0x00, false);
+        bus.write_byte(0xFF53, 0x80, false);
+        bus.write_byte(0xFF54, 0x00, false);
+
+        // Start a HDMA of three blocks.
+        bus.write_byte(0xFF55, 0x82, false);
+        assert_eq!(bus.read_byte(0xFF55, false), 0x02);
+
+        tick_hdma_block(&mut bus);
+        assert_eq!(bus.read_byte(0xFF55, false), 0x01);
+
+        tick_hdma_block(&mut bus);
+        tick_hdma_block(&mut bus);
+        assert_eq!(bus.read_byte(0xFF55, false), 0xFF);
+    }
+
+    #[test]
+    fn rp_stores_the_writable_bits() {
+        let mut bus = cgb_bus();
+
+        bus.write_byte(0xFF56, 0xFF, false);
+        assert_eq!(bus.read_byte(0xFF56, false), 0xFF);
+
+        bus.write_byte(0xFF56, 0x00, false);
+        assert_eq!(bus.read_byte(0xFF56, false), 0x3E);
+
+        // Bit 1 reads as zero while receiving, if reading is enabled.
+        bus.ir_callback = Some(Box::new(|_| true));
+        assert_eq!(bus.read_byte(0xFF56, false), 0x3E);
+
+        bus.write_byte(0xFF56, 0xC0, false);
+        assert_eq!(bus.read_byte(0xFF56, false), 0xFC);
+    }
+
+    #[test]
+    fn rom_only_writes_trigger_the_unmapped_write_callback() {
+        let writes = Rc::new(RefCell::new(Vec::new()));
+
+        let mut bus = Bus::new(&rom_with_program(&[]), Box::new(|_| {}), None);
+        let log = writes.clone();
+        bus.unmapped_write_callback = Some(Box::new(move |addr, value| {
+            log.borrow_mut().push((addr, value))
+        }));
+
+        bus.write_byte(0x2000, 0x01, true);
+        assert_eq!(*writes.borrow(), vec![(0x2000, 0x01)]);
+
+        // Writes to the ROM of a cartridge with a MBC select banks.
+        let mut rom = rom_with_program(&[]);
+        rom[0x0147] = 0x01;
+
+        let mut bus = Bus::new(&rom, Box::new(|_| {}), None);
+        let log = writes.clone();
+        bus.unmapped_write_callback = Some(Box::new(move |addr, value| {
+            log.borrow_mut().push((addr, value))
+        }));
+
+        bus.write_byte(0x2000, 0x01, true);
+        assert_eq!(writes.borrow().len(), 1);
+    }
+
+    #[test]
+    fn unknown_ram_size_writes_trigger_the_unmapped_write_callback() {
+        let writes = Rc::new(RefCell::new(Vec::new()));
+
+        let mut rom = rom_with_program(&[]);
+        rom[0x0147] = 0x1B;
+        rom[0x0149] = 0x07;
+
+        let mut bus = Bus::new(&rom, Box::new(|_| {}), None);
+        let log = writes.clone();
+        bus.unmapped_write_callback = Some(Box::new(move |addr, value| {
+            log.borrow_mut().push((addr, value))
+        }));
+
+        bus.write_byte(0xA123, 0x45, true);
+        assert_eq!(*writes.borrow(), vec![(0xA123, 0x45)]);
+    }
+
+    #[test]
+    fn oam_dma_restarts_from_the_new_source() {
+        let mut bus = Bus::new(&rom_with_program(&[]), Box::new(|_| {}), None);
+
+        bus.oam_dma_accurate = true;
+        bus.write_byte(0xFF40, 0x00, false);
+
+        for i in 0..0xA0 {
+            bus.write_byte(0xC000 + i, 0x11, false);
+            bus.write_byte(0xC100 + i, 0x22, false);
+        }
+
+        // The transfer starts one M-cycle after the write.
+        bus.write_byte(0xFF46, 0xC0, false);
+
+        for _ in 0..40 {
+            bus.tick();
+        }
+
+        bus.write_byte(0xFF46, 0xC1, false);
+
+        // The old transfer copies one more byte before restarting.
+        bus.tick();
+        assert_eq!(bus.read_byte(0xFE27, false), 0x11);
+        assert_eq!(bus.read_byte(0xFE28, false), 0x00);
+
+        for _ in 0..0xA0 {
+            bus.tick();
+        }
+
+        assert!(bus.oam_dma_src.is_none());
+        assert!((0xFE00..0xFEA0).all(|addr| bus.read_byte(addr, false) == 0x22));
+    }
+
+    #[test]
+    fn hdma_pauses_while_the_lcd_is_off() {
+        let mut bus = cgb_bus();
+
+        bus.write_byte(0xFF51, 0xC0, false);
+        bus.write_byte(0xFF52, 0x00, false);
+        bus.write_byte(0xFF53, 0x80, false);
+        bus.write_byte(0xFF54, 0x00, false);
+        bus.write_byte(0xFF55, 0x83, false);
+
+        tick_hdma_block(&mut bus);
+        assert_eq!(bus.read_byte(0xFF55, false), 0x02);
+
+        // Nothing is copied while the LCD is off, for well over a frame.
+        bus.write_byte(0xFF40, 0x11, false);
+
+        for _ in 0..20000 {
+            bus.tick();
+        }
+
+        assert_eq!(bus.read_byte(0xFF55, false), 0x02);
+
+        bus.write_byte(0xFF40, 0x91, false);
+        tick_hdma_block(&mut bus);
+        assert_eq!(bus.read_byte(0xFF55, false), 0x01);
+    }
+
+    #[test]
+    fn key0_selects_dmg_compatibility_during_boot() {
+        let mut rom = rom_with_program(&[]);
+        rom[0x0143] = 0x80;
+
+        let mut bus = Bus::new(&rom, Box::new(|_| {}), None);
+
+        // Put different bytes in the two VRAM banks.
+        bus.write_byte(0xFF4F, 0x01, false);
+        bus.write_byte(0x8000, 0x22, false);
+        bus.write_byte(0xFF4F, 0x00, false);
+        bus.write_byte(0x8000, 0x11, false);
+        bus.write_byte(0xFF4F, 0x01, false);
+        assert_eq!(bus.read_byte(0x8000, false), 0x22);
+
+        // In DMG compatibility mode the PPU no longer banks VRAM, but
+        // the rest of the system stays in CGB mode till the boot ROM ends.
+        bus.write_byte(0xFF4C, 0x04, false);
+        assert_eq!(bus.read_byte(0x8000, false), 0x11);
+        assert!(bus.cgb_mode);
+
+        bus.write_byte(0xFF50, 0x01, false);
+        assert!(!bus.cgb_mode);
+
+        // KEY0 is locked once the boot ROM is unmapped.
+        bus.write_byte(0xFF4C, 0x00, false);
+        assert_eq!(bus.key0_reg, 0x04);
+        assert_eq!(bus.read_byte(0x8000, false), 0x11);
+    }
+
+    #[test]
+    fn unusable_region_reads() {
+        let mut dmg_bus = Bus::new(&rom_with_program(&[]), Box::new(|_| {}), None);
+        dmg_bus.skip_bootrom();
+
+        for (bus, idle) in [(dmg_bus, 0x00), (cgb_bus(), 0xAA)].iter_mut() {
+            // The simple behaviour is to always read 0xFF.
+            bus.write_byte(0xFF40, 0x00, false);
+            assert_eq!(bus.read_byte(0xFEA5, false), 0xFF);
+
+            bus.unusable_reads_accurate = true;
+            assert_eq!(bus.read_byte(0xFEA5, false), *idle);
+
+            // Both models read 0xFF while the PPU uses OAM, in modes 2 and 3.
+            bus.write_byte(0xFF40, 0x91, false);
+
+            for &mode in [2, 3].iter() {
+                while bus.read_byte(0xFF41, false) & 0x03 != mode {
+                    bus.tick();
+                }
+
+                assert_eq!(bus.read_byte(0xFEA5, false), 0xFF);
+            }
+
+            while bus.read_byte(0xFF41, false) & 0x03 != 0 {
+                bus.tick();
+            }
+
+            assert_eq!(bus.read_byte(0xFEA5, false), *idle);
+        }
+    }
+
+    #[test]
+    fn pcm_registers_pack_the_channel_outputs() {
+        let mut bus = cgb_bus();
+
+        // Trigger all four channels at full volume.
+        for &(addr, value) in [
+            (0xFF26, 0x80),
+            (0xFF11, 0x80),
+            (0xFF12, 0xF0),
+            (0xFF14, 0x87),
+            (0xFF16, 0xC0),
+            (0xFF17, 0x80),
+            (0xFF19, 0x87),
+            (0xFF30, 0x9F),
+            (0xFF1A, 0x80),
+            (0xFF1C, 0x20),
+            (0xFF1E, 0x87),
+            (0xFF21, 0xF0),
+            (0xFF23, 0x80),
+        ]
+        .iter()
+        {
+            bus.write_byte(addr, value, false);
+        }
+
+        let mut seen = [false; 4];
+
+        for _ in 0..0x1000 {
+            bus.apu.tick(4);
+
+            let outputs: Vec<u8> = (0..4)
+                .map(|channel| bus.apu.channel_output(channel).unwrap())
+                .collect();
+
+            assert_eq!(bus.read_byte(0xFF76, false), outputs[0] | (outputs[1] << 4));
+            assert_eq!(bus.read_byte(0xFF77, false), outputs[2] | (outputs[3] << 4));
+
+            for (seen, &output) in seen.iter_mut().zip(&outputs) {
+                *seen |= output != 0;
+            }
+        }
+
+        assert_eq!(seen, [true; 4]);
+
+        // The registers only exist on a CGB.
+        let mut dmg_bus = Bus::new(&rom_with_program(&[]), Box::new(|_| {}), None);
+        dmg_bus.skip_bootrom();
+
+        assert_eq!(dmg_bus.read_byte(0xFF76, false), 0xFF);
+        assert_eq!(dmg_bus.read_byte(0xFF77, false), 0xFF);
+    }
+
+    #[test]
+    fn partial_oam_dma_only_leaves_hram_accessible() {
+        let mut bus = Bus::new(&rom_with_program(&[]), Box::new(|_| {}), None);
+
+        bus.oam_dma_accurate = true;
+        bus.write_byte(0xFF40, 0x00, false);
+
+        for i in 0..0xA0 {
+            bus.write_byte(0xC000 + i, 0x11, false);
+        }
+
+        bus.write_byte(0xFF80, 0x42, false);
+        bus.write_byte(0xFF46, 0xC0, false);
+
+        // The start delay, then half of the transfer.
+        for _ in 0..1 + 0x50 {
+            bus.tick();
+        }
+
+        assert_eq!(bus.oam_dma_index, 0x50);
+        assert!((0xFE00..0xFE50).all(|addr| bus.read_byte(addr, false) == 0x11));
+        assert!((0xFE50..0xFEA0).all(|addr| bus.read_byte(addr, false) == 0x00));
+
+        // The CPU only sees HRAM and the IO registers.
+        assert_eq!(bus.read_byte(0xC000, true), 0xFF);
+        assert_eq!(bus.read_byte(0xFE00, true), 0xFF);
+        assert_eq!(bus.read_byte(0xFF80, true), 0x42);
+
+        bus.write_byte(0xC000, 0x33, true);
+        bus.write_byte(0xFF81, 0x24, true);
+        assert_eq!(bus.read_byte(0xFF81, true), 0x24);
+
+        // Once the transfer is done, the CPU can access everything again.
+        while bus.oam_dma_src.is_some() {
+            bus.tick();
+        }
+
+        assert_eq!(bus.oam_dma_index, 0xA0);
+        assert_eq!(bus.read_byte(0xC000, true), 0x11);
+        assert!((0xFE00..0xFEA0).all(|addr| bus.read_byte(addr, false) == 0x11));
+    }
+}
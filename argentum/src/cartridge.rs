@@ -1,7 +1,72 @@
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::savestate::{ensure, SaveStateError, StateReader, StateWriter};
+
 /// RAM Size corresponding to indices
 /// in cartridge headers.
 const RAM_SIZES: [usize; 6] = [0x0000, 0x0000, 0x2000, 0x8000, 0x20000, 0x10000];
 
+/// Decode the RAM size code (0x0149) of a cartridge header to bytes.
+/// Unknown size codes are treated as no RAM.
+pub(crate) fn decode_ram_size(code: u8) -> usize {
+    RAM_SIZES.get(code as usize).copied().unwrap_or(0)
+}
+
+/// Whether the RAM size code (0x0149) of a cartridge header is a known one.
+pub(crate) fn is_known_ram_size(code: u8) -> bool {
+    (code as usize) < RAM_SIZES.len()
+}
+
+/// Decode the ROM size code (0x0148) of a cartridge header to bytes.
+/// Returns `None` for unknown size codes.
+pub(crate) fn decode_rom_size(code: u8) -> Option<usize> {
+    match code {
+        0x00..=0x08 => Some(0x8000 << code),
+
+        // 72, 80 and 96 banks, which only a few carts declared.
+        0x52 => Some(72 * 0x4000),
+        0x53 => Some(80 * 0x4000),
+        0x54 => Some(96 * 0x4000),
+
+        _ => None,
+    }
+}
+
+/// Get the external RAM size declared in the cartridge header.
+fn ram_size(rom: &[u8]) -> usize {
+    decode_ram_size(rom[0x0149])
+}
+
+/// Whether the given cartridge type has a battery to keep its RAM.
+fn is_battery_type(cartridge_type: u8) -> bool {
+    matches!(
+        cartridge_type,
+        0x03 | 0x06 | 0x09 | 0x0D | 0x0F | 0x10 | 0x13 | 0x1B | 0x1E | 0x22 | 0xFF
+    )
+}
+
+/// Get the number of 16 KiB ROM banks declared in the cartridge header.
+/// Unknown size codes fall back to the size of the ROM itself.
+fn rom_banks(rom: &[u8]) -> usize {
+    let size = decode_rom_size(rom[0x0148]).unwrap_or(rom.len());
+
+    size.div_ceil(0x4000).max(2)
+}
+
+/// Copy the ROM, padding it with 0xFF up to the size declared in
+/// the cartridge header, so that truncated dumps can't be indexed
+/// out of bounds.
+fn load_rom(rom: &[u8]) -> Vec<u8> {
+    let mut rom = rom.to_vec();
+    let declared_size = rom_banks(&rom) * 0x4000;
+
+    if rom.len() < declared_size {
+        rom.resize(declared_size, 0xFF);
+    }
+
+    rom
+}
+
 /// Trait implemented by all cartridges.
 pub trait Cartridge {
     /// Return the title of the game.
@@ -13,6 +78,12 @@ pub trait Cartridge {
     /// Write a byte to the given address.
     fn write_byte(&mut self, addr: u16, value: u8);
 
+    /// Whether the cartridge has a MBC that listens to writes
+    /// in the ROM area.
+    fn has_mbc(&self) -> bool {
+        true
+    }
+
     /// Detects whether the game is a CGB game.
     fn has_cgb_support(&self) -> bool {
         let cgb_flag_byte = self.read_byte(0x0143);
@@ -21,7 +92,45 @@ pub trait Cartridge {
         (cgb_flag_byte & 0x80) != 0
     }
 
+    /// Detects whether the game only runs on a CGB.
+    fn is_cgb_only(&self) -> bool {
+        // CGB only games set both of the upper bits.
+        self.read_byte(0x0143) == 0xC0
+    }
+
+    /// Detects whether the cartridge has a battery to keep its RAM.
+    fn has_battery(&self) -> bool {
+        is_battery_type(self.read_byte(0x0147))
+    }
+
+    /// Set the real time clock to the given time since the Unix epoch.
+    /// Cartridges without a clock ignore this.
+    fn set_rtc(&mut self, _time: Duration) {}
+
+    /// Return the MBC1 banking mode, true for the advanced mode.
+    /// Other cartridges return `None`.
+    fn banking_mode(&self) -> Option<bool> {
+        None
+    }
+
+    /// Set the MBC1 banking mode, other cartridges ignore this.
+    fn set_banking_mode(&mut self, _advanced: bool) {}
+
+    /// Return the number of 16 KiB ROM banks.
+    fn rom_bank_count(&self) -> usize;
+
+    /// Return the number of 8 KiB external RAM banks.
+    fn ram_bank_count(&self) -> usize;
+
     fn dump_ram(&self) -> Option<Vec<u8>>;
+
+    /// Serialize the external RAM and the MBC registers.
+    fn save_state(&self, _state: &mut StateWriter) {}
+
+    /// Restore the external RAM and the MBC registers.
+    fn load_state(&mut self, _state: &mut StateReader) -> Result<(), SaveStateError> {
+        Ok(())
+    }
 }
 
 /// Cartridge with just two ROM banks.
@@ -33,7 +142,7 @@ pub struct RomOnly {
 impl RomOnly {
     /// Create a new `RomOnly` instance.
     pub fn new(rom: &[u8]) -> Self {
-        Self { rom: rom.to_vec() }
+        Self { rom: load_rom(rom) }
     }
 }
 
@@ -47,11 +156,28 @@ impl Cartridge for RomOnly {
     }
 
     fn read_byte(&self, addr: u16) -> u8 {
-        self.rom[addr as usize]
+        match addr {
+            0x0000..=0x7FFF => self.rom[addr as usize],
+
+            // There is no external RAM, the bus is left floating.
+            _ => 0xFF,
+        }
     }
 
     fn write_byte(&mut self, _: u16, _: u8) {}
 
+    fn has_mbc(&self) -> bool {
+        false
+    }
+
+    fn rom_bank_count(&self) -> usize {
+        2
+    }
+
+    fn ram_bank_count(&self) -> usize {
+        0
+    }
+
     fn dump_ram(&self) -> Option<Vec<u8>> {
         None
     }
@@ -91,16 +217,27 @@ pub struct Mbc1 {
 
 impl Mbc1 {
     /// Create a new `Mbc1` instance.
-    pub fn new(rom: &[u8]) -> Self {
+    pub fn new(rom: &[u8], save_file: Option<Vec<u8>>) -> Self {
+        let mut ram = vec![0u8; ram_size(rom)];
+
+        // Only cartridges with a battery keep their RAM.
+        if is_battery_type(rom[0x0147]) {
+            if let Some(ram_save) = save_file {
+                if ram.len() == ram_save.len() {
+                    ram.copy_from_slice(&ram_save);
+                }
+            }
+        }
+
         Self {
-            rom: rom.to_vec(),
-            ram: vec![0u8; RAM_SIZES[rom[0x0149] as usize]],
+            rom: load_rom(rom),
+            ram,
             ram_enabled: false,
             rom_bank_lower: 1,
             rom_bank_upper: 0,
             banking_mode: false,
-            rom_banks: 2 * 2usize.pow(rom[0x0148] as u32),
-            ram_banks: (RAM_SIZES[rom[0x0149] as usize] >> 13) as usize,
+            rom_banks: rom_banks(rom),
+            ram_banks: (ram_size(rom) >> 13) as usize,
         }
     }
 }
@@ -117,7 +254,9 @@ impl Cartridge for Mbc1 {
     fn read_byte(&self, addr: u16) -> u8 {
         match addr {
             0x0000..=0x3FFF => {
-                let bank = if self.banking_mode {
+                // Only carts with 1 MB of ROM or more use the upper bank
+                // register to remap this region in mode 1.
+                let bank = if self.banking_mode && self.rom_banks > 32 {
                     (self.rom_bank_upper as usize) << 5
                 } else {
                     0
@@ -137,7 +276,7 @@ impl Cartridge for Mbc1 {
                 self.rom[addr]
             }
 
-            0xA000..=0xBFFF if self.ram_enabled => {
+            0xA000..=0xBFFF if self.ram_enabled && self.ram_banks > 0 => {
                 let bank = if self.banking_mode {
                     self.rom_bank_upper as usize
                 } else {
@@ -175,7 +314,7 @@ impl Cartridge for Mbc1 {
                 self.banking_mode = (value & 0b1) != 0;
             }
 
-            0xA000..=0xBFFF if self.ram_enabled => {
+            0xA000..=0xBFFF if self.ram_enabled && self.ram_banks > 0 => {
                 let bank = if self.banking_mode {
                     self.rom_bank_upper as usize
                 } else {
@@ -191,6 +330,22 @@ impl Cartridge for Mbc1 {
         }
     }
 
+    fn banking_mode(&self) -> Option<bool> {
+        Some(self.banking_mode)
+    }
+
+    fn set_banking_mode(&mut self, advanced: bool) {
+        self.banking_mode = advanced;
+    }
+
+    fn rom_bank_count(&self) -> usize {
+        self.rom_banks
+    }
+
+    fn ram_bank_count(&self) -> usize {
+        self.ram_banks
+    }
+
     fn dump_ram(&self) -> Option<Vec<u8>> {
         if !self.ram.is_empty() {
             Some(self.ram.clone())
@@ -198,6 +353,362 @@ impl Cartridge for Mbc1 {
             None
         }
     }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.ram);
+        state.bool(self.ram_enabled);
+        state.u8(self.rom_bank_lower);
+        state.u8(self.rom_bank_upper);
+        state.bool(self.banking_mode);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), SaveStateError> {
+        state.bytes(&mut self.ram)?;
+        self.ram_enabled = state.bool()?;
+        self.rom_bank_lower = state.u8()?;
+        self.rom_bank_upper = state.u8()?;
+        self.banking_mode = state.bool()?;
+
+        ensure(self.rom_bank_lower <= 0x1F && self.rom_bank_upper <= 0x03)?;
+
+        Ok(())
+    }
+}
+
+/// Cartridge with the MBC2 chip.
+/// Max 2 MBit ROM and 512x4 bits of built-in RAM.
+pub struct Mbc2 {
+    /// ROM with a maximum size of 2 MBit.
+    rom: Vec<u8>,
+
+    /// The built-in RAM, 512 half bytes.
+    /// Only the lower nibble of each byte is used.
+    ram: Vec<u8>,
+
+    /// RAM gate register.
+    /// Used to enable access to the built-in RAM.
+    ram_enabled: bool,
+
+    /// ROM bank register.
+    /// Stores the 4 bits of the ROM bank, which can't be zero.
+    rom_bank: u8,
+
+    /// The number of ROM banks in the cartridge.
+    rom_banks: usize,
+}
+
+impl Mbc2 {
+    /// Create a new `Mbc2` instance.
+    pub fn new(rom: &[u8], save_file: Option<Vec<u8>>) -> Self {
+        // The RAM is built into the MBC, the header declares none.
+        let mut ram = vec![0u8; 0x200];
+
+        if is_battery_type(rom[0x0147]) {
+            if let Some(ram_save) = save_file {
+                if ram.len() == ram_save.len() {
+                    ram.copy_from_slice(&ram_save);
+                }
+            }
+        }
+
+        Self {
+            rom: load_rom(rom),
+            ram,
+            ram_enabled: false,
+            rom_bank: 1,
+            rom_banks: rom_banks(rom),
+        }
+    }
+}
+
+impl Cartridge for Mbc2 {
+    fn game_title(&self) -> String {
+        if self.has_cgb_support() {
+            String::from_utf8_lossy(&self.rom[0x134..=0x013E]).into()
+        } else {
+            String::from_utf8_lossy(&self.rom[0x134..=0x0143]).into()
+        }
+    }
+
+    fn read_byte(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x3FFF => self.rom[addr as usize],
+
+            0x4000..=0x7FFF => {
+                let bank = (self.rom_bank as usize) % self.rom_banks;
+                let addr = (bank * 0x4000) + (addr as usize - 0x4000);
+
+                self.rom[addr]
+            }
+
+            // The 512 half bytes are mirrored through the whole region,
+            // and the upper nibble is left floating.
+            0xA000..=0xBFFF if self.ram_enabled => self.ram[(addr & 0x1FF) as usize] | 0xF0,
+
+            _ => 0xFF,
+        }
+    }
+
+    fn write_byte(&mut self, addr: u16, value: u8) {
+        match addr {
+            // Bit 8 of the address selects between the
+            // RAM gate and the ROM bank register.
+            0x0000..=0x3FFF => {
+                if (addr & 0x100) == 0 {
+                    self.ram_enabled = (value & 0x0F) == 0b1010;
+                } else if (value & 0x0F) == 0 {
+                    self.rom_bank = 1;
+                } else {
+                    self.rom_bank = value & 0x0F;
+                }
+            }
+
+            0xA000..=0xBFFF if self.ram_enabled => {
+                self.ram[(addr & 0x1FF) as usize] = value & 0x0F;
+            }
+
+            _ => {}
+        }
+    }
+
+    fn rom_bank_count(&self) -> usize {
+        self.rom_banks
+    }
+
+    fn ram_bank_count(&self) -> usize {
+        0
+    }
+
+    fn dump_ram(&self) -> Option<Vec<u8>> {
+        Some(self.ram.clone())
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.ram);
+        state.bool(self.ram_enabled);
+        state.u8(self.rom_bank);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), SaveStateError> {
+        state.bytes(&mut self.ram)?;
+        self.ram_enabled = state.bool()?;
+        self.rom_bank = state.u8()?;
+
+        ensure(self.rom_bank <= 0x0F)?;
+
+        Ok(())
+    }
+}
+
+/// The size of the RTC state appended to MBC3 saves. This is the
+/// format used by BGB and VBA-M, the current and latched registers
+/// as 32-bit words followed by a 64-bit Unix timestamp.
+const RTC_SAVE_SIZE: usize = 48;
+
+/// Real time clock of MBC3 cartridges.
+#[derive(Clone)]
+struct Rtc {
+    /// Seconds, minutes, hours, the lower 8 bits of the day counter,
+    /// and DH. Bit 0 of DH is the upper bit of the day counter, bit 6
+    /// halts the clock and bit 7 is set when the day counter overflows.
+    registers: [u8; 5],
+
+    /// The registers as they were when last latched.
+    /// These are the values the game gets to read.
+    latched: [u8; 5],
+
+    /// Whether 0x00 was the last value written to the latch register.
+    /// Writing 0x01 next latches the registers.
+    latch_armed: bool,
+
+    /// When the registers were last brought up to date.
+    last_update: Option<Instant>,
+
+    /// Time elapsed since the last whole second was counted.
+    subsecond: Duration,
+}
+
+impl Rtc {
+    /// Create a new `Rtc` instance.
+    fn new() -> Self {
+        Self {
+            registers: [0; 5],
+            latched: [0; 5],
+            latch_armed: false,
+            last_update: Some(Instant::now()),
+            subsecond: Duration::default(),
+        }
+    }
+
+    /// Whether the clock is halted.
+    fn is_halted(&self) -> bool {
+        (self.registers[4] & 0b0100_0000) != 0
+    }
+
+    /// Advance the registers by the wall clock time elapsed since
+    /// they were last brought up to date.
+    fn update(&mut self) {
+        let now = Instant::now();
+
+        if let Some(last_update) = self.last_update {
+            if !self.is_halted() {
+                let elapsed = self.subsecond + now.duration_since(last_update);
+
+                self.subsecond = Duration::from_nanos(elapsed.subsec_nanos() as u64);
+                self.advance(elapsed.as_secs());
+            }
+        }
+
+        self.last_update = Some(now);
+    }
+
+    /// Advance the registers by the given number of seconds.
+    fn advance(&mut self, seconds: u64) {
+        if seconds == 0 || self.is_halted() {
+            return;
+        }
+
+        let days = (((self.registers[4] & 0b1) as u64) << 8) | self.registers[3] as u64;
+
+        let total = self.registers[0] as u64
+            + (self.registers[1] as u64 * 60)
+            + (self.registers[2] as u64 * 3600)
+            + (days * 86400)
+            + seconds;
+
+        let days = total / 86400;
+
+        self.registers[0] = (total % 60) as u8;
+        self.registers[1] = ((total / 60) % 60) as u8;
+        self.registers[2] = ((total / 3600) % 24) as u8;
+        self.registers[3] = days as u8;
+        self.registers[4] = (self.registers[4] & 0b1100_0000) | ((days >> 8) & 0b1) as u8;
+
+        // The day counter is 9 bits wide, the carry stays set
+        // until the game clears it.
+        if days > 0x1FF {
+            self.registers[4] |= 0b1000_0000;
+        }
+    }
+
+    /// Write to the latch register.
+    fn write_latch(&mut self, value: u8) {
+        if self.latch_armed && value == 0x01 {
+            self.update();
+            self.latched = self.registers;
+        }
+
+        self.latch_armed = value == 0x00;
+    }
+
+    /// Read the latched value of a register.
+    fn read_register(&self, index: usize) -> u8 {
+        self.latched[index]
+    }
+
+    /// Write to a register, this takes effect immediately.
+    fn write_register(&mut self, index: usize, value: u8) {
+        const MASKS: [u8; 5] = [0x3F, 0x3F, 0x1F, 0xFF, 0xC1];
+
+        self.update();
+
+        // Writing the seconds resets the sub-second counter.
+        if index == 0 {
+            self.subsecond = Duration::default();
+        }
+
+        self.registers[index] = value & MASKS[index];
+        self.latched[index] = self.registers[index];
+    }
+
+    /// Set the registers to the given time since the Unix epoch.
+    fn set_time(&mut self, time: Duration) {
+        let seconds = time.as_secs();
+        let days = seconds / 86400;
+
+        self.update();
+
+        self.registers[0] = (seconds % 60) as u8;
+        self.registers[1] = ((seconds / 60) % 60) as u8;
+        self.registers[2] = ((seconds / 3600) % 24) as u8;
+
+        // Keep the halt flag and clear the carry.
+        self.registers[3] = days as u8;
+        self.registers[4] = (self.registers[4] & 0b0100_0000) | ((days >> 8) & 0b1) as u8;
+
+        self.subsecond = Duration::from_nanos(time.subsec_nanos() as u64);
+        self.latched = self.registers;
+    }
+
+    /// Serialize the clock for saving, with the current time as the timestamp.
+    fn save(&self) -> [u8; RTC_SAVE_SIZE] {
+        let mut data = [0u8; RTC_SAVE_SIZE];
+
+        let mut rtc = self.clone();
+        rtc.update();
+
+        for (i, &value) in rtc.registers.iter().chain(&rtc.latched).enumerate() {
+            data[i * 4] = value;
+        }
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        data[40..].copy_from_slice(&timestamp.to_le_bytes());
+
+        data
+    }
+
+    /// Restore the clock from a save, accounting for the time that
+    /// passed since it was saved.
+    fn load(&mut self, data: &[u8]) {
+        // Time spent before the save was loaded is covered by the
+        // timestamp, so the clock counts again from now on.
+        self.last_update = Some(Instant::now());
+        self.subsecond = Duration::default();
+
+        for i in 0..5 {
+            self.registers[i] = data[i * 4];
+            self.latched[i] = data[(i + 5) * 4];
+        }
+
+        let mut timestamp = [0u8; 8];
+        timestamp.copy_from_slice(&data[40..48]);
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        self.advance(now.saturating_sub(u64::from_le_bytes(timestamp)));
+    }
+
+    /// Serialize the clock as of its last update. Unlike `save`, no
+    /// timestamp is included, so the same machine state always gives
+    /// the same save state.
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.registers);
+        state.bytes(&self.latched);
+        state.bool(self.latch_armed);
+        state.u32(self.subsecond.subsec_nanos());
+    }
+
+    /// Restore the clock, which counts again from now on.
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), SaveStateError> {
+        state.bytes(&mut self.registers)?;
+        state.bytes(&mut self.latched)?;
+        self.latch_armed = state.bool()?;
+
+        let nanos = state.u32()?;
+        ensure(nanos < 1_000_000_000)?;
+
+        self.subsecond = Duration::from_nanos(nanos as u64);
+        self.last_update = Some(Instant::now());
+
+        Ok(())
+    }
 }
 
 /// Cartridge with the MBC3 chip.
@@ -226,29 +737,44 @@ pub struct Mbc3 {
 
     /// The number of RAM banks in the cartridge.
     ram_banks: usize,
+
+    /// Whether the cartridge has a real time clock.
+    has_rtc: bool,
+
+    /// The real time clock, its registers are selected with RAMB 0x08 - 0x0C.
+    rtc: Rtc,
 }
 
 impl Mbc3 {
     /// Create a new `Mbc3` instance.
     pub fn new(rom: &[u8], save_file: Option<Vec<u8>>) -> Self {
-        let mut ram = vec![0u8; RAM_SIZES[rom[0x0149] as usize]];
+        let mut ram = vec![0u8; ram_size(rom)];
+        let mut rtc = Rtc::new();
 
-        if !ram.is_empty() {
-            if let Some(ram_save) = save_file {
-                if ram.len() == ram_save.len() {
-                    ram.copy_from_slice(&ram_save);
-                }
+        let has_rtc = matches!(rom[0x0147], 0x0F | 0x10);
+
+        // Saves of cartridges with a clock may have its state appended.
+        if let Some(save) = save_file {
+            let ram_len = ram.len();
+
+            if save.len() == ram_len + RTC_SAVE_SIZE && has_rtc {
+                rtc.load(&save[ram_len..]);
+                ram.copy_from_slice(&save[..ram_len]);
+            } else if save.len() == ram_len {
+                ram.copy_from_slice(&save);
             }
         }
 
         Self {
-            rom: rom.to_vec(),
+            rom: load_rom(rom),
             ram,
             ram_enabled: false,
             rom_bank: 1,
             ram_bank: 0,
-            rom_banks: 2 * 2usize.pow(rom[0x0148] as u32),
-            ram_banks: (RAM_SIZES[rom[0x0149] as usize] >> 13) as usize,
+            rom_banks: rom_banks(rom),
+            ram_banks: (ram_size(rom) >> 13) as usize,
+            has_rtc,
+            rtc,
         }
     }
 }
@@ -274,13 +800,19 @@ impl Cartridge for Mbc3 {
                 self.rom[addr]
             }
 
-            0xA000..=0xBFFF if self.ram_enabled => {
+            0xA000..=0xBFFF if self.ram_enabled && self.ram_bank <= 0x03 && self.ram_banks > 0 => {
                 let addr =
                     (0x2000 * (self.ram_bank as usize % self.ram_banks)) + (addr as usize - 0xA000);
 
                 self.ram[addr]
             }
 
+            0xA000..=0xBFFF if self.ram_enabled && self.has_rtc => match self.ram_bank {
+                0x08..=0x0C => self.rtc.read_register((self.ram_bank - 0x08) as usize),
+
+                _ => 0xFF,
+            },
+
             _ => 0xFF,
         }
     }
@@ -300,27 +832,75 @@ impl Cartridge for Mbc3 {
             }
 
             0x4000..=0x5FFF => {
-                self.ram_bank = value & 0b11;
+                self.ram_bank = value & 0x0F;
             }
 
-            0xA000..=0xBFFF if self.ram_enabled => {
+            0x6000..=0x7FFF if self.has_rtc => self.rtc.write_latch(value),
+
+            0xA000..=0xBFFF if self.ram_enabled && self.ram_bank <= 0x03 && self.ram_banks > 0 => {
                 let addr =
                     (0x2000 * (self.ram_bank as usize % self.ram_banks)) + (addr as usize - 0xA000);
 
                 self.ram[addr] = value;
             }
 
+            0xA000..=0xBFFF if self.ram_enabled && self.has_rtc => {
+                if let 0x08..=0x0C = self.ram_bank {
+                    self.rtc
+                        .write_register((self.ram_bank - 0x08) as usize, value);
+                }
+            }
+
             _ => {}
         }
     }
 
+    fn set_rtc(&mut self, time: Duration) {
+        if self.has_rtc {
+            self.rtc.set_time(time);
+        }
+    }
+
+    fn rom_bank_count(&self) -> usize {
+        self.rom_banks
+    }
+
+    fn ram_bank_count(&self) -> usize {
+        self.ram_banks
+    }
+
     fn dump_ram(&self) -> Option<Vec<u8>> {
-        if !self.ram.is_empty() {
-            Some(self.ram.clone())
+        let mut save = self.ram.clone();
+
+        if self.has_rtc {
+            save.extend_from_slice(&self.rtc.save());
+        }
+
+        if !save.is_empty() {
+            Some(save)
         } else {
             None
         }
     }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.ram);
+        state.bool(self.ram_enabled);
+        state.u8(self.rom_bank);
+        state.u8(self.ram_bank);
+        self.rtc.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), SaveStateError> {
+        state.bytes(&mut self.ram)?;
+        self.ram_enabled = state.bool()?;
+        self.rom_bank = state.u8()?;
+        self.ram_bank = state.u8()?;
+
+        ensure(self.rom_bank <= 0x7F && self.ram_bank <= 0x0F)?;
+
+        self.rtc.load_state(state)
+    }
 }
 
 /// Cartridge with the MBC5 chip.
@@ -357,16 +937,27 @@ pub struct Mbc5 {
 
 impl Mbc5 {
     /// Create a new `Mbc5` instance.
-    pub fn new(rom: &[u8]) -> Self {
+    pub fn new(rom: &[u8], save_file: Option<Vec<u8>>) -> Self {
+        let mut ram = vec![0u8; ram_size(rom)];
+
+        // Only cartridges with a battery keep their RAM.
+        if is_battery_type(rom[0x0147]) {
+            if let Some(ram_save) = save_file {
+                if ram.len() == ram_save.len() {
+                    ram.copy_from_slice(&ram_save);
+                }
+            }
+        }
+
         Self {
-            rom: rom.to_vec(),
-            ram: vec![0u8; RAM_SIZES[rom[0x0149] as usize]],
+            rom: load_rom(rom),
+            ram,
             ram_enabled: false,
             rom_bank_lower: 1,
             rom_bank_upper: 0,
             ram_bank: 0,
-            rom_banks: 2 * 2usize.pow(rom[0x0148] as u32),
-            ram_banks: (RAM_SIZES[rom[0x0149] as usize] >> 13) as usize,
+            rom_banks: rom_banks(rom),
+            ram_banks: (ram_size(rom) >> 13) as usize,
         }
     }
 }
@@ -395,7 +986,7 @@ impl Cartridge for Mbc5 {
                 self.rom[addr]
             }
 
-            0xA000..=0xBFFF if self.ram_enabled => {
+            0xA000..=0xBFFF if self.ram_enabled && self.ram_banks > 0 => {
                 let addr =
                     (0x2000 * (self.ram_bank as usize % self.ram_banks)) + (addr as usize - 0xA000);
 
@@ -412,6 +1003,8 @@ impl Cartridge for Mbc5 {
                 self.ram_enabled = value == 0b0000_1010;
             }
 
+            // Unlike MBC1, a zero written here is not translated to
+            // bank 1, and bank 0 can be mapped into 0x4000..=0x7FFF.
             0x2000..=0x2FFF => {
                 self.rom_bank_lower = value;
             }
@@ -424,8 +1017,9 @@ impl Cartridge for Mbc5 {
                 self.ram_bank = value & 0b1111;
             }
 
-            0xA000..=0xBFFF if self.ram_enabled => {
-                let addr = (0x2000 * self.ram_bank as usize) + (addr as usize - 0xA000);
+            0xA000..=0xBFFF if self.ram_enabled && self.ram_banks > 0 => {
+                let addr =
+                    (0x2000 * (self.ram_bank as usize % self.ram_banks)) + (addr as usize - 0xA000);
 
                 self.ram[addr] = value;
             }
@@ -434,6 +1028,14 @@ impl Cartridge for Mbc5 {
         }
     }
 
+    fn rom_bank_count(&self) -> usize {
+        self.rom_banks
+    }
+
+    fn ram_bank_count(&self) -> usize {
+        self.ram_banks
+    }
+
     fn dump_ram(&self) -> Option<Vec<u8>> {
         if !self.ram.is_empty() {
             Some(self.ram.clone())
@@ -441,4 +1043,388 @@ impl Cartridge for Mbc5 {
             None
         }
     }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.ram);
+        state.bool(self.ram_enabled);
+        state.u8(self.rom_bank_lower);
+        state.u8(self.rom_bank_upper);
+        state.u8(self.ram_bank);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), SaveStateError> {
+        state.bytes(&mut self.ram)?;
+        self.ram_enabled = state.bool()?;
+        self.rom_bank_lower = state.u8()?;
+        self.rom_bank_upper = state.u8()?;
+        self.ram_bank = state.u8()?;
+
+        ensure(self.rom_bank_upper <= 0x01 && self.ram_bank <= 0x0F)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a ROM with the given header bytes, whose banks
+    /// hold their own number in their last byte.
+    fn rom(cartridge_type: u8, rom_size: u8, ram_size: u8) -> Vec<u8> {
+        let mut rom = vec![0; decode_rom_size(rom_size).unwrap()];
+
+        for (bank, chunk) in rom.chunks_exact_mut(0x4000).enumerate() {
+            chunk[0x3FFF] = bank as u8;
+        }
+
+        rom[0x0147] = cartridge_type;
+        rom[0x0148] = rom_size;
+        rom[0x0149] = ram_size;
+
+        rom
+    }
+
+    #[test]
+    fn mbc5_maps_bank_zero_into_switchable_region() {
+        let mut mbc = Mbc5::new(&rom(0x19, 2, 0), None);
+
+        mbc.write_byte(0x2000, 3);
+        assert_eq!(mbc.read_byte(0x7FFF), 3);
+
+        mbc.write_byte(0x2000, 0);
+        assert_eq!(mbc.read_byte(0x7FFF), 0);
+        assert_eq!(mbc.read_byte(0x3FFF), 0);
+    }
+
+    #[test]
+    fn mbc1_mode_one_keeps_bank_zero_on_small_carts() {
+        // 256 KB, 16 banks.
+        let mut mbc = Mbc1::new(&rom(0x01, 3, 0), None);
+
+        mbc.write_byte(0x4000, 1);
+        mbc.write_byte(0x6000, 1);
+
+        assert_eq!(mbc.read_byte(0x3FFF), 0);
+    }
+
+    #[test]
+    fn mbc1_mode_one_remaps_bank_zero_on_large_carts() {
+        // 2 MB, 128 banks.
+        let mut mbc = Mbc1::new(&rom(0x01, 6, 0), None);
+
+        mbc.write_byte(0x4000, 1);
+        assert_eq!(mbc.read_byte(0x3FFF), 0);
+
+        mbc.write_byte(0x6000, 1);
+        assert_eq!(mbc.read_byte(0x3FFF), 32);
+        assert_eq!(mbc.read_byte(0x7FFF), 33);
+    }
+
+    #[test]
+    fn cgb_flag_bytes() {
+        for &(flag, support, only) in [
+            (0x00, false, false),
+            (0x80, true, false),
+            (0xC0, true, true),
+        ]
+        .iter()
+        {
+            let mut rom = rom(0x00, 0, 0);
+            rom[0x0143] = flag;
+
+            let cartridge = RomOnly::new(&rom);
+
+            assert_eq!(cartridge.has_cgb_support(), support);
+            assert_eq!(cartridge.is_cgb_only(), only);
+        }
+    }
+
+    #[test]
+    fn ram_less_carts_read_open_bus() {
+        let mut carts: [Box<dyn Cartridge>; 3] = [
+            Box::new(Mbc1::new(&rom(0x01, 2, 0), None)),
+            Box::new(Mbc3::new(&rom(0x11, 2, 0), None)),
+            Box::new(Mbc5::new(&rom(0x19, 2, 0), None)),
+        ];
+
+        for cart in carts.iter_mut() {
+            cart.write_byte(0x0000, 0x0A);
+            cart.write_byte(0x4000, 0x03);
+            cart.write_byte(0x6000, 0x01);
+
+            cart.write_byte(0xA000, 0x42);
+            assert_eq!(cart.read_byte(0xA000), 0xFF);
+        }
+    }
+
+    #[test]
+    fn unknown_ram_size_codes_allocate_nothing() {
+        for code in 0x06..=0xFF {
+            assert!(!is_known_ram_size(code));
+            assert_eq!(decode_ram_size(code), 0);
+
+            let mut mbc = Mbc5::new(&rom(0x1B, 2, code), None);
+
+            mbc.write_byte(0x0000, 0x0A);
+            mbc.write_byte(0xA000, 0x42);
+            assert_eq!(mbc.read_byte(0xA000), 0xFF);
+            assert_eq!(mbc.dump_ram(), None);
+        }
+    }
+
+    #[test]
+    fn mbc2_ram_is_512_half_bytes() {
+        let mut mbc = Mbc2::new(&rom(0x06, 3, 0), None);
+
+        // The RAM gate is selected with bit 8 of the address clear.
+        mbc.write_byte(0x0100, 0x0A);
+        assert_eq!(mbc.read_byte(0xA000), 0xFF);
+
+        mbc.write_byte(0x0000, 0x0A);
+        mbc.write_byte(0xA000, 0x5C);
+        mbc.write_byte(0xA1FF, 0x03);
+
+        assert_eq!(mbc.read_byte(0xA000), 0xFC);
+        assert_eq!(mbc.read_byte(0xA200), 0xFC);
+        assert_eq!(mbc.read_byte(0xBFFF), 0xF3);
+
+        let ram = mbc.dump_ram().unwrap();
+        assert_eq!((ram.len(), ram[0], ram[0x1FF]), (0x200, 0x0C, 0x03));
+
+        // The ROM bank is selected with bit 8 of the address set.
+        mbc.write_byte(0x2100, 0x05);
+        assert_eq!(mbc.read_byte(0x7FFF), 5);
+
+        mbc.write_byte(0x2100, 0x00);
+        assert_eq!(mbc.read_byte(0x7FFF), 1);
+
+        let restored = Mbc2::new(&rom(0x06, 3, 0), Some(ram));
+        assert_eq!(restored.ram[0x1FF], 0x03);
+    }
+
+    /// Latch the clock of a MBC3 cartridge, and read its registers.
+    fn read_rtc(mbc: &mut Mbc3) -> [u8; 5] {
+        let mut registers = [0; 5];
+
+        mbc.write_byte(0x6000, 0x00);
+        mbc.write_byte(0x6000, 0x01);
+
+        for (i, register) in registers.iter_mut().enumerate() {
+            mbc.write_byte(0x4000, 0x08 + i as u8);
+            *register = mbc.read_byte(0xA000);
+        }
+
+        registers
+    }
+
+    #[test]
+    fn set_rtc_updates_the_registers() {
+        let mut mbc = Mbc3::new(&rom(0x10, 2, 2), None);
+        mbc.write_byte(0x0000, 0x0A);
+
+        // 257 days, 2 hours, 3 minutes and 4 seconds.
+        let time = Duration::from_secs(257 * 86400 + 2 * 3600 + 3 * 60 + 4);
+        mbc.set_rtc(time);
+
+        assert_eq!(read_rtc(&mut mbc), [4, 3, 2, 1, 0x01]);
+
+        // Cartridges without a clock ignore it.
+        let mut mbc = Mbc3::new(&rom(0x13, 2, 2), None);
+        mbc.set_rtc(time);
+
+        assert_eq!(mbc.rtc.registers, [0; 5]);
+    }
+
+    #[test]
+    fn truncated_roms_are_padded() {
+        // 2 MB declared, 512 KB present.
+        let mut truncated = rom(0x19, 6, 0);
+        truncated.truncate(0x80000);
+
+        let mut mbc = Mbc5::new(&truncated, None);
+        assert_eq!(mbc.rom_bank_count(), 128);
+
+        mbc.write_byte(0x2000, 31);
+        assert_eq!(mbc.read_byte(0x7FFF), 31);
+
+        mbc.write_byte(0x2000, 100);
+        assert_eq!(mbc.read_byte(0x4000), 0xFF);
+        assert_eq!(mbc.read_byte(0x7FFF), 0xFF);
+    }
+
+    #[test]
+    fn rom_size_codes() {
+        assert_eq!(decode_rom_size(0x00), Some(0x8000));
+        assert_eq!(decode_rom_size(0x08), Some(0x800000));
+        assert_eq!(decode_rom_size(0x52), Some(0x120000));
+        assert_eq!(decode_rom_size(0x53), Some(0x140000));
+        assert_eq!(decode_rom_size(0x54), Some(0x180000));
+        assert_eq!(decode_rom_size(0x09), None);
+        assert_eq!(decode_rom_size(0xFF), None);
+
+        // Unknown codes fall back to the size of the ROM.
+        let mut unknown = rom(0x19, 3, 0);
+        unknown[0x0148] = 0x42;
+        assert_eq!(Mbc5::new(&unknown, None).rom_bank_count(), 16);
+    }
+
+    #[test]
+    fn battery_cartridge_types() {
+        let battery = [0x03, 0x06, 0x09, 0x0D, 0x0F, 0x10, 0x13, 0x1B, 0x1E];
+        let no_battery = [0x00, 0x01, 0x02, 0x05, 0x08, 0x11, 0x12, 0x19, 0x1A, 0x1C];
+
+        for &kind in battery.iter() {
+            assert!(is_battery_type(kind), "{:02X}", kind);
+        }
+
+        for &kind in no_battery.iter() {
+            assert!(!is_battery_type(kind), "{:02X}", kind);
+        }
+
+        // Every MBC goes by its header.
+        let carts: [(Box<dyn Cartridge>, bool); 8] = [
+            (Box::new(RomOnly::new(&rom(0x00, 0, 0))), false),
+            (Box::new(Mbc1::new(&rom(0x01, 2, 0), None)), false),
+            (Box::new(Mbc1::new(&rom(0x03, 2, 2), None)), true),
+            (Box::new(Mbc2::new(&rom(0x06, 2, 0), None)), true),
+            (Box::new(Mbc3::new(&rom(0x11, 2, 0), None)), false),
+            (Box::new(Mbc3::new(&rom(0x10, 2, 3), None)), true),
+            (Box::new(Mbc5::new(&rom(0x1A, 2, 3), None)), false),
+            (Box::new(Mbc5::new(&rom(0x1B, 2, 3), None)), true),
+        ];
+
+        for (cart, battery) in carts.iter() {
+            assert_eq!(cart.has_battery(), *battery);
+        }
+    }
+
+    #[test]
+    fn loading_the_rtc_restarts_it_from_now() {
+        let mut rtc = Rtc::new();
+        assert!(rtc.last_update.is_some());
+
+        rtc.registers = [10, 20, 3, 4, 0];
+        let mut save = rtc.save();
+
+        // Date the save in the future, so no time passes while loading it.
+        save[40..].copy_from_slice(&u64::MAX.to_le_bytes());
+
+        // A clock which hasn't been updated in a while, and is most of
+        // the way into the next second, shouldn't carry that into the load.
+        let mut loaded = Rtc::new();
+        loaded.last_update = Instant::now().checked_sub(Duration::from_secs(100));
+        loaded.subsecond = Duration::from_millis(900);
+        loaded.load(&save);
+
+        assert_eq!(loaded.subsecond, Duration::default());
+
+        loaded.update();
+        assert_eq!(loaded.registers[..4], [10, 20, 3, 4]);
+    }
+
+    #[test]
+    fn mbc5_ram_round_trips_through_a_save() {
+        let mut mbc = Mbc5::new(&rom(0x1B, 2, 3), None);
+        mbc.write_byte(0x0000, 0x0A);
+        mbc.write_byte(0x4000, 0x02);
+        mbc.write_byte(0xA123, 0x42);
+
+        let save = mbc.dump_ram().unwrap();
+        assert_eq!(save.len(), 0x8000);
+
+        let mut mbc = Mbc5::new(&rom(0x1B, 2, 3), Some(save.clone()));
+        mbc.write_byte(0x0000, 0x0A);
+        mbc.write_byte(0x4000, 0x02);
+        assert_eq!(mbc.read_byte(0xA123), 0x42);
+
+        // Cartridges without a battery start with blank RAM.
+        let mut mbc = Mbc5::new(&rom(0x1A, 2, 3), Some(save));
+        mbc.write_byte(0x0000, 0x0A);
+        mbc.write_byte(0x4000, 0x02);
+        assert_eq!(mbc.read_byte(0xA123), 0x00);
+    }
+
+    #[test]
+    fn mbc1_ram_round_trips_through_a_save() {
+        // Select RAM banking mode, so the upper bits pick the RAM bank.
+        let enable = |mbc: &mut Mbc1| {
+            mbc.write_byte(0x0000, 0x0A);
+            mbc.write_byte(0x6000, 0x01);
+            mbc.write_byte(0x4000, 0x03);
+        };
+
+        let mut mbc = Mbc1::new(&rom(0x03, 2, 3), None);
+        enable(&mut mbc);
+        mbc.write_byte(0xB000, 0x42);
+
+        let save = mbc.dump_ram().unwrap();
+        assert_eq!(save.len(), 0x8000);
+
+        let mut mbc = Mbc1::new(&rom(0x03, 2, 3), Some(save.clone()));
+        enable(&mut mbc);
+        assert_eq!(mbc.read_byte(0xB000), 0x42);
+
+        // Cartridges without a battery start with blank RAM.
+        let mut mbc = Mbc1::new(&rom(0x02, 2, 3), Some(save));
+        enable(&mut mbc);
+        assert_eq!(mbc.read_byte(0xB000), 0x00);
+    }
+
+    #[test]
+    fn mbc3_save_states() {
+        let mut mbc = Mbc3::new(&rom(0x10, 2, 3), None);
+        mbc.set_rtc(Duration::from_secs(12345));
+
+        let save = |mbc: &Mbc3| {
+            let mut writer = StateWriter::new();
+            mbc.save_state(&mut writer);
+            writer.finish()
+        };
+
+        // The clock is saved without a timestamp, so saves are repeatable.
+        let data = save(&mbc);
+        assert_eq!(save(&mbc), data);
+
+        let mut loaded = Mbc3::new(&rom(0x10, 2, 3), None);
+        assert_eq!(
+            loaded.load_state(&mut StateReader::new(&data).unwrap()),
+            Ok(())
+        );
+        assert_eq!(loaded.rtc.registers, mbc.rtc.registers);
+
+        // A RAM bank past the 4 bits of RAMB is rejected.
+        mbc.ram_bank = 0x10;
+        let data = save(&mbc);
+
+        assert_eq!(
+            loaded.load_state(&mut StateReader::new(&data).unwrap()),
+            Err(SaveStateError::Corrupted)
+        );
+    }
+
+    #[test]
+    fn mbc1_banking_mode_can_be_toggled() {
+        // 1 MB, 64 banks.
+        let mut mbc = Mbc1::new(&rom(0x01, 5, 0), None);
+
+        mbc.write_byte(0x4000, 1);
+        assert_eq!(mbc.banking_mode(), Some(false));
+        assert_eq!(mbc.read_byte(0x3FFF), 0);
+
+        mbc.set_banking_mode(true);
+        assert_eq!(mbc.banking_mode(), Some(true));
+        assert_eq!(mbc.read_byte(0x3FFF), 32);
+
+        // Writes by the game still switch the mode.
+        mbc.write_byte(0x6000, 0);
+        assert_eq!(mbc.banking_mode(), Some(false));
+        assert_eq!(mbc.read_byte(0x3FFF), 0);
+
+        // Only MBC1 has a banking mode.
+        let mut mbc = Mbc5::new(&rom(0x19, 2, 0), None);
+
+        mbc.set_banking_mode(true);
+        assert_eq!(mbc.banking_mode(), None);
+    }
 }
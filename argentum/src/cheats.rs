@@ -0,0 +1,168 @@
+//! Game Genie and GameShark cheat codes.
+
+use std::{
+    error::Error,
+    fmt::{Display, Formatter, Result},
+};
+
+/// Errors that can occur while loading a cheat file.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CheatError {
+    /// The line does not hold a valid Game Genie or GameShark code.
+    InvalidCode { line: usize },
+}
+
+impl Display for CheatError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            Self::InvalidCode { line } => write!(f, "invalid cheat code on line {}", line),
+        }
+    }
+}
+
+impl Error for CheatError {}
+
+/// What a cheat does to the running game.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum CheatEffect {
+    /// Game Genie, replaces a byte read from the ROM, optionally
+    /// only if it matches the compare value.
+    RomPatch {
+        address: u16,
+        value: u8,
+        compare: Option<u8>,
+    },
+
+    /// GameShark, writes a byte to RAM once every frame.
+    RamWrite { bank: u8, address: u16, value: u8 },
+}
+
+/// A single cheat code.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cheat {
+    /// The name given to the cheat, may be empty.
+    pub name: String,
+
+    /// The code as it was written.
+    pub code: String,
+
+    /// Whether the cheat is applied.
+    pub enabled: bool,
+
+    pub(crate) effect: CheatEffect,
+}
+
+impl Cheat {
+    /// Parse a Game Genie (`ABC-DEF` or `ABC-DEF-GHI`) or
+    /// GameShark (`TTVVAAAA`) code.
+    pub fn new(code: &str, name: &str) -> Option<Self> {
+        let digits = code
+            .chars()
+            .filter(|&c| c != '-')
+            .map(|c| c.to_digit(16).map(|d| d as u8))
+            .collect::<Option<Vec<u8>>>()?;
+
+        let byte = |i: usize| (digits[i] << 4) | digits[i + 1];
+
+        let effect = match digits.len() {
+            8 if !code.contains('-') => CheatEffect::RamWrite {
+                bank: byte(0),
+                value: byte(2),
+                address: u16::from_le_bytes([byte(4), byte(6)]),
+            },
+
+            6 | 9 => {
+                // The upper nibble of the address is stored inverted.
+                let address = (((digits[5] ^ 0xF) as u16) << 12)
+                    | ((digits[2] as u16) << 8)
+                    | ((digits[3] as u16) << 4)
+                    | digits[4] as u16;
+
+                // The compare value is scrambled, and the middle digit
+                // of the last group is not used.
+                let compare = if digits.len() == 9 {
+                    Some(((digits[6] << 4) | digits[8]).rotate_right(2) ^ 0xBA)
+                } else {
+                    None
+                };
+
+                if address >= 0x8000 {
+                    return None;
+                }
+
+                CheatEffect::RomPatch {
+                    address,
+                    value: byte(0),
+                    compare,
+                }
+            }
+
+            _ => return None,
+        };
+
+        Some(Self {
+            name: name.to_string(),
+            code: code.to_string(),
+            enabled: true,
+            effect,
+        })
+    }
+}
+
+/// Parse a cheat file, which holds one code per line. The code can
+/// be followed by `on` or `off` and then by the name of the cheat.
+/// Blank lines and lines starting with `#` are skipped.
+pub(crate) fn parse_cheat_file(contents: &str) -> std::result::Result<Vec<Cheat>, CheatError> {
+    let mut cheats = Vec::new();
+
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (code, rest) = split_word(line);
+        let (flag, name) = split_word(rest);
+
+        let (enabled, name) = match flag.to_ascii_lowercase().as_str() {
+            "on" => (true, name),
+            "off" => (false, name),
+            _ => (true, rest),
+        };
+
+        let mut cheat =
+            Cheat::new(code, name).ok_or(CheatError::InvalidCode { line: index + 1 })?;
+        cheat.enabled = enabled;
+
+        cheats.push(cheat);
+    }
+
+    Ok(cheats)
+}
+
+/// Split off the first whitespace separated word of the string.
+fn split_word(s: &str) -> (&str, &str) {
+    match s.find(char::is_whitespace) {
+        Some(index) => (&s[..index], s[index..].trim_start()),
+        None => (s, ""),
+    }
+}
+
+/// Apply the enabled Game Genie cheats to a byte read from the ROM.
+pub(crate) fn patch_rom(cheats: &[Cheat], addr: u16, value: u8) -> u8 {
+    for cheat in cheats.iter().filter(|cheat| cheat.enabled) {
+        if let CheatEffect::RomPatch {
+            address,
+            value: patched,
+            compare,
+        } = cheat.effect
+        {
+            if address == addr && compare.unwrap_or(value) == value {
+                return patched;
+            }
+        }
+    }
+
+    value
+}
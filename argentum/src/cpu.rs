@@ -6,9 +6,12 @@ mod registers;
 
 use std::fmt::{Display, Formatter, Result};
 
+pub use self::decode::instruction_length;
+
 use self::registers::Registers;
 use crate::{
     bus::Bus,
+    savestate::{ensure, SaveStateError, StateReader, StateWriter},
     util::{bit, res},
 };
 
@@ -17,6 +20,10 @@ use crate::{
 pub enum CpuState {
     Halted,
     Running,
+
+    /// Locked up after executing an illegal opcode,
+    /// only a reset gets the CPU out of this state.
+    Locked,
 }
 
 /// Implementation of the Sharp SM83 CPU.
@@ -72,6 +79,50 @@ impl Cpu {
         }
     }
 
+    /// Serialize the registers and the state of the CPU.
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.u16(self.reg.get_af());
+        state.u16(self.reg.get_bc());
+        state.u16(self.reg.get_de());
+        state.u16(self.reg.get_hl());
+        state.u16(self.reg.sp);
+        state.u16(self.reg.pc);
+        state.bool(self.ime);
+        state.bool(self.is_double_speed);
+
+        state.u8(match self.state {
+            CpuState::Running => 0,
+            CpuState::Halted => 1,
+            CpuState::Locked => 2,
+        });
+    }
+
+    /// Restore the registers and the state of the CPU.
+    pub fn load_state(
+        &mut self,
+        state: &mut StateReader,
+    ) -> std::result::Result<(), SaveStateError> {
+        self.reg.set_af(state.u16()?);
+        self.reg.set_bc(state.u16()?);
+        self.reg.set_de(state.u16()?);
+        self.reg.set_hl(state.u16()?);
+        self.reg.sp = state.u16()?;
+        self.reg.pc = state.u16()?;
+        self.ime = state.bool()?;
+        self.is_double_speed = state.bool()?;
+
+        let cpu_state = state.u8()?;
+        ensure(cpu_state <= 2)?;
+
+        self.state = match cpu_state {
+            0 => CpuState::Running,
+            1 => CpuState::Halted,
+            _ => CpuState::Locked,
+        };
+
+        Ok(())
+    }
+
     pub fn read_byte(&mut self, bus: &mut Bus, addr: u16) -> u8 {
         self.cycles += 4;
         bus.read_byte(addr, true)
@@ -230,9 +281,17 @@ impl Cpu {
 
     /// Handle all pending interrupts.
     /// Only one interrupt is serviced at one time.
+    ///
+    /// Dispatching an interrupt takes 5 M-cycles (20 T-cycles), two wait
+    /// states, two writes to push PC and one to jump to the vector.
     pub fn handle_interrupts(&mut self, bus: &mut Bus) {
         let interrupts = bus.ie_reg & *bus.if_reg.borrow();
 
+        // A locked up CPU doesn't respond to interrupts.
+        if self.state == CpuState::Locked {
+            return;
+        }
+
         // If there are pending interrupts, CPU should be
         // back up and running.
         if interrupts != 0 {
@@ -244,6 +303,9 @@ impl Cpu {
             return;
         }
 
+        // When several interrupts are pending, the lowest bit has the
+        // highest priority, so VBlank always goes first. Only the serviced
+        // bit is cleared in IF, the others stay pending till IME is back on.
         if interrupts != 0 {
             for i in 0..5 {
                 if bit!(&bus.ie_reg, i) && bit!(bus.if_reg.borrow(), i) {
@@ -286,11 +348,13 @@ impl Cpu {
     pub fn execute_next(&mut self, bus: &mut Bus) -> u32 {
         self.cycles = 0;
 
-        // Handle pending interrupts.
+        // Handle pending interrupts. This happens before the fetch, so a
+        // CPU woken from HALT with IME set jumps to the handler first, and
+        // with IME clear it just resumes at the instruction after HALT.
         self.handle_interrupts(bus);
 
-        // If the CPU is in HALT state, it just burns one M cycle.
-        if self.state == CpuState::Halted {
+        // If the CPU is halted or locked up, it just burns one M cycle.
+        if self.state != CpuState::Running {
             self.internal_cycle(bus);
         } else {
             // Fetch the opcode.
@@ -303,3 +367,110 @@ impl Cpu {
         self.cycles >> (self.is_double_speed as u8)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::rom_with_program;
+
+    #[test]
+    fn interrupt_dispatch_takes_20_cycles() {
+        let mut bus = Bus::new(&rom_with_program(&[]), Box::new(|_| {}), None);
+        bus.skip_bootrom();
+
+        let mut cpu = Cpu::new();
+        cpu.skip_bootrom(false);
+        cpu.reg.pc = 0x1234;
+        cpu.reg.sp = 0xFFFE;
+        cpu.ime = true;
+
+        // Request a timer interrupt.
+        bus.ie_reg = 0x04;
+        *bus.if_reg.borrow_mut() = 0x04;
+
+        cpu.handle_interrupts(&mut bus);
+
+        assert_eq!(cpu.cycles, 20);
+        assert_eq!(cpu.reg.pc, 0x0050);
+        assert_eq!(cpu.reg.sp, 0xFFFC);
+        assert_eq!(bus.read_byte(0xFFFC, false), 0x34);
+        assert_eq!(bus.read_byte(0xFFFD, false), 0x12);
+        assert!(!cpu.ime);
+        assert_eq!(*bus.if_reg.borrow(), 0x00);
+    }
+
+    #[test]
+    fn lowest_pending_interrupt_goes_first() {
+        let mut bus = Bus::new(&rom_with_program(&[]), Box::new(|_| {}), None);
+        bus.skip_bootrom();
+
+        let mut cpu = Cpu::new();
+        cpu.skip_bootrom(false);
+        cpu.reg.sp = 0xFFFE;
+        cpu.ime = true;
+
+        // VBlank and timer are enabled, serial is pending but not enabled.
+        bus.ie_reg = 0x05;
+        *bus.if_reg.borrow_mut() = 0x0D;
+
+        cpu.handle_interrupts(&mut bus);
+
+        assert_eq!(cpu.reg.pc, 0x0040);
+        assert_eq!(*bus.if_reg.borrow(), 0x0C);
+
+        // The timer is only serviced once IME is back on.
+        cpu.handle_interrupts(&mut bus);
+        assert_eq!(cpu.reg.pc, 0x0040);
+
+        cpu.ime = true;
+        cpu.handle_interrupts(&mut bus);
+
+        assert_eq!(cpu.reg.pc, 0x0050);
+        assert_eq!(*bus.if_reg.borrow(), 0x08);
+    }
+
+    #[test]
+    fn halt_exits_on_an_interrupt_for_both_ime_states() {
+        for &ime in [true, false].iter() {
+            // HALT; INC B; JR -3
+            let rom = rom_with_program(&[0x76, 0x04, 0x18, 0xFD]);
+            let mut bus = Bus::new(&rom, Box::new(|_| {}), None);
+            bus.skip_bootrom();
+
+            let mut cpu = Cpu::new();
+            cpu.skip_bootrom(false);
+            cpu.reg.sp = 0xFFFE;
+            cpu.reg.b = 0;
+            cpu.ime = ime;
+            bus.ie_reg = 0x04;
+
+            for _ in 0..10 {
+                cpu.execute_next(&mut bus);
+            }
+
+            assert!(cpu.state == CpuState::Halted);
+            assert_eq!(cpu.reg.pc, 0x0101);
+
+            // Request a timer interrupt.
+            *bus.if_reg.borrow_mut() = 0x04;
+            cpu.execute_next(&mut bus);
+
+            assert!(cpu.state == CpuState::Running);
+
+            if ime {
+                // The handler runs before the instruction after HALT,
+                // so the NOP at the vector has been executed instead.
+                assert_eq!(cpu.reg.pc, 0x0051);
+                assert_eq!(cpu.reg.b, 0);
+                assert_eq!(bus.read_byte(0xFFFC, false), 0x01);
+                assert_eq!(bus.read_byte(0xFFFD, false), 0x01);
+                assert_eq!(*bus.if_reg.borrow() & 0x04, 0x00);
+            } else {
+                // Execution resumes after HALT, and the interrupt stays pending.
+                assert_eq!(cpu.reg.pc, 0x0102);
+                assert_eq!(cpu.reg.b, 1);
+                assert_eq!(*bus.if_reg.borrow() & 0x04, 0x04);
+            }
+        }
+    }
+}
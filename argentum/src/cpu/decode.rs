@@ -3,6 +3,38 @@
 use super::{Cpu, CpuState};
 use crate::bus::Bus;
 
+/// The length in bytes of every unprefixed opcode, including
+/// the opcode itself. Illegal opcodes are taken to be one byte.
+#[rustfmt::skip]
+static INSTRUCTION_LENGTHS: [u8; 256] = [
+    1, 3, 1, 1, 1, 1, 2, 1, 3, 1, 1, 1, 1, 1, 2, 1, // 0x00
+    2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1, // 0x10
+    2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1, // 0x20
+    2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1, // 0x30
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0x40
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0x50
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0x60
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0x70
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0x80
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0x90
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0xA0
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0xB0
+    1, 1, 3, 3, 3, 1, 2, 1, 1, 1, 3, 2, 3, 3, 2, 1, // 0xC0
+    1, 1, 3, 1, 3, 1, 2, 1, 1, 1, 3, 1, 3, 1, 2, 1, // 0xD0
+    2, 1, 1, 1, 1, 1, 2, 1, 2, 1, 3, 1, 1, 1, 2, 1, // 0xE0
+    2, 1, 1, 1, 1, 1, 2, 1, 2, 1, 3, 1, 1, 1, 2, 1, // 0xF0
+];
+
+/// Get the length in bytes of an instruction without executing it.
+/// CB prefixed instructions are always two bytes long.
+pub fn instruction_length(opcode: u8, cb: bool) -> u8 {
+    if cb {
+        2
+    } else {
+        INSTRUCTION_LENGTHS[opcode as usize]
+    }
+}
+
 impl Cpu {
     /// Decode the provided opcode and execute it.
     pub fn decode_and_execute(&mut self, bus: &mut Bus, opcode: u8) {
@@ -155,6 +187,8 @@ impl Cpu {
                 self.conditional_jp(bus, condition);
             }
 
+            // LD (FF00 + C), A and LD A, (FF00 + C) take 8 T-cycles,
+            // the opcode fetch and a single memory access.
             0xE2 => {
                 let address = (0xFF00u16).wrapping_add(self.reg.c as u16);
 
@@ -284,7 +318,51 @@ impl Cpu {
                 self.reg.pc = vec;
             }
 
-            _ => {}
+            // Illegal opcodes, these lock up the hardware.
+            _ => {
+                let pc = self.reg.pc.wrapping_sub(1);
+
+                if let Some(callback) = &bus.illegal_opcode_callback {
+                    callback(pc, opcode);
+                }
+
+                if bus.lock_on_illegal_opcode {
+                    self.state = CpuState::Locked;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn instruction_lengths() {
+        // (opcode, length): NOP, LD BC,u16, STOP, JR i8, LD (u16),SP,
+        // LD B,C, JP u16, CB prefix, LDH (u8),A, LD (C),A, LD A,(u16), illegal.
+        let cases = [
+            (0x00, 1),
+            (0x01, 3),
+            (0x10, 2),
+            (0x18, 2),
+            (0x08, 3),
+            (0x41, 1),
+            (0xC3, 3),
+            (0xCB, 2),
+            (0xE0, 2),
+            (0xE2, 1),
+            (0xFA, 3),
+            (0xD3, 1),
+        ];
+
+        for &(opcode, length) in cases.iter() {
+            assert_eq!(instruction_length(opcode, false), length, "{:02X}", opcode);
+        }
+
+        for opcode in 0..=0xFF {
+            assert_eq!(instruction_length(opcode, true), 2);
         }
     }
 }
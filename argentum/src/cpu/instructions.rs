@@ -48,7 +48,8 @@ impl Cpu {
             }
         }
 
-        self.reg.pc += 1;
+        // STOP is a two byte instruction, the operand is skipped.
+        self.reg.pc = self.reg.pc.wrapping_add(1);
     }
 
     /// JR (unconditional).
@@ -82,9 +83,11 @@ impl Cpu {
         let hl = self.reg.get_hl();
         let value = self.read_r16::<1>(r16);
 
+        // 8 T-cycles in total, the opcode fetch and one internal cycle.
         self.reg.set_hl(hl.wrapping_add(value));
         self.internal_cycle(bus);
 
+        // The Z flag is left untouched.
         self.reg.set_flag(Flags::N, false);
         self.reg
             .set_flag(Flags::H, (hl & 0xFFF) + (value & 0xFFF) > 0xFFF);
@@ -320,6 +323,10 @@ impl Cpu {
 
         self.reg.set_flag(Flags::Z, result == 0);
         self.reg.set_flag(Flags::N, true);
+
+        // The sums can't overflow a u8, so a borrow out of bit 4 happens
+        // exactly when the lower nibble of A is smaller than the lower
+        // nibble of the operand plus the carry, even if they sum to 0x10.
         self.reg
             .set_flag(Flags::H, (a & 0xF) < ((value & 0xF) + (f & 0xF)));
         self.reg
@@ -680,3 +687,59 @@ impl Cpu {
         self.write_byte(bus, self.reg.sp, lower);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::rom_with_program;
+
+    #[test]
+    fn sbc_matches_a_reference_implementation() {
+        let mut cpu = Cpu::new();
+
+        for a in 0..=0xFF {
+            for value in 0..=0xFF {
+                for &carry in [false, true].iter() {
+                    cpu.reg.a = a;
+                    cpu.reg.set_flag(Flags::C, carry);
+                    cpu.sbc_r8(value);
+
+                    // Subtract with signed integers, any negative
+                    // result means a borrow was needed.
+                    let borrow = carry as i32;
+                    let result = a as i32 - value as i32 - borrow;
+                    let half = (a & 0xF) as i32 - (value & 0xF) as i32 - borrow;
+
+                    let case = (a, value, carry);
+                    assert_eq!(cpu.reg.a, result as u8, "{:?}", case);
+                    assert_eq!(cpu.reg.get_flag(Flags::Z), result as u8 == 0, "{:?}", case);
+                    assert!(cpu.reg.get_flag(Flags::N), "{:?}", case);
+                    assert_eq!(cpu.reg.get_flag(Flags::H), half < 0, "{:?}", case);
+                    assert_eq!(cpu.reg.get_flag(Flags::C), result < 0, "{:?}", case);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn ff00_plus_c_loads() {
+        // LD (FF00+C), A; LD A, (FF00+C)
+        let mut bus = Bus::new(&rom_with_program(&[0xE2, 0xF2]), Box::new(|_| {}), None);
+        bus.skip_bootrom();
+
+        let mut cpu = Cpu::new();
+        cpu.skip_bootrom(false);
+
+        // Go through TIMA, which stays put with the timer stopped.
+        cpu.reg.c = 0x05;
+        cpu.reg.a = 0x5A;
+
+        assert_eq!(cpu.execute_next(&mut bus), 8);
+        assert_eq!(bus.read_byte(0xFF05, false), 0x5A);
+
+        cpu.reg.a = 0x00;
+
+        assert_eq!(cpu.execute_next(&mut bus), 8);
+        assert_eq!(cpu.reg.a, 0x5A);
+    }
+}
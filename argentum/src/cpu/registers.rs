@@ -71,6 +71,9 @@ impl Registers {
     #[inline]
     pub fn set_af(&mut self, value: u16) {
         self.a = (value >> 8) as u8;
+
+        // The lower nibble of F is not backed by any flag, truncating
+        // to the known flags makes it always read back as 0.
         self.f = Flags::from_bits_truncate(value as u8);
     }
 
@@ -102,3 +105,22 @@ impl Registers {
         self.f.set(flag, value);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lower_nibble_of_f_reads_as_zero() {
+        let mut reg = Registers::new();
+
+        reg.set_af(0x12F0);
+        assert_eq!(reg.get_af(), 0x12F0);
+
+        reg.set_af(0x12FF);
+        assert_eq!(reg.get_af(), 0x12F0);
+
+        reg.set_af(0x340F);
+        assert_eq!(reg.get_af(), 0x3400);
+    }
+}
@@ -1,6 +1,13 @@
 use std::{cell::RefCell, rc::Rc};
 
-use crate::util::set;
+use crate::{
+    savestate::{SaveStateError, StateReader, StateWriter},
+    util::set,
+};
+
+/// T-cycles between a key press and the joypad interrupt being
+/// requested, when input latency is emulated.
+const INTERRUPT_LATENCY: u8 = 16;
 
 #[repr(u8)]
 pub enum ArgentumKey {
@@ -14,6 +21,19 @@ pub enum ArgentumKey {
     Start = 0x80,
 }
 
+/// How simultaneous presses of opposing directions are resolved.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SocdMode {
+    /// Both directions are seen as pressed, like the hardware.
+    Allow,
+
+    /// Opposing directions cancel each other out.
+    Neutral,
+
+    /// Only the direction pressed last is seen as pressed.
+    LastWins,
+}
+
 pub struct Joypad {
     /// Contains the current state of the buttons and DPAD.
     joypad_state: u8,
@@ -24,6 +44,18 @@ pub struct Joypad {
     /// Indicates if the button control bit selected.
     buttons: bool,
 
+    /// Whether the joypad interrupt is delayed like on hardware.
+    pub emulate_latency: bool,
+
+    /// The T-cycles remaining for the joypad interrupt to be requested, if any.
+    interrupt_delay: Option<u8>,
+
+    /// How opposing directions held together are resolved.
+    pub socd_mode: SocdMode,
+
+    /// The horizontal and vertical directions pressed last.
+    last_directions: u8,
+
     /// Shared reference to IF register.
     if_reg: Rc<RefCell<u8>>,
 }
@@ -35,14 +67,63 @@ impl Joypad {
             joypad_state: 0x00,
             dpad: false,
             buttons: false,
+            emulate_latency: false,
+            interrupt_delay: None,
+            socd_mode: SocdMode::Allow,
+            last_directions: 0x00,
             if_reg,
         }
     }
 
+    /// Serialize the selected lines and the pending interrupt.
+    /// The keys held are left out, they belong to the host.
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.dpad);
+        state.bool(self.buttons);
+        state.bool(self.interrupt_delay.is_some());
+        state.u8(self.interrupt_delay.unwrap_or(0));
+    }
+
+    /// Restore the selected lines and the pending interrupt.
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), SaveStateError> {
+        self.dpad = state.bool()?;
+        self.buttons = state.bool()?;
+
+        let pending = state.bool()?;
+        let delay = state.u8()?;
+        self.interrupt_delay = if pending { Some(delay) } else { None };
+
+        Ok(())
+    }
+
     /// Register a key being pressed.
     pub fn key_down(&mut self, key: ArgentumKey) {
-        self.joypad_state |= key as u8;
-        set!(self.if_reg.borrow_mut(), 4);
+        let key = key as u8;
+
+        self.joypad_state |= key;
+        self.record_directions(key);
+        self.request_interrupt();
+    }
+
+    /// Request the joypad interrupt, after a delay if latency is emulated.
+    fn request_interrupt(&mut self) {
+        if self.emulate_latency {
+            self.interrupt_delay = Some(INTERRUPT_LATENCY);
+        } else {
+            set!(self.if_reg.borrow_mut(), 4);
+        }
+    }
+
+    /// Tick the pending joypad interrupt by 4 T-cycles.
+    pub fn tick(&mut self) {
+        if let Some(ref mut cycles) = self.interrupt_delay {
+            *cycles = cycles.saturating_sub(4);
+
+            if *cycles == 0 {
+                self.interrupt_delay = None;
+                set!(self.if_reg.borrow_mut(), 4);
+            }
+        }
     }
 
     /// Register a key being unpressed.
@@ -50,19 +131,73 @@ impl Joypad {
         self.joypad_state &= !(key as u8);
     }
 
+    /// Replace the state of all the keys at once.
+    /// Each bit corresponds to an `ArgentumKey`.
+    pub fn set_state(&mut self, state: u8) {
+        let pressed = state & !self.joypad_state;
+
+        self.joypad_state = state;
+        self.record_directions(pressed);
+
+        if pressed != 0 {
+            self.request_interrupt();
+        }
+    }
+
+    /// Remember the newly pressed directions, for each axis where
+    /// exactly one direction was pressed.
+    fn record_directions(&mut self, pressed: u8) {
+        for &axis in &[0x03, 0x0C] {
+            let directions = pressed & axis;
+
+            if directions != 0 && directions != axis {
+                self.last_directions = (self.last_directions & !axis) | directions;
+            }
+        }
+    }
+
+    /// Get the state of the keys, after resolving opposing directions.
+    fn resolved_state(&self) -> u8 {
+        let mut state = self.joypad_state;
+
+        for &axis in &[0x03, 0x0C] {
+            if state & axis != axis {
+                continue;
+            }
+
+            match self.socd_mode {
+                SocdMode::Allow => {}
+                SocdMode::Neutral => state &= !axis,
+                SocdMode::LastWins => {
+                    state = (state & !axis) | (self.last_directions & axis);
+                }
+            }
+        }
+
+        state
+    }
+
     /// Read a byte from the specified address.
     pub fn read_byte(&self, _: u16) -> u8 {
+        // With neither line selected the lower nibble reads as all 1s,
+        // regardless of the keys being held. The select bits themselves
+        // read back as 1, and so do the unused upper bits.
+        if !self.dpad && !self.buttons {
+            return 0xFF;
+        }
+
+        let state = self.resolved_state();
         let mut joyp = 0x00;
 
         joyp |= (self.dpad as u8) << 4;
         joyp |= (self.buttons as u8) << 5;
 
         if self.dpad {
-            joyp |= (self.joypad_state & 0x0F) >> 0;
+            joyp |= (state & 0x0F) >> 0;
         }
 
         if self.buttons {
-            joyp |= (self.joypad_state & 0xF0) >> 4;
+            joyp |= (state & 0xF0) >> 4;
         }
 
         !joyp
@@ -74,3 +209,78 @@ impl Joypad {
         self.buttons = (value & 0x20) == 0;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn joypad_interrupt_is_delayed() {
+        let if_reg = Rc::new(RefCell::new(0));
+        let mut joypad = Joypad::new(if_reg.clone());
+
+        joypad.emulate_latency = true;
+        joypad.key_down(ArgentumKey::ButtonA);
+        assert_eq!(*if_reg.borrow() & 0x10, 0);
+
+        for _ in 0..(INTERRUPT_LATENCY / 4 - 1) {
+            joypad.tick();
+            assert_eq!(*if_reg.borrow() & 0x10, 0);
+        }
+
+        joypad.tick();
+        assert_eq!(*if_reg.borrow() & 0x10, 0x10);
+    }
+
+    #[test]
+    fn deselected_read_ignores_held_keys() {
+        let mut joypad = Joypad::new(Rc::new(RefCell::new(0)));
+
+        joypad.key_down(ArgentumKey::ButtonA);
+        joypad.key_down(ArgentumKey::Down);
+        joypad.write_byte(0xFF00, 0x30);
+
+        assert_eq!(joypad.read_byte(0xFF00), 0xFF);
+        assert_eq!(joypad.read_byte(0xFF00) & 0x0F, 0x0F);
+    }
+
+    #[test]
+    fn set_state_interrupt_is_delayed() {
+        let if_reg = Rc::new(RefCell::new(0));
+        let mut joypad = Joypad::new(if_reg.clone());
+
+        joypad.emulate_latency = true;
+        joypad.set_state(ArgentumKey::Start as u8);
+        assert_eq!(*if_reg.borrow() & 0x10, 0);
+
+        for _ in 0..(INTERRUPT_LATENCY / 4) {
+            joypad.tick();
+        }
+
+        assert_eq!(*if_reg.borrow() & 0x10, 0x10);
+    }
+
+    #[test]
+    fn socd_modes_resolve_left_and_right() {
+        let cases = [
+            (SocdMode::Allow, 0xEC),
+            (SocdMode::Neutral, 0xEF),
+            (SocdMode::LastWins, 0xED),
+        ];
+
+        for &(mode, expected) in cases.iter() {
+            let mut joypad = Joypad::new(Rc::new(RefCell::new(0)));
+
+            joypad.socd_mode = mode;
+            joypad.key_down(ArgentumKey::Right);
+            joypad.key_down(ArgentumKey::Left);
+            joypad.write_byte(0xFF00, 0x20);
+
+            assert_eq!(joypad.read_byte(0xFF00), expected, "{:?}", mode);
+
+            // Releasing one direction leaves the other pressed.
+            joypad.key_up(ArgentumKey::Left);
+            assert_eq!(joypad.read_byte(0xFF00), 0xEE, "{:?}", mode);
+        }
+    }
+}
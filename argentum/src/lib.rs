@@ -2,10 +2,24 @@ mod argentum;
 mod audio;
 mod bus;
 mod cartridge;
+mod cheats;
 mod cpu;
 mod joypad;
 mod ppu;
+mod rewind;
+mod savestate;
+mod serial;
 mod timer;
 mod util;
 
-pub use {argentum::Argentum, joypad::ArgentumKey};
+#[cfg(test)]
+mod test_util;
+
+pub use {
+    argentum::{Argentum, AvCallback, FrameOutput, FrameResult, PowerOnPattern, TurboAudio},
+    audio::{AudioCallback, AudioSync},
+    cheats::{Cheat, CheatError},
+    joypad::{ArgentumKey, SocdMode},
+    ppu::{FrameTiming, PixelOrder, TileInfo, VBlankCallback},
+    savestate::{FullStateImage, SaveStateError},
+};
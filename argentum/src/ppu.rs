@@ -1,14 +1,20 @@
 use std::{cell::RefCell, rc::Rc};
 
-use crate::util::{bit, res, set};
+use crate::{
+    savestate::{ensure, SaveStateError, StateReader, StateWriter},
+    util::{bit, res, set},
+};
 
-/// The colour palette used in DMG mode.
+/// The default colour palette used in DMG mode.
 /// 0 - White
 /// 1 - Light Gray
 /// 2 - Dark Gray
 /// 3 - Black
 static DMG_MODE_PALETTE: [u32; 4] = [0xFF18D0FE, 0xFF0056D3, 0xFF10125E, 0xFF05040D];
 
+/// Called with the front buffer whenever a frame is completed.
+pub type VBlankCallback = Box<dyn FnMut(&[u8])>;
+
 /// Represents sprite data as stored in OAM.
 #[derive(Clone, Copy)]
 #[repr(C)]
@@ -37,6 +43,41 @@ impl From<[u8; 4]> for Sprite {
     }
 }
 
+/// The order of the colour bytes of a pixel in the framebuffer.
+/// The alpha byte always comes last.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PixelOrder {
+    /// Red, green, blue.
+    Rgb,
+
+    /// Blue, green, red.
+    Bgr,
+}
+
+/// Information about a tile in one of the tile maps.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TileInfo {
+    /// The tile number stored in the tile map.
+    pub index: u8,
+
+    /// The BG map attributes (CGB Mode Only).
+    pub attributes: u8,
+
+    /// The address of the tile data, resolved using the
+    /// addressing mode selected in LCDC. The attributes tell
+    /// which VRAM bank it is in.
+    pub address: u16,
+}
+
+/// The T-cycles the PPU spent in each mode during a frame.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FrameTiming {
+    pub oam_search: u32,
+    pub drawing: u32,
+    pub hblank: u32,
+    pub vblank: u32,
+}
+
 /// Enumerates all the different modes the PPU can be in.
 #[derive(Clone, Copy)]
 #[repr(u8)]
@@ -99,6 +140,16 @@ pub(crate) struct Ppu {
     /// 0xFF49 - Sprite Palette 1 (DMG Mode Only).
     obp1: u8,
 
+    /// BGP, OBP0 and OBP1 as they were when the current line started drawing.
+    line_start_palettes: [u8; 3],
+
+    /// Writes to BGP, OBP0 and OBP1 while the current line was drawn,
+    /// as the pixel they landed on, the palette index and the value.
+    palette_writes: Vec<(u8, usize, u8)>,
+
+    /// BGP, OBP0 and OBP1 in effect for each pixel of the current line.
+    line_palettes: [[u8; 3]; 160],
+
     /// 0xFF4A - Window Y coordinate.
     wy: u8,
 
@@ -108,10 +159,37 @@ pub(crate) struct Ppu {
     /// Internal GB window line counter.
     window_line_counter: u8,
 
+    /// Latched once WY = LY during a frame, the window can only be
+    /// drawn from that line onwards till the end of the frame.
+    wy_triggered: bool,
+
     /// Indicates whether we should emulate DMG or
     /// CGB behaviour.
     cgb_mode: bool,
 
+    /// The colours of the four DMG shades, lightest first.
+    dmg_palette: [u32; 4],
+
+    /// Limit the number of sprites on a line to 10, like the hardware.
+    sprite_limit: bool,
+
+    /// The number of sprites selected on the last rendered line.
+    line_sprite_count: u8,
+
+    /// How much every other row of the output is darkened,
+    /// from 0.0 (off) to 1.0 (black).
+    scanline_overlay: f32,
+
+    /// The order of the colour bytes of each pixel in the framebuffer.
+    pixel_order: PixelOrder,
+
+    /// Whether the background, window and sprite layers are drawn.
+    /// Hidden layers are left blank, to help debug compositing.
+    layer_mask: (bool, bool, bool),
+
+    /// Blur the output with a 3x3 tent filter.
+    blur_filter: bool,
+
     /// 0xFF68 - BCPS.
     ///
     /// Specifies the index of the byte curently
@@ -149,12 +227,38 @@ pub(crate) struct Ppu {
     /// Total cycles ticked under the current mode.
     total_cycles: u32,
 
+    /// Cycles spent in each mode during the current frame.
+    frame_timing: FrameTiming,
+
+    /// Cycles spent in each mode during the last complete frame.
+    pub last_frame_timing: FrameTiming,
+
     /// RGB24 framebuffer, this is the back buffer.
     back_framebuffer: Box<[u8; 160 * 144 * 4]>,
 
     /// RGB24 framebuffer, this is the front buffer.
     pub front_framebuffer: Box<[u8; 160 * 144 * 4]>,
 
+    /// Emulate LY reading 0 early on in line 153.
+    pub line_153_quirk: bool,
+
+    /// Whether LY has already wrapped to 0 during line 153.
+    ly_wrapped: bool,
+
+    /// The STAT interrupt line, the OR of all enabled STAT sources.
+    /// The interrupt is only requested when this goes high.
+    stat_line: bool,
+
+    /// Set whenever a line finishes drawing.
+    pub line_drawn: bool,
+
+    /// Whether lines are rendered to the framebuffer. Timing and
+    /// interrupts are unaffected when this is disabled.
+    pub rendering_enabled: bool,
+
+    /// Called with the front buffer every time a frame is completed.
+    pub vblank_callback: Option<VBlankCallback>,
+
     /// Shared reference to IF register.
     if_reg: Rc<RefCell<u8>>,
 }
@@ -174,10 +278,21 @@ impl Ppu {
             bgp: 0xFC,
             obp0: 0xFF,
             obp1: 0xFF,
+            line_start_palettes: [0; 3],
+            palette_writes: Vec::new(),
+            line_palettes: [[0; 3]; 160],
             wx: 0,
             wy: 0,
             window_line_counter: 0,
+            wy_triggered: false,
             cgb_mode,
+            dmg_palette: DMG_MODE_PALETTE,
+            sprite_limit: true,
+            line_sprite_count: 0,
+            scanline_overlay: 0.0,
+            pixel_order: PixelOrder::Rgb,
+            layer_mask: (true, true, true),
+            blur_filter: false,
             bcps: 0,
             bgd_palettes: [0; 0x40],
             ocps: 0,
@@ -186,8 +301,16 @@ impl Ppu {
             vram_banked: false,
             current_mode: PpuMode::OamSearch,
             total_cycles: 0,
+            frame_timing: FrameTiming::default(),
+            last_frame_timing: FrameTiming::default(),
             back_framebuffer: Box::new([0; 160 * 144 * 4]),
             front_framebuffer: Box::new([0; 160 * 144 * 4]),
+            line_153_quirk: false,
+            ly_wrapped: false,
+            stat_line: false,
+            line_drawn: false,
+            rendering_enabled: true,
+            vblank_callback: None,
             if_reg,
         }
     }
@@ -208,9 +331,13 @@ impl Ppu {
             0xFE00..=0xFE9F => self.oam_ram[(addr - 0xFE00) as usize],
 
             0xFF40 => self.lcdc,
+
+            // The mode bits read as zero while the LCD is off.
+            0xFF41 if !bit!(&self.lcdc, 7) => self.stat | 0x80,
             0xFF41 => (self.stat | 0x80) | self.current_mode as u8,
             0xFF42 => self.scy,
             0xFF43 => self.scx,
+            0xFF44 if !bit!(&self.lcdc, 7) => 0x00,
             0xFF44 => self.ly,
             0xFF45 => self.lyc,
             0xFF47 => self.bgp,
@@ -244,15 +371,39 @@ impl Ppu {
 
             0xFE00..=0xFE9F => self.oam_ram[(addr - 0xFE00) as usize] = value,
 
-            0xFF40 => self.lcdc = value,
-            0xFF41 => self.stat = value & 0x78,
+            0xFF40 => {
+                let was_enabled = bit!(&self.lcdc, 7);
+
+                self.lcdc = value;
+
+                if was_enabled && !bit!(&self.lcdc, 7) {
+                    self.disable_lcd();
+                } else if !was_enabled && bit!(&self.lcdc, 7) {
+                    self.enable_lcd();
+                }
+            }
+            0xFF41 => {
+                self.stat = (self.stat & 0x04) | (value & 0x78);
+                self.update_stat_line();
+            }
             0xFF42 => self.scy = value,
             0xFF43 => self.scx = value,
             0xFF44 => {}
             0xFF45 => self.lyc = value,
-            0xFF47 => self.bgp = value,
-            0xFF48 => self.obp0 = value,
-            0xFF49 => self.obp1 = value,
+            0xFF47 => {
+                self.record_palette_write(0, value);
+                self.bgp = value;
+            }
+
+            0xFF48 => {
+                self.record_palette_write(1, value);
+                self.obp0 = value;
+            }
+
+            0xFF49 => {
+                self.record_palette_write(2, value);
+                self.obp1 = value;
+            }
             0xFF4A => self.wy = value,
             0xFF4B => self.wx = value,
 
@@ -284,6 +435,450 @@ impl Ppu {
         }
     }
 
+    /// Fill VRAM and OAM with bytes from the given generator.
+    pub fn fill_memory(&mut self, fill: &mut dyn FnMut() -> u8) {
+        self.vram.iter_mut().for_each(|byte| *byte = fill());
+        self.oam_ram.iter_mut().for_each(|byte| *byte = fill());
+    }
+
+    /// Serialize the memories, registers and the frames of the PPU.
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.vram);
+        state.bytes(&self.oam_ram);
+
+        for &reg in &[
+            self.lcdc,
+            self.stat,
+            self.scy,
+            self.scx,
+            self.ly,
+            self.lyc,
+            self.bgp,
+            self.obp0,
+            self.obp1,
+            self.wy,
+            self.wx,
+            self.window_line_counter,
+            self.bcps,
+            self.ocps,
+        ] {
+            state.u8(reg);
+        }
+
+        state.bytes(&self.line_start_palettes);
+        state.u16(self.palette_writes.len() as u16);
+
+        for &(x, index, value) in &self.palette_writes {
+            state.u8(x);
+            state.u16(index as u16);
+            state.u8(value);
+        }
+
+        state.bytes(&self.bgd_palettes);
+        state.bytes(&self.obj_palettes);
+        state.bool(self.wy_triggered);
+        state.bool(self.cgb_mode);
+        state.bool(self.vram_banked);
+        state.bool(self.ly_wrapped);
+        state.bool(self.stat_line);
+        state.u8(self.current_mode as u8);
+        state.u32(self.total_cycles);
+        state.bytes(&self.back_framebuffer[..]);
+        state.bytes(&self.front_framebuffer[..]);
+    }
+
+    /// Restore the memories, registers and the frames of the PPU.
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), SaveStateError> {
+        state.bytes(&mut self.vram)?;
+        state.bytes(&mut self.oam_ram)?;
+
+        for reg in [
+            &mut self.lcdc,
+            &mut self.stat,
+            &mut self.scy,
+            &mut self.scx,
+            &mut self.ly,
+            &mut self.lyc,
+            &mut self.bgp,
+            &mut self.obp0,
+            &mut self.obp1,
+            &mut self.wy,
+            &mut self.wx,
+            &mut self.window_line_counter,
+            &mut self.bcps,
+            &mut self.ocps,
+        ]
+        .iter_mut()
+        {
+            **reg = state.u8()?;
+        }
+
+        state.bytes(&mut self.line_start_palettes)?;
+        self.palette_writes.clear();
+
+        for _ in 0..state.u16()? {
+            let x = state.u8()?;
+            let index = state.u16()? as usize;
+            let value = state.u8()?;
+
+            // Writes are recorded for BGP, OBP0 and OBP1 only.
+            ensure(index < 3)?;
+
+            self.palette_writes.push((x, index, value));
+        }
+
+        state.bytes(&mut self.bgd_palettes)?;
+        state.bytes(&mut self.obj_palettes)?;
+        self.wy_triggered = state.bool()?;
+        self.cgb_mode = state.bool()?;
+        self.vram_banked = state.bool()?;
+        self.ly_wrapped = state.bool()?;
+        self.stat_line = state.bool()?;
+
+        self.current_mode = match state.u8()? & 0x03 {
+            0 => PpuMode::HBlank,
+            1 => PpuMode::VBlank,
+            2 => PpuMode::OamSearch,
+            _ => PpuMode::Drawing,
+        };
+
+        self.total_cycles = state.u32()?;
+        state.bytes(&mut self.back_framebuffer[..])?;
+        state.bytes(&mut self.front_framebuffer[..])?;
+
+        // Lines past 143 are never drawn, and are only reached in VBlank.
+        ensure(self.ly <= 153)?;
+        ensure(self.ly < 144 || matches!(self.current_mode, PpuMode::VBlank))?;
+
+        Ok(())
+    }
+
+    /// Enable or disable the 10 sprites per line limit.
+    pub fn set_sprite_limit(&mut self, enabled: bool) {
+        self.sprite_limit = enabled;
+    }
+
+    /// Get the number of sprites selected on the last rendered line.
+    pub fn last_line_sprite_count(&self) -> u8 {
+        self.line_sprite_count
+    }
+
+    /// Set the intensity of the scanline overlay, 0.0 disables it.
+    pub fn set_scanline_overlay(&mut self, intensity: f32) {
+        self.scanline_overlay = intensity.clamp(0.0, 1.0);
+    }
+
+    /// Render like a DMG, as selected by KEY0 on a CGB.
+    pub fn set_dmg_compatibility(&mut self, enabled: bool) {
+        self.cgb_mode = !enabled;
+    }
+
+    /// Get the current line, from the frame being drawn.
+    pub fn current_line(&self) -> &[u8] {
+        let offset = self.ly.min(143) as usize * 160 * 4;
+
+        &self.back_framebuffer[offset..offset + 160 * 4]
+    }
+
+    /// Set the order of the colour bytes in the framebuffer.
+    pub fn set_pixel_order(&mut self, order: PixelOrder) {
+        self.pixel_order = order;
+    }
+
+    /// Show or hide the background, window and sprite layers.
+    pub fn set_layer_mask(&mut self, bg: bool, window: bool, sprites: bool) {
+        self.layer_mask = (bg, window, sprites);
+    }
+
+    /// Set the colours of the four DMG shades, lightest first,
+    /// each given as 0xRRGGBB.
+    pub fn set_dmg_palette(&mut self, colours: [u32; 4]) {
+        for (shade, colour) in self.dmg_palette.iter_mut().zip(&colours) {
+            *shade = ((colour << 8) | 0xFF).swap_bytes();
+        }
+    }
+
+    /// Enable or disable the blur filter.
+    pub fn set_blur_filter(&mut self, enabled: bool) {
+        self.blur_filter = enabled;
+    }
+
+    /// Copy the back buffer to the front buffer through a 3x3 tent filter.
+    /// Each output pixel weighs itself 4, its direct neighbours 2 and its
+    /// diagonal neighbours 1, so dithering blends into flat colours while
+    /// the image stays centred. Neighbours past the edges are mirrored.
+    fn blur_to_front(&mut self) {
+        const WEIGHTS: [u16; 3] = [1, 2, 1];
+
+        // The neighbour of `i` at `d - 1`, mirrored at the edges.
+        let neighbour = |i: usize, d: usize, len: usize| match (i + d).checked_sub(1) {
+            None => 1,
+            Some(n) if n == len => len - 2,
+            Some(n) => n,
+        };
+
+        for y in 0..144 {
+            for x in 0..160 {
+                let mut sums = [0u16; 4];
+
+                for (dy, &wy) in WEIGHTS.iter().enumerate() {
+                    for (dx, &wx) in WEIGHTS.iter().enumerate() {
+                        let offset = (neighbour(y, dy, 144) * 160 + neighbour(x, dx, 160)) * 4;
+
+                        for (channel, sum) in sums.iter_mut().enumerate() {
+                            *sum += self.back_framebuffer[offset + channel] as u16 * wy * wx;
+                        }
+                    }
+                }
+
+                let dst = (y * 160 + x) * 4;
+
+                for (channel, sum) in sums.iter().enumerate() {
+                    self.front_framebuffer[dst + channel] = (sum / 16) as u8;
+                }
+            }
+        }
+    }
+
+    /// Darken the odd rows of the front buffer to mimic a CRT.
+    fn apply_scanline_overlay(&mut self) {
+        if self.scanline_overlay == 0.0 {
+            return;
+        }
+
+        let scale = 1.0 - self.scanline_overlay;
+
+        for row in self
+            .front_framebuffer
+            .chunks_exact_mut(160 * 4)
+            .skip(1)
+            .step_by(2)
+        {
+            for pixel in row.chunks_exact_mut(4) {
+                for channel in &mut pixel[..3] {
+                    *channel = (*channel as f32 * scale) as u8;
+                }
+            }
+        }
+    }
+
+    /// Get information about the tile at the given coordinates of
+    /// the first (0x9800) or second (0x9C00) tile map.
+    pub fn tile_at(&self, map: u8, tx: u8, ty: u8) -> TileInfo {
+        let tile_map = if map == 0 { 0x1800 } else { 0x1C00 };
+        let offset = tile_map + ((ty as usize & 0x1F) << 5) + (tx as usize & 0x1F);
+
+        let index = self.vram[offset];
+        let attributes = if self.cgb_mode {
+            self.vram[offset + 0x2000]
+        } else {
+            0x00
+        };
+
+        // 1. 0x8000: (TILE_NUMBER as u8 * 16) + 0x8000.
+        // 2. 0x8800: (TILE_NUMBER as i8 * 16) + 0x9000.
+        let address = if bit!(&self.lcdc, 4) {
+            0x8000 + ((index as u16) << 4)
+        } else {
+            0x9000u16.wrapping_add(((index as i8 as i16) as u16) << 4)
+        };
+
+        TileInfo {
+            index,
+            attributes,
+            address,
+        }
+    }
+
+    /// Render all 384 tiles of a VRAM bank in a grid 16 tiles wide and
+    /// 24 tiles high, in the same format as the framebuffer. DMG tiles are
+    /// coloured through BGP, CGB tiles with a plain grayscale ramp.
+    /// Returns `None` for banks other than 0 and 1.
+    pub fn dump_tile_atlas(&self, bank: usize) -> Option<Box<[u8; 128 * 192 * 4]>> {
+        let tiles = match bank {
+            0 => &self.vram[..0x1800],
+            1 => &self.vram[0x2000..0x3800],
+
+            _ => return None,
+        };
+
+        let mut shades = [0u32; 4];
+
+        for (i, shade) in shades.iter_mut().enumerate() {
+            *shade = if self.cgb_mode {
+                let level = 0xFF - 0x55 * i as u8;
+
+                u32::from_le_bytes([level, level, level, 0xFF])
+            } else {
+                self.dmg_palette[((self.bgp >> (i << 1)) & 0x03) as usize]
+            };
+        }
+
+        let mut atlas = Box::new([0; 128 * 192 * 4]);
+
+        for (index, tile) in tiles.chunks_exact(16).enumerate() {
+            let (tx, ty) = (index % 16, index / 16);
+
+            for (row, bytes) in tile.chunks_exact(2).enumerate() {
+                for column in 0..8 {
+                    let colour_index = (((bytes[1] >> (7 - column)) & 0x01) << 1)
+                        | ((bytes[0] >> (7 - column)) & 0x01);
+
+                    let colour = self.order_colour(shades[colour_index as usize]);
+                    let offset = ((ty * 8 + row) * 128 + tx * 8 + column) * 4;
+
+                    atlas[offset..offset + 4].copy_from_slice(&colour.to_ne_bytes());
+                }
+            }
+        }
+
+        Some(atlas)
+    }
+
+    /// Scan the tile data in VRAM bank 0 for tiles matching the
+    /// given pattern and return their addresses.
+    pub fn find_tile(&self, pattern: &[u8; 16]) -> Vec<u16> {
+        self.vram[..0x1800]
+            .chunks_exact(16)
+            .enumerate()
+            .filter(|(_, tile)| *tile == pattern)
+            .map(|(i, _)| 0x8000 + (i as u16) * 16)
+            .collect()
+    }
+
+    /// Scan OAM for sprites using the given tile index and
+    /// return their indices in OAM.
+    pub fn find_sprite_using_tile(&self, index: u8) -> Vec<usize> {
+        self.oam_ram
+            .chunks_exact(4)
+            .enumerate()
+            .filter(|(_, entry)| entry[2] == index)
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    /// Export the DMG palette registers and all CGB palettes as JSON.
+    /// Each CGB colour carries its raw 15-bit value and the colour
+    /// corrected RGB value.
+    pub fn export_palettes(&self) -> String {
+        let hex = |colour: u32| {
+            let [r, g, b, _] = colour.to_le_bytes();
+
+            format!("\"#{:02X}{:02X}{:02X}\"", r, g, b)
+        };
+
+        let cgb_palettes = |palettes: &[u8; 0x40]| {
+            let entries: Vec<String> = palettes
+                .chunks_exact(8)
+                .map(|palette| {
+                    let colours: Vec<String> = palette
+                        .chunks_exact(2)
+                        .map(|colour| {
+                            let raw = u16::from_le_bytes([colour[0], colour[1]]);
+
+                            format!(
+                                "{{\"raw\": {}, \"rgb\": {}}}",
+                                raw,
+                                hex(self.scale_rgb(raw))
+                            )
+                        })
+                        .collect();
+
+                    format!("[{}]", colours.join(", "))
+                })
+                .collect();
+
+            format!("[{}]", entries.join(", "))
+        };
+
+        let dmg_colours: Vec<String> = self.dmg_palette.iter().map(|&c| hex(c)).collect();
+
+        format!(
+            "{{\"dmg\": {{\"bgp\": {}, \"obp0\": {}, \"obp1\": {}, \"colours\": [{}]}}, \"bg\": {}, \"obj\": {}}}",
+            self.bgp,
+            self.obp0,
+            self.obp1,
+            dmg_colours.join(", "),
+            cgb_palettes(&self.bgd_palettes),
+            cgb_palettes(&self.obj_palettes),
+        )
+    }
+
+    /// Import palettes previously exported with `export_palettes`.
+    /// Only the DMG palette registers and the raw CGB colours are read.
+    pub fn import_palettes(&mut self, json: &str) -> Result<(), String> {
+        // Parse the number following every occurrence of the given key.
+        let values = |key: &str| -> Result<Vec<u16>, String> {
+            let key = format!("\"{}\":", key);
+
+            json.match_indices(&key)
+                .map(|(index, _)| {
+                    let rest = json[index + key.len()..].trim_start();
+                    let end = rest
+                        .find(|c: char| !c.is_ascii_digit())
+                        .unwrap_or(rest.len());
+
+                    rest[..end]
+                        .parse()
+                        .map_err(|_| format!("invalid value for {}", key))
+                })
+                .collect()
+        };
+
+        let register = |key: &str| -> Result<u8, String> {
+            match values(key)?.as_slice() {
+                [value] if *value <= 0xFF => Ok(*value as u8),
+                _ => Err(format!("expected a single byte for \"{}\"", key)),
+            }
+        };
+
+        let raw = values("raw")?;
+
+        if raw.len() != 64 {
+            return Err(format!("expected 64 CGB colours, found {}", raw.len()));
+        }
+
+        self.bgp = register("bgp")?;
+        self.obp0 = register("obp0")?;
+        self.obp1 = register("obp1")?;
+
+        for (i, colour) in raw.iter().enumerate() {
+            let [lower, upper] = (colour & 0x7FFF).to_le_bytes();
+
+            let palettes = if i < 32 {
+                &mut self.bgd_palettes
+            } else {
+                &mut self.obj_palettes
+            };
+
+            palettes[(i % 32) * 2] = lower;
+            palettes[(i % 32) * 2 + 1] = upper;
+        }
+
+        Ok(())
+    }
+
+    /// Switch off the LCD, this resets LY and puts the PPU
+    /// in HBlank until it is switched back on.
+    fn disable_lcd(&mut self) {
+        self.ly = 0;
+        self.ly_wrapped = false;
+        self.stat_line = false;
+        self.window_line_counter = 0;
+        self.wy_triggered = false;
+        self.total_cycles = 0;
+        self.current_mode = PpuMode::HBlank;
+    }
+
+    /// Switch on the LCD, rendering restarts from the
+    /// first scanline.
+    fn enable_lcd(&mut self) {
+        self.total_cycles = 0;
+        self.current_mode = PpuMode::OamSearch;
+        self.wy_triggered |= self.wy == self.ly;
+        self.compare_lyc();
+    }
+
     /// Change the PPU's current mode.
     fn change_mode(&mut self, mode: PpuMode) {
         self.current_mode = mode;
@@ -291,35 +886,26 @@ impl Ppu {
         match mode {
             PpuMode::HBlank => {
                 self.render_scanline();
-
-                // Request STAT interrupt if HBlank bit
-                // in LCD STAT is set.
-                if bit!(&self.stat, 3) {
-                    set!(self.if_reg.borrow_mut(), 1);
-                }
+                self.line_drawn = true;
             }
 
             PpuMode::VBlank => {
                 // Request a VBlank interrupt.
                 set!(self.if_reg.borrow_mut(), 0);
+            }
 
-                // Request STAT interrupt if VBlank bit
-                // in LCD STAT is set.
-                if bit!(&self.stat, 4) {
-                    set!(self.if_reg.borrow_mut(), 1);
-                }
+            PpuMode::Drawing => {
+                self.line_start_palettes = [self.bgp, self.obp0, self.obp1];
+                self.palette_writes.clear();
             }
 
             PpuMode::OamSearch => {
-                // Request STAT interrupt if OamSearch bit
-                // in LCD STAT is set.
-                if bit!(&self.stat, 5) {
-                    set!(self.if_reg.borrow_mut(), 1);
-                }
+                // The WY condition is checked at the start of every line.
+                self.wy_triggered |= self.wy == self.ly;
             }
-
-            _ => {}
         }
+
+        self.update_stat_line();
     }
 
     /// Compare LY and LYC and if they are equal,
@@ -328,13 +914,31 @@ impl Ppu {
     fn compare_lyc(&mut self) {
         if self.ly == self.lyc {
             set!(&mut self.stat, 2);
-
-            if bit!(&self.stat, 6) {
-                set!(self.if_reg.borrow_mut(), 1);
-            }
         } else {
             res!(&mut self.stat, 2);
         }
+
+        self.update_stat_line();
+    }
+
+    /// Recompute the STAT interrupt line, and request a
+    /// STAT interrupt if it went from low to high.
+    fn update_stat_line(&mut self) {
+        let mode_source = match self.current_mode {
+            PpuMode::HBlank => bit!(&self.stat, 3),
+            PpuMode::VBlank => bit!(&self.stat, 4),
+            PpuMode::OamSearch => bit!(&self.stat, 5),
+            PpuMode::Drawing => false,
+        };
+
+        let line =
+            bit!(&self.lcdc, 7) && (mode_source || (bit!(&self.stat, 2) && bit!(&self.stat, 6)));
+
+        if line && !self.stat_line {
+            set!(self.if_reg.borrow_mut(), 1);
+        }
+
+        self.stat_line = line;
     }
 
     /// Tick the PPU by 1 M cycle, and return a bool
@@ -346,6 +950,13 @@ impl Ppu {
 
         self.total_cycles += cycles;
 
+        match self.current_mode {
+            PpuMode::OamSearch => self.frame_timing.oam_search += cycles,
+            PpuMode::Drawing => self.frame_timing.drawing += cycles,
+            PpuMode::HBlank => self.frame_timing.hblank += cycles,
+            PpuMode::VBlank => self.frame_timing.vblank += cycles,
+        }
+
         let mut entered_hblank = false;
 
         // The actual PPU timings are not fixed.
@@ -380,23 +991,56 @@ impl Ppu {
                 self.compare_lyc();
             }
 
+            // LY already reads 0 a few cycles into line 153, which
+            // is when LYC=0 coincidences happen on hardware.
+            PpuMode::VBlank
+                if self.line_153_quirk
+                    && self.ly == 153
+                    && !self.ly_wrapped
+                    && self.total_cycles >= 4 =>
+            {
+                self.ly = 0;
+                self.ly_wrapped = true;
+                self.compare_lyc();
+            }
+
             PpuMode::VBlank if self.total_cycles >= 456 => {
                 self.total_cycles -= 456;
-                self.ly += 1;
+
+                let wrapped_early = std::mem::take(&mut self.ly_wrapped);
+
+                if !wrapped_early {
+                    self.ly += 1;
+                }
 
                 // The PPU actually has 154 lines instead of 144.
                 // These 10 lines are `psuedo lines` of sorts.
-                if self.ly == 154 {
+                if self.ly == 154 || wrapped_early {
                     // Swap the copy the back buffer to the front buffer.
-                    self.front_framebuffer
-                        .copy_from_slice(self.back_framebuffer.as_ref());
+                    if self.blur_filter {
+                        self.blur_to_front();
+                    } else {
+                        self.front_framebuffer
+                            .copy_from_slice(self.back_framebuffer.as_ref());
+                    }
+
+                    self.apply_scanline_overlay();
+                    self.last_frame_timing = std::mem::take(&mut self.frame_timing);
+
+                    if let Some(callback) = &mut self.vblank_callback {
+                        callback(self.front_framebuffer.as_ref());
+                    }
 
                     self.ly = 0;
                     self.window_line_counter = 0;
+                    self.wy_triggered = false;
                     self.change_mode(PpuMode::OamSearch);
                 }
 
-                self.compare_lyc();
+                // LY was compared with LYC when it went to 0 early.
+                if !wrapped_early {
+                    self.compare_lyc();
+                }
             }
 
             _ => {}
@@ -407,6 +1051,11 @@ impl Ppu {
 
     /// Render the current scanline.
     fn render_scanline(&mut self) {
+        if !self.rendering_enabled {
+            return;
+        }
+
+        self.latch_line_palettes();
         self.render_background();
         self.render_sprites();
     }
@@ -415,12 +1064,26 @@ impl Ppu {
     /// coordinates.
     fn set_pixel(&mut self, x: u8, y: u8, colour: u32) {
         let offset = ((y as usize * 160) + x as usize) * 4;
+        let colour = self.order_colour(colour);
 
         unsafe {
             *(self.back_framebuffer.as_mut_ptr().add(offset) as *mut u32) = colour;
         }
     }
 
+    /// Arrange the bytes of a colour in the selected pixel order.
+    fn order_colour(&self, colour: u32) -> u32 {
+        match self.pixel_order {
+            PixelOrder::Rgb => colour,
+            PixelOrder::Bgr => {
+                let mut bytes = colour.to_ne_bytes();
+                bytes.swap(0, 2);
+
+                u32::from_ne_bytes(bytes)
+            }
+        }
+    }
+
     /// Scale the CGB 5 bit RGB to standard 8 bit RGB.
     /// Colour Correction Algorithm taken from Byuu's (Near) blog.
     /// https://near.sh/articles/video/color-emulation
@@ -447,11 +1110,48 @@ impl Ppu {
         scaled.swap_bytes()
     }
 
+    /// Remember a DMG palette write, if it landed while a line was being
+    /// drawn. Pixel output starts 12 T-cycles into the drawing mode.
+    fn record_palette_write(&mut self, index: usize, value: u8) {
+        if bit!(&self.lcdc, 7) && !self.cgb_mode && matches!(self.current_mode, PpuMode::Drawing) {
+            let x = self.total_cycles.saturating_sub(12).min(160) as u8;
+
+            self.palette_writes.push((x, index, value));
+        }
+    }
+
+    /// Work out the DMG palettes in effect for each pixel of the line,
+    /// so that palettes changed mid-line split the line like on hardware.
+    fn latch_line_palettes(&mut self) {
+        if self.palette_writes.is_empty() {
+            self.line_palettes = [[self.bgp, self.obp0, self.obp1]; 160];
+
+            return;
+        }
+
+        let mut palettes = self.line_start_palettes;
+        let mut writes = self.palette_writes.iter().peekable();
+
+        for x in 0..160 {
+            while let Some(&(_, index, value)) = writes.next_if(|write| write.0 <= x as u8) {
+                palettes[index] = value;
+            }
+
+            self.line_palettes[x] = palettes;
+        }
+    }
+
     /// Render the background map and the window map for this scanline.
     fn render_background(&mut self) {
         // The 0th bit of the LCDC in DMG mode when zero disables all forms
-        // of background and window rendering.
+        // of background and window rendering. The line is blank, and
+        // sprites are drawn over it regardless of their priority.
         if !bit!(&self.lcdc, 0) && !self.cgb_mode {
+            for x in 0u8..160u8 {
+                self.bgd_line[x as usize] = (0, false);
+                self.set_pixel(x, self.ly, self.dmg_palette[0]);
+            }
+
             return;
         }
 
@@ -476,21 +1176,41 @@ impl Ppu {
         for x in 0u8..160u8 {
             // Extract the absolute X and Y coordinates of the pixel in
             // the respective 256 x 256 tile map.
-            let (map_x, map_y, tile_map) =
-                if bit!(&self.lcdc, 5) && self.wy <= self.ly && self.wx <= x + 7 {
+            let (map_x, map_y, tile_map, is_window) =
+                if bit!(&self.lcdc, 5) && self.wy_triggered && self.wx <= x + 7 {
                     let map_x = x.wrapping_add(7).wrapping_sub(self.wx);
                     let map_y = self.window_line_counter;
 
                     increment_window_counter = true;
 
-                    (map_x, map_y, win_map)
+                    (map_x, map_y, win_map, true)
                 } else {
                     let map_x = x.wrapping_add(self.scx);
                     let map_y = self.ly.wrapping_add(self.scy);
 
-                    (map_x, map_y, bgd_map)
+                    (map_x, map_y, bgd_map, false)
                 };
 
+            // Leave the pixel blank if its layer is hidden.
+            let layer_shown = if is_window {
+                self.layer_mask.1
+            } else {
+                self.layer_mask.0
+            };
+
+            if !layer_shown {
+                let blank = if self.cgb_mode {
+                    self.scale_rgb(0x7FFF)
+                } else {
+                    self.dmg_palette[0]
+                };
+
+                self.bgd_line[x as usize] = (0, false);
+                self.set_pixel(x, self.ly, blank);
+
+                continue;
+            }
+
             // Extract the X and Y coordinates of the pixel inside the
             // respective tile.
             let mut tile_x = map_x & 0x07;
@@ -546,8 +1266,8 @@ impl Ppu {
                 self.bgd_line[x as usize] = (pixel_colour, false);
 
                 // Extract the actual pixel colour.
-                let actual_pixel_colour =
-                    DMG_MODE_PALETTE[((self.bgp >> (pixel_colour << 1)) & 0x03) as usize];
+                let actual_pixel_colour = self.dmg_palette
+                    [((self.line_palettes[x as usize][0] >> (pixel_colour << 1)) & 0x03) as usize];
 
                 self.set_pixel(x, self.ly, actual_pixel_colour);
             } else {
@@ -595,6 +1315,7 @@ impl Ppu {
         // The 1st bit of LCDC controls whether OBJs (sprites)
         // are rendered or not.
         if !bit!(&self.lcdc, 1) {
+            self.line_sprite_count = 0;
             return;
         }
 
@@ -609,10 +1330,13 @@ impl Ppu {
         // The requirements for a sprite to be visible are,
         // 1. Y <= LY
         // 2. LY < (Y + SPRITE SIZE)
+        //
+        // Both DMG and CGB select at most 10 sprites per line, picking
+        // the first ones in OAM order, unless the limit is disabled.
         let mut sprites = Vec::with_capacity(10);
 
         for i in (0x00..0xA0).step_by(4) {
-            if sprites.len() == 10 {
+            if self.sprite_limit && sprites.len() == 10 {
                 break;
             }
 
@@ -631,6 +1355,12 @@ impl Ppu {
             }
         }
 
+        self.line_sprite_count = sprites.len() as u8;
+
+        if !self.layer_mask.2 {
+            return;
+        }
+
         // Sort the sprites in a way that,
         //
         // 1. The sprite that has the lower X coordinate will draw
@@ -653,12 +1383,8 @@ impl Ppu {
             // Is the sprite flipped over the X axis.
             let x_flip = bit!(&sprite_attr, 5);
 
-            // The palette used to render the sprite. (DMG)
-            let palette = if bit!(&sprite_attr, 4) {
-                self.obp1
-            } else {
-                self.obp0
-            };
+            // The palette used to render the sprite, OBP0 or OBP1. (DMG)
+            let palette_index = if bit!(&sprite_attr, 4) { 2 } else { 1 };
 
             // The palette used to render the sprite. (CGB)
             let colour_palette = (sprite_attr & 0x07) as usize;
@@ -711,12 +1437,19 @@ impl Ppu {
 
                         self.scale_rgb(cgb_colour)
                     } else {
-                        DMG_MODE_PALETTE[((palette >> (colour_index << 1)) & 0x03) as usize]
+                        let palette = self.line_palettes[actual_x as usize][palette_index];
+
+                        self.dmg_palette[((palette >> (colour_index << 1)) & 0x03) as usize]
                     };
 
                     // We don't draw pixels that are transparent.
                     if colour_index != 0 {
                         if self.cgb_mode {
+                            // In CGB mode the sprite is drawn when,
+                            // 1. LCDC bit 0 is clear, the master priority override.
+                            // 2. The BG colour is 0, whatever the priority bits say.
+                            // 3. Neither the BG attributes nor the sprite
+                            //    give priority to the BG.
                             if !bit!(&self.lcdc, 0)
                                 || (self.bgd_line[actual_x as usize].0 == 0)
                                 || (!self.bgd_line[actual_x as usize].1 && sprite_over_bg)
@@ -732,3 +1465,564 @@ impl Ppu {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+
+    use super::*;
+
+    /// Create a DMG mode `Ppu`, along with the IF register it requests interrupts on.
+    fn ppu() -> (Ppu, Rc<RefCell<u8>>) {
+        let if_reg = Rc::new(RefCell::new(0));
+
+        (Ppu::new(if_reg.clone(), false), if_reg)
+    }
+
+    /// Get the colour of a pixel in the back buffer.
+    fn pixel(ppu: &Ppu, x: usize, y: usize) -> u32 {
+        let offset = (y * 160 + x) * 4;
+
+        u32::from_ne_bytes(ppu.back_framebuffer[offset..offset + 4].try_into().unwrap())
+    }
+
+    /// Tick the PPU until it starts drawing the given line.
+    fn run_to_line(ppu: &mut Ppu, ly: u8) {
+        while ppu.ly != ly || !matches!(ppu.current_mode, PpuMode::Drawing) {
+            ppu.tick(4);
+        }
+    }
+
+    #[test]
+    fn lcd_off_reads_mode_and_ly_as_zero() {
+        let (mut ppu, _) = ppu();
+
+        // Run into the middle of the frame.
+        run_to_line(&mut ppu, 10);
+
+        assert_eq!(ppu.read_byte(0xFF41) & 0x03, PpuMode::Drawing as u8);
+        assert_eq!(ppu.read_byte(0xFF44), 10);
+
+        ppu.write_byte(0xFF40, 0x11);
+
+        assert_eq!(ppu.read_byte(0xFF41) & 0x03, 0);
+        assert_eq!(ppu.read_byte(0xFF44), 0);
+        assert!(matches!(ppu.current_mode, PpuMode::HBlank));
+        assert_eq!(ppu.ly, 0);
+
+        // Nothing moves while the LCD is off.
+        ppu.tick(456);
+
+        assert_eq!(ppu.read_byte(0xFF44), 0);
+    }
+
+    #[test]
+    fn find_placed_tile_and_sprites() {
+        let (mut ppu, _) = ppu();
+        let pattern = [
+            0x3C, 0x7E, 0x42, 0x42, 0x42, 0x42, 0x7E, 0x7E, 0x5E, 0x7E, 0x0A, 0x7C, 0x56, 0x38,
+            0x7C, 0x00,
+        ];
+
+        for (i, &byte) in pattern.iter().enumerate() {
+            ppu.write_byte(0x8120 + i as u16, byte);
+        }
+
+        ppu.write_byte(0xFE06, 0x12);
+        ppu.write_byte(0xFE1A, 0x12);
+
+        assert_eq!(ppu.find_tile(&pattern), vec![0x8120]);
+        assert_eq!(ppu.find_sprite_using_tile(0x12), vec![1, 6]);
+        assert!(ppu.find_sprite_using_tile(0x13).is_empty());
+    }
+
+    #[test]
+    fn palette_export_import_round_trip() {
+        let if_reg = Rc::new(RefCell::new(0));
+        let mut ppu = Ppu::new(if_reg.clone(), true);
+
+        ppu.write_byte(0xFF47, 0xE4);
+        ppu.write_byte(0xFF48, 0xD2);
+        ppu.write_byte(0xFF49, 0x1B);
+
+        // Fill the palette RAM through the auto-incrementing index.
+        ppu.write_byte(0xFF68, 0x80);
+        ppu.write_byte(0xFF6A, 0x80);
+
+        // The upper bit of every colour is unused.
+        for i in 0..0x40u8 {
+            let mask = if i % 2 == 0 { 0xFF } else { 0x7F };
+
+            ppu.write_byte(0xFF69, i.wrapping_mul(37) & mask);
+            ppu.write_byte(0xFF6B, i.wrapping_mul(11).wrapping_add(3) & mask);
+        }
+
+        let mut imported = Ppu::new(if_reg, true);
+        imported.import_palettes(&ppu.export_palettes()).unwrap();
+
+        assert_eq!(imported.bgd_palettes, ppu.bgd_palettes);
+        assert_eq!(imported.obj_palettes, ppu.obj_palettes);
+        assert_eq!(
+            (imported.bgp, imported.obp0, imported.obp1),
+            (0xE4, 0xD2, 0x1B)
+        );
+    }
+
+    #[test]
+    fn window_triggers_once_wy_matches_ly() {
+        let (mut ppu, _) = ppu();
+
+        ppu.write_byte(0xFF40, 0xB1);
+        ppu.write_byte(0xFF4A, 50);
+
+        // Moving WY above the current line doesn't trigger the window.
+        run_to_line(&mut ppu, 30);
+        ppu.write_byte(0xFF4A, 20);
+        run_to_line(&mut ppu, 40);
+        assert!(!ppu.wy_triggered);
+
+        // It triggers on the line WY matches LY.
+        ppu.write_byte(0xFF4A, 70);
+        run_to_line(&mut ppu, 69);
+        assert!(!ppu.wy_triggered);
+        run_to_line(&mut ppu, 70);
+        assert!(ppu.wy_triggered);
+
+        // And stays active for the rest of the frame.
+        ppu.write_byte(0xFF4A, 200);
+        run_to_line(&mut ppu, 143);
+        assert!(ppu.wy_triggered);
+
+        // Until the next frame starts.
+        run_to_line(&mut ppu, 0);
+        assert!(!ppu.wy_triggered);
+    }
+
+    #[test]
+    fn ten_sprites_per_line_in_oam_order() {
+        let (mut ppu, _) = ppu();
+
+        ppu.write_byte(0xFF40, 0x93);
+        ppu.write_byte(0xFF47, 0xE4);
+        ppu.write_byte(0xFF48, 0xE4);
+
+        // Tile 1 is filled with colour 3.
+        for addr in 0x8010..0x8020 {
+            ppu.write_byte(addr, 0xFF);
+        }
+
+        // Place 15 sprites on line 20, the later ones in OAM further left.
+        for i in 0..15 {
+            let oam = 0xFE00 + i * 4;
+
+            ppu.write_byte(oam, 20 + 16);
+            ppu.write_byte(oam + 1, (14 - i as u8) * 10 + 8);
+            ppu.write_byte(oam + 2, 1);
+            ppu.write_byte(oam + 3, 0);
+        }
+
+        run_to_line(&mut ppu, 21);
+
+        assert_eq!(ppu.last_line_sprite_count(), 10);
+
+        for i in 0..15 {
+            let x = (14 - i) * 10;
+            let expected = if i < 10 { 3 } else { 0 };
+
+            assert_eq!(
+                pixel(&ppu, x, 20),
+                ppu.dmg_palette[expected],
+                "sprite {}",
+                i
+            );
+        }
+
+        // All of them are drawn with the limit disabled.
+        ppu.set_sprite_limit(false);
+        run_to_line(&mut ppu, 20);
+        run_to_line(&mut ppu, 21);
+
+        assert_eq!(ppu.last_line_sprite_count(), 15);
+        assert_eq!(pixel(&ppu, 0, 20), ppu.dmg_palette[3]);
+    }
+
+    #[test]
+    fn scanline_overlay_darkens_odd_rows() {
+        let (mut ppu, _) = ppu();
+
+        ppu.front_framebuffer
+            .iter_mut()
+            .for_each(|byte| *byte = 0xFF);
+        ppu.apply_scanline_overlay();
+        assert!(ppu.front_framebuffer.iter().all(|&byte| byte == 0xFF));
+
+        ppu.set_scanline_overlay(1.0);
+        ppu.apply_scanline_overlay();
+
+        for (y, row) in ppu.front_framebuffer.chunks_exact(160 * 4).enumerate() {
+            for pixel in row.chunks_exact(4) {
+                let expected = if y % 2 == 1 { 0x00 } else { 0xFF };
+
+                assert_eq!(pixel, [expected, expected, expected, 0xFF]);
+            }
+        }
+    }
+
+    #[test]
+    fn tile_at_resolves_the_tile_address() {
+        let (mut ppu, _) = ppu();
+
+        ppu.write_byte(0xFF40, 0x00);
+        ppu.write_byte(0x9C65, 0x7F);
+        ppu.write_byte(0x9800, 0x80);
+
+        let tile = ppu.tile_at(1, 5, 3);
+        assert_eq!(
+            (tile.index, tile.attributes, tile.address),
+            (0x7F, 0x00, 0x97F0)
+        );
+        assert_eq!(ppu.tile_at(0, 0, 0).address, 0x8800);
+
+        ppu.write_byte(0xFF40, 0x10);
+
+        assert_eq!(ppu.tile_at(1, 5, 3).address, 0x87F0);
+        assert_eq!(ppu.tile_at(0, 0, 0).address, 0x8800);
+    }
+
+    #[test]
+    fn frame_timing_adds_up_to_a_frame() {
+        let (mut ppu, _) = ppu();
+        let frames = Rc::new(RefCell::new(0));
+        let counter = frames.clone();
+
+        ppu.vblank_callback = Some(Box::new(move |_| *counter.borrow_mut() += 1));
+
+        // The first frame after power on is partial.
+        while *frames.borrow() < 2 {
+            ppu.tick(4);
+        }
+
+        let timing = ppu.last_frame_timing;
+
+        assert_eq!(
+            timing.oam_search + timing.drawing + timing.hblank + timing.vblank,
+            70224
+        );
+        assert_eq!(timing.oam_search, 144 * 80);
+        assert_eq!(timing.vblank, 10 * 456);
+    }
+
+    #[test]
+    fn blur_filter_turns_a_checkerboard_gray() {
+        let (mut ppu, _) = ppu();
+
+        for (i, pixel) in ppu.back_framebuffer.chunks_exact_mut(4).enumerate() {
+            let (x, y) = (i % 160, i / 160);
+            let shade = if (x + y) % 2 == 0 { 0xFF } else { 0x00 };
+
+            pixel.copy_from_slice(&[shade, shade, shade, 0xFF]);
+        }
+
+        ppu.set_blur_filter(true);
+        ppu.blur_to_front();
+
+        for pixel in ppu.front_framebuffer.chunks_exact(4) {
+            assert_eq!(pixel, [0x7F, 0x7F, 0x7F, 0xFF]);
+        }
+    }
+
+    #[test]
+    fn blur_filter_keeps_the_image_centred() {
+        let (mut ppu, _) = ppu();
+
+        // A white column on black, in the middle and at the left edge.
+        for (i, pixel) in ppu.back_framebuffer.chunks_exact_mut(4).enumerate() {
+            let shade = if i % 160 == 80 || i % 160 == 0 {
+                0xFF
+            } else {
+                0x00
+            };
+
+            pixel.copy_from_slice(&[shade, shade, shade, 0xFF]);
+        }
+
+        ppu.blur_to_front();
+
+        let front = |x: usize| ppu.front_framebuffer[(72 * 160 + x) * 4];
+
+        assert_eq!([front(79), front(80), front(81)], [0x3F, 0x7F, 0x3F]);
+        assert_eq!([front(0), front(1)], [0x7F, 0x3F]);
+    }
+
+    #[test]
+    fn cgb_sprite_priority_combinations() {
+        for case in 0..16 {
+            let lcdc0 = (case & 1) != 0;
+            let bg_priority = (case & 2) != 0;
+            let obj_priority = (case & 4) != 0;
+            let bg_colour = if (case & 8) != 0 { 2 } else { 0 };
+
+            let (_, if_reg) = ppu();
+            let mut ppu = Ppu::new(if_reg, true);
+
+            ppu.lcdc = 0x82 | lcdc0 as u8;
+
+            // A sprite at the top left corner, using tile 1 whose
+            // first row is colour 1, which is white in palette 0.
+            ppu.oam_ram[..4].copy_from_slice(&[16, 8, 1, (obj_priority as u8) << 7]);
+            ppu.vram[0x10] = 0xFF;
+            ppu.obj_palettes[2..4].copy_from_slice(&[0xFF, 0x7F]);
+
+            ppu.bgd_line = [(bg_colour, bg_priority); 160];
+            ppu.render_sprites();
+
+            let drawn = !lcdc0 || bg_colour == 0 || (!bg_priority && !obj_priority);
+
+            assert_eq!(
+                pixel(&ppu, 0, 0) == ppu.scale_rgb(0x7FFF),
+                drawn,
+                "LCDC0 {}, BG priority {}, OBJ priority {}, BG colour {}",
+                lcdc0,
+                bg_priority,
+                obj_priority,
+                bg_colour
+            );
+        }
+    }
+
+    #[test]
+    fn layer_mask_hides_sprites() {
+        let (mut ppu, _) = ppu();
+
+        ppu.write_byte(0xFF40, 0x93);
+        ppu.write_byte(0xFF47, 0xE4);
+        ppu.write_byte(0xFF48, 0xE4);
+
+        // Tile 0, used by the whole background, is filled with
+        // colour 1, and tile 1 with colour 3.
+        for i in 0..16 {
+            ppu.write_byte(0x8000 + i, if i % 2 == 0 { 0xFF } else { 0x00 });
+            ppu.write_byte(0x8010 + i, 0xFF);
+        }
+
+        // A sprite at the left edge of line 20.
+        ppu.write_byte(0xFE00, 20 + 16);
+        ppu.write_byte(0xFE01, 8);
+        ppu.write_byte(0xFE02, 1);
+        ppu.write_byte(0xFE03, 0);
+
+        run_to_line(&mut ppu, 21);
+        assert_eq!(pixel(&ppu, 0, 20), ppu.dmg_palette[3]);
+        assert_eq!(pixel(&ppu, 8, 20), ppu.dmg_palette[1]);
+
+        ppu.set_layer_mask(true, true, false);
+        run_to_line(&mut ppu, 20);
+        run_to_line(&mut ppu, 21);
+
+        assert_eq!(pixel(&ppu, 0, 20), ppu.dmg_palette[1]);
+        assert_eq!(pixel(&ppu, 8, 20), ppu.dmg_palette[1]);
+    }
+
+    #[test]
+    fn bgp_write_mid_line_splits_the_line() {
+        let (mut ppu, _) = ppu();
+
+        ppu.write_byte(0xFF40, 0x91);
+        ppu.write_byte(0xFF47, 0xE4);
+
+        // Fill tile 0, used by the whole background, with colour 1.
+        for i in 0..16 {
+            ppu.write_byte(0x8000 + i, if i % 2 == 0 { 0xFF } else { 0x00 });
+        }
+
+        run_to_line(&mut ppu, 20);
+
+        // Swap the palette about halfway through the line.
+        while ppu.total_cycles < 92 {
+            ppu.tick(4);
+        }
+
+        ppu.write_byte(0xFF47, 0x1B);
+        let split = ppu.palette_writes[0].0 as usize;
+        assert!((70..100).contains(&split));
+
+        // The pending write survives a save state round trip.
+        let mut writer = StateWriter::new();
+        ppu.save_state(&mut writer);
+        let data = writer.finish();
+
+        let (mut restored, _) = self::ppu();
+        restored
+            .load_state(&mut StateReader::new(&data).unwrap())
+            .unwrap();
+        assert_eq!(restored.palette_writes, ppu.palette_writes);
+
+        for ppu in [&mut ppu, &mut restored].iter_mut() {
+            run_to_line(ppu, 21);
+
+            assert_eq!(pixel(ppu, 0, 20), ppu.dmg_palette[1]);
+            assert_eq!(pixel(ppu, split - 1, 20), ppu.dmg_palette[1]);
+            assert_eq!(pixel(ppu, split, 20), ppu.dmg_palette[2]);
+            assert_eq!(pixel(ppu, 159, 20), ppu.dmg_palette[2]);
+        }
+    }
+
+    #[test]
+    fn bgr_order_swaps_red_and_blue() {
+        let (mut ppu, _) = ppu();
+        let colour = u32::from_ne_bytes([0x11, 0x22, 0x33, 0xFF]);
+
+        ppu.set_pixel(5, 7, colour);
+        assert_eq!(pixel(&ppu, 5, 7).to_ne_bytes(), [0x11, 0x22, 0x33, 0xFF]);
+
+        ppu.set_pixel_order(PixelOrder::Bgr);
+        ppu.set_pixel(5, 7, colour);
+        assert_eq!(pixel(&ppu, 5, 7).to_ne_bytes(), [0x33, 0x22, 0x11, 0xFF]);
+    }
+
+    #[test]
+    fn dmg_compatibility_renders_with_bgp() {
+        let (_, if_reg) = ppu();
+        let mut ppu = Ppu::new(if_reg, true);
+        ppu.set_dmg_compatibility(true);
+
+        ppu.write_byte(0xFF40, 0x91);
+        ppu.write_byte(0xFF47, 0x1B);
+
+        // Fill tile 0, used by the whole background, with colour 1.
+        for i in 0..16 {
+            ppu.write_byte(0x8000 + i, if i % 2 == 0 { 0xFF } else { 0x00 });
+        }
+
+        run_to_line(&mut ppu, 1);
+        assert_eq!(pixel(&ppu, 0, 0), ppu.dmg_palette[2]);
+    }
+
+    #[test]
+    fn lyc_0_coincidence_on_the_wrap() {
+        for &(quirk, earliest, latest) in [(false, 456, 460), (true, 4, 8)].iter() {
+            let (mut ppu, if_reg) = ppu();
+            ppu.line_153_quirk = quirk;
+
+            ppu.write_byte(0xFF45, 0x00);
+            ppu.write_byte(0xFF41, 0x40);
+            ppu.write_byte(0xFF40, 0x91);
+
+            while ppu.read_byte(0xFF44) != 153 {
+                ppu.tick(4);
+            }
+
+            *if_reg.borrow_mut() = 0;
+            let mut cycles = 0;
+
+            while *if_reg.borrow() & 0x02 == 0 {
+                ppu.tick(4);
+                cycles += 4;
+            }
+
+            assert!((earliest..=latest).contains(&cycles), "{}", cycles);
+            assert_eq!(ppu.read_byte(0xFF44), 0);
+        }
+    }
+
+    #[test]
+    fn all_black_palette_renders_black() {
+        let (mut ppu, _) = ppu();
+        let black = u32::from_ne_bytes([0x00, 0x00, 0x00, 0xFF]);
+
+        ppu.set_dmg_palette([0x000000; 4]);
+        ppu.write_byte(0xFF40, 0x91);
+        ppu.write_byte(0xFF47, 0xE4);
+
+        // Tile 0, used by the whole background, holds all four colours.
+        for i in 0..16 {
+            ppu.write_byte(0x8000 + i, if i % 2 == 0 { 0x33 } else { 0x0F });
+        }
+
+        run_to_line(&mut ppu, 143);
+
+        while ppu.ly != 144 {
+            ppu.tick(4);
+        }
+
+        for y in 0..144 {
+            for x in 0..160 {
+                assert_eq!(pixel(&ppu, x, y), black);
+            }
+        }
+    }
+
+    #[test]
+    fn tile_atlas_renders_the_vram_tiles() {
+        // Colour of the pixel at `x`, `y` of the atlas.
+        let atlas_pixel = |atlas: &[u8], x: usize, y: usize| {
+            let offset = (y * 128 + x) * 4;
+
+            u32::from_ne_bytes(atlas[offset..offset + 4].try_into().unwrap())
+        };
+
+        let (mut ppu, if_reg) = ppu();
+        ppu.write_byte(0xFF47, 0xE4);
+
+        // The first row of tile 17 holds colours 3, 3, 1, 1, 2, 2, 0, 0.
+        ppu.write_byte(0x8110, 0xF0);
+        ppu.write_byte(0x8111, 0xCC);
+
+        let framebuffer = ppu.back_framebuffer.clone();
+        let atlas = ppu.dump_tile_atlas(0).unwrap();
+
+        for (column, &colour) in [3, 3, 1, 1, 2, 2, 0, 0].iter().enumerate() {
+            assert_eq!(
+                atlas_pixel(&atlas[..], 8 + column, 8),
+                ppu.dmg_palette[colour]
+            );
+        }
+
+        assert_eq!(ppu.back_framebuffer, framebuffer);
+        assert!(ppu.dump_tile_atlas(2).is_none());
+
+        // The second bank of a CGB, drawn with a grayscale ramp.
+        let mut ppu = Ppu::new(if_reg, true);
+        ppu.write_byte(0xFF4F, 0x01);
+        ppu.write_byte(0x8000, 0x80);
+        ppu.write_byte(0x8001, 0x80);
+
+        let black = u32::from_le_bytes([0x00, 0x00, 0x00, 0xFF]);
+        let white = u32::from_le_bytes([0xFF, 0xFF, 0xFF, 0xFF]);
+
+        let atlas = ppu.dump_tile_atlas(1).unwrap();
+        assert_eq!(atlas_pixel(&atlas[..], 0, 0), black);
+        assert_eq!(atlas_pixel(&atlas[..], 1, 0), white);
+
+        let atlas = ppu.dump_tile_atlas(0).unwrap();
+        assert_eq!(atlas_pixel(&atlas[..], 0, 0), white);
+    }
+
+    #[test]
+    fn stat_interrupts_fire_on_the_rising_edge_of_the_line() {
+        let (mut ppu, if_reg) = ppu();
+
+        // Both the LY=LYC and the HBlank sources are enabled, the
+        // HBlank one turns on while LY=LYC still holds the line high.
+        ppu.write_byte(0xFF45, 10);
+        ppu.write_byte(0xFF41, 0x48);
+        ppu.write_byte(0xFF40, 0x91);
+
+        run_to_line(&mut ppu, 9);
+        *if_reg.borrow_mut() = 0;
+
+        let mut interrupts = Vec::new();
+
+        while ppu.ly != 11 {
+            ppu.tick(4);
+
+            if *if_reg.borrow() & 0x02 != 0 {
+                *if_reg.borrow_mut() = 0;
+                interrupts.push((ppu.ly, ppu.read_byte(0xFF41) & 0x03));
+            }
+        }
+
+        // One for the HBlank of line 9, one for LY=LYC on line 10.
+        assert_eq!(interrupts, [(9, 0), (10, 2)]);
+    }
+}
@@ -0,0 +1,99 @@
+//! Ring buffer of save states, used to rewind the emulation.
+
+use std::collections::VecDeque;
+
+/// The bytes that differ between two save states.
+struct Delta {
+    /// The length of the save state this delta restores.
+    len: usize,
+
+    /// Runs of bytes, and the offsets they start at.
+    runs: Vec<(usize, Vec<u8>)>,
+}
+
+impl Delta {
+    /// Compute the delta which turns `from` into `to`.
+    fn new(from: &[u8], to: &[u8]) -> Self {
+        let mut runs: Vec<(usize, Vec<u8>)> = Vec::new();
+
+        for (offset, &byte) in to.iter().enumerate() {
+            if from.get(offset) == Some(&byte) {
+                continue;
+            }
+
+            match runs.last_mut() {
+                Some((start, run)) if *start + run.len() == offset => run.push(byte),
+                _ => runs.push((offset, vec![byte])),
+            }
+        }
+
+        Self {
+            len: to.len(),
+            runs,
+        }
+    }
+
+    /// Apply the delta to the given save state.
+    fn apply(&self, state: &mut Vec<u8>) {
+        state.resize(self.len, 0);
+
+        for (offset, run) in &self.runs {
+            state[*offset..*offset + run.len()].copy_from_slice(run);
+        }
+    }
+}
+
+/// Keeps the most recent save state in full, and every older one as
+/// the delta against the state that came after it.
+pub(crate) struct RewindBuffer {
+    /// The maximum number of save states kept.
+    capacity: usize,
+
+    /// The most recent save state.
+    latest: Option<Vec<u8>>,
+
+    /// Deltas leading to the older save states, the oldest first.
+    deltas: VecDeque<Delta>,
+}
+
+impl RewindBuffer {
+    /// Create a new `RewindBuffer` instance.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            latest: None,
+            deltas: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// Record a save state, dropping the oldest one if the buffer is full.
+    pub fn push(&mut self, state: Vec<u8>) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        if let Some(latest) = self.latest.take() {
+            self.deltas.push_back(Delta::new(&state, &latest));
+
+            if self.deltas.len() >= self.capacity {
+                self.deltas.pop_front();
+            }
+        }
+
+        self.latest = Some(state);
+    }
+
+    /// Take out the most recent save state.
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let latest = self.latest.take()?;
+
+        if let Some(delta) = self.deltas.pop_back() {
+            let mut previous = latest.clone();
+            delta.apply(&mut previous);
+
+            self.latest = Some(previous);
+        }
+
+        Some(latest)
+    }
+}
@@ -0,0 +1,292 @@
+//! Versioned binary format used by save states.
+
+use std::{
+    error::Error,
+    fmt::{Display, Formatter, Result},
+};
+
+/// Magic number at the start of every save state.
+pub const SAVE_STATE_MAGIC: [u8; 4] = *b"AGSS";
+
+/// Version of the save state format, bumped whenever the
+/// layout of the serialized state changes.
+pub const SAVE_STATE_VERSION: u16 = 4;
+
+/// Errors that can occur while loading a save state.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SaveStateError {
+    /// The save state ended before all the data could be read.
+    Truncated,
+
+    /// The save state does not start with the magic number.
+    InvalidMagic,
+
+    /// The save state was produced by an incompatible version.
+    VersionMismatch { found: u16, expected: u16 },
+
+    /// The save state was made with a different ROM loaded.
+    RomMismatch,
+
+    /// The save state holds a value the emulator can never be in.
+    Corrupted,
+}
+
+impl Display for SaveStateError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            Self::Truncated => write!(f, "save state is truncated"),
+            Self::InvalidMagic => write!(f, "not an Argentum save state"),
+            Self::VersionMismatch { found, expected } => write!(
+                f,
+                "save state version {} is not supported, expected {}",
+                found, expected
+            ),
+            Self::RomMismatch => write!(f, "save state belongs to a different ROM"),
+            Self::Corrupted => write!(f, "save state is corrupted"),
+        }
+    }
+}
+
+impl Error for SaveStateError {}
+
+/// The CPU registers and memory contents needed to start
+/// execution in the middle of a program.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FullStateImage {
+    pub af: u16,
+    pub bc: u16,
+    pub de: u16,
+    pub hl: u16,
+    pub sp: u16,
+    pub pc: u16,
+
+    /// The Interrupt Master Enable flag.
+    pub ime: bool,
+
+    /// The IE register.
+    pub ie: u8,
+
+    /// The IF register.
+    pub interrupt_flags: u8,
+
+    /// All 8 banks of the work RAM, 32 KB.
+    pub wram: Vec<u8>,
+
+    /// Both banks of the video RAM, 16 KB.
+    pub vram: Vec<u8>,
+
+    /// The object attribute memory, 160 bytes.
+    pub oam: Vec<u8>,
+
+    /// The high RAM, 127 bytes.
+    pub hram: Vec<u8>,
+}
+
+impl FullStateImage {
+    /// Check that all the memories have the right size.
+    pub fn validate(&self) -> std::result::Result<(), SaveStateError> {
+        let sizes = [
+            (self.wram.len(), 0x8000),
+            (self.vram.len(), 0x4000),
+            (self.oam.len(), 0xA0),
+            (self.hram.len(), 0x7F),
+        ];
+
+        if sizes.iter().all(|&(len, expected)| len == expected) {
+            Ok(())
+        } else {
+            Err(SaveStateError::Truncated)
+        }
+    }
+}
+
+/// Reject a value read from a save state, unless it is `valid`.
+/// Out of range values would make the emulator misbehave or panic later.
+pub fn ensure(valid: bool) -> std::result::Result<(), SaveStateError> {
+    if valid {
+        Ok(())
+    } else {
+        Err(SaveStateError::Corrupted)
+    }
+}
+
+/// Check the header of a save state and return the data following it.
+pub fn read_header(data: &[u8]) -> std::result::Result<&[u8], SaveStateError> {
+    if data.len() < 6 {
+        return Err(SaveStateError::Truncated);
+    }
+
+    if data[0..4] != SAVE_STATE_MAGIC {
+        return Err(SaveStateError::InvalidMagic);
+    }
+
+    let version = u16::from_le_bytes([data[4], data[5]]);
+
+    if version != SAVE_STATE_VERSION {
+        return Err(SaveStateError::VersionMismatch {
+            found: version,
+            expected: SAVE_STATE_VERSION,
+        });
+    }
+
+    Ok(&data[6..])
+}
+
+/// Serializes the state of the emulator, in little endian.
+pub(crate) struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    /// Create a new `StateWriter`, with the header already written.
+    pub fn new() -> Self {
+        let mut data = SAVE_STATE_MAGIC.to_vec();
+        data.extend_from_slice(&SAVE_STATE_VERSION.to_le_bytes());
+
+        Self { data }
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.data.push(value as u8);
+    }
+
+    pub fn bytes(&mut self, value: &[u8]) {
+        self.data.extend_from_slice(value);
+    }
+
+    /// Get the serialized state.
+    pub fn finish(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+/// Deserializes the state written by a `StateWriter`.
+pub(crate) struct StateReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    /// Create a new `StateReader`, after checking the header.
+    pub fn new(data: &'a [u8]) -> std::result::Result<Self, SaveStateError> {
+        Ok(Self {
+            data: read_header(data)?,
+        })
+    }
+
+    /// Take the next `len` bytes of the state.
+    fn take(&mut self, len: usize) -> std::result::Result<&'a [u8], SaveStateError> {
+        if self.data.len() < len {
+            return Err(SaveStateError::Truncated);
+        }
+
+        let (taken, rest) = self.data.split_at(len);
+        self.data = rest;
+
+        Ok(taken)
+    }
+
+    pub fn u8(&mut self) -> std::result::Result<u8, SaveStateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> std::result::Result<u16, SaveStateError> {
+        let mut bytes = [0; 2];
+        self.bytes(&mut bytes)?;
+
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    pub fn u32(&mut self) -> std::result::Result<u32, SaveStateError> {
+        let mut bytes = [0; 4];
+        self.bytes(&mut bytes)?;
+
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub fn u64(&mut self) -> std::result::Result<u64, SaveStateError> {
+        let mut bytes = [0; 8];
+        self.bytes(&mut bytes)?;
+
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    pub fn bool(&mut self) -> std::result::Result<bool, SaveStateError> {
+        Ok(self.u8()? != 0)
+    }
+
+    /// Fill `buffer` with the next bytes of the state.
+    pub fn bytes(&mut self, buffer: &mut [u8]) -> std::result::Result<(), SaveStateError> {
+        buffer.copy_from_slice(self.take(buffer.len())?);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state() -> Vec<u8> {
+        let mut state = StateWriter::new();
+        state.u32(0xDEAD_BEEF);
+
+        state.finish()
+    }
+
+    #[test]
+    fn reads_back_written_state() {
+        let data = state();
+        let mut state = StateReader::new(&data).unwrap();
+
+        assert_eq!(state.u32(), Ok(0xDEAD_BEEF));
+        assert_eq!(state.u8(), Err(SaveStateError::Truncated));
+    }
+
+    #[test]
+    fn truncated_state() {
+        let data = state();
+
+        assert_eq!(read_header(&data[..3]), Err(SaveStateError::Truncated));
+
+        let mut state = StateReader::new(&data[..8]).unwrap();
+        assert_eq!(state.u32(), Err(SaveStateError::Truncated));
+    }
+
+    #[test]
+    fn wrong_magic() {
+        let mut data = state();
+        data[0] = b'X';
+
+        assert_eq!(read_header(&data), Err(SaveStateError::InvalidMagic));
+    }
+
+    #[test]
+    fn wrong_version() {
+        let mut data = state();
+        data[4..6].copy_from_slice(&(SAVE_STATE_VERSION + 1).to_le_bytes());
+
+        assert_eq!(
+            read_header(&data),
+            Err(SaveStateError::VersionMismatch {
+                found: SAVE_STATE_VERSION + 1,
+                expected: SAVE_STATE_VERSION,
+            })
+        );
+    }
+}
@@ -0,0 +1,88 @@
+use std::{cell::RefCell, rc::Rc};
+
+use crate::{
+    savestate::{SaveStateError, StateReader, StateWriter},
+    util::{bit, set},
+};
+
+pub struct Serial {
+    /// 0xFF01 - Serial Transfer Data.
+    sb: u8,
+
+    /// 0xFF02 - Serial Transfer Control.
+    sc: u8,
+
+    /// Callback which is called with every byte shifted out.
+    callback: Option<Box<dyn Fn(u8)>>,
+
+    /// Shared reference to IF register.
+    if_reg: Rc<RefCell<u8>>,
+}
+
+impl Serial {
+    /// Create a new `Serial` instance.
+    pub fn new(if_reg: Rc<RefCell<u8>>) -> Self {
+        Self {
+            sb: 0x00,
+            sc: 0x00,
+            callback: None,
+            if_reg,
+        }
+    }
+
+    /// Serialize the SB and SC registers.
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.sb);
+        state.u8(self.sc);
+    }
+
+    /// Restore the SB and SC registers.
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), SaveStateError> {
+        self.sb = state.u8()?;
+        self.sc = state.u8()?;
+
+        Ok(())
+    }
+
+    /// Set the callback which receives the bytes sent over the link port.
+    pub fn set_callback(&mut self, callback: Box<dyn Fn(u8)>) {
+        self.callback = Some(callback);
+    }
+
+    /// Read a byte from the specified address.
+    pub fn read_byte(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF01 => self.sb,
+            0xFF02 => self.sc | 0x7E,
+
+            _ => unreachable!(),
+        }
+    }
+
+    /// Write a byte to the specified address.
+    pub fn write_byte(&mut self, addr: u16, value: u8) {
+        match addr {
+            0xFF01 => self.sb = value,
+
+            0xFF02 => {
+                self.sc = value;
+
+                // Transfers using the internal clock complete instantly.
+                // There is never another Game Boy connected, so the
+                // received byte is always 0xFF.
+                if bit!(&self.sc, 7) && bit!(&self.sc, 0) {
+                    if let Some(callback) = &self.callback {
+                        callback(self.sb);
+                    }
+
+                    self.sb = 0xFF;
+                    self.sc &= 0x7F;
+
+                    set!(self.if_reg.borrow_mut(), 3);
+                }
+            }
+
+            _ => unreachable!(),
+        }
+    }
+}
@@ -0,0 +1,9 @@
+//! Helpers shared by the unit tests.
+
+/// Build a 32 KB ROM without a MBC, which runs `program` from the entry point.
+pub fn rom_with_program(program: &[u8]) -> Vec<u8> {
+    let mut rom = vec![0; 0x8000];
+
+    rom[0x100..0x100 + program.len()].copy_from_slice(program);
+    rom
+}
@@ -1,6 +1,9 @@
 use std::{cell::RefCell, hint::unreachable_unchecked, rc::Rc};
 
-use crate::util::set;
+use crate::{
+    savestate::{ensure, SaveStateError, StateReader, StateWriter},
+    util::set,
+};
 
 #[derive(Default)]
 pub(crate) struct Timer {
@@ -34,6 +37,11 @@ pub(crate) struct Timer {
     last_and_result: u8,
 
     /// The T-cycles remaining for TIMA reload to occur, if any.
+    ///
+    /// TIMA reads as 0x00 for the whole M-cycle following an overflow,
+    /// and only then is TMA loaded and the interrupt requested. `Some(0)`
+    /// marks the M-cycle in which the reload happened, when writes to
+    /// TIMA are ignored and writes to TMA go through to TIMA.
     tima_reload: Option<u8>,
 
     /// Shared reference to IF register.
@@ -49,6 +57,40 @@ impl Timer {
         }
     }
 
+    /// Serialize the state of the timer.
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.u16(self.div);
+        state.u8(self.tima);
+        state.u8(self.tma);
+        state.u8(self.tac);
+        state.u8(self.last_and_result);
+        state.bool(self.tima_reload.is_some());
+        state.u8(self.tima_reload.unwrap_or(0));
+    }
+
+    /// Restore the state of the timer.
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), SaveStateError> {
+        self.div = state.u16()?;
+        self.tima = state.u8()?;
+        self.tma = state.u8()?;
+        self.tac = state.u8()?;
+        self.last_and_result = state.u8()?;
+
+        let reloading = state.bool()?;
+        let reload = state.u8()?;
+        self.tima_reload = if reloading { Some(reload) } else { None };
+
+        // The reload is counted down 4 T-cycles at a time from 4.
+        ensure(reload == 0 || reload == 4)?;
+
+        Ok(())
+    }
+
+    /// Get the full 16-bit internal divider counter.
+    pub fn internal_div(&self) -> u16 {
+        self.div
+    }
+
     /// Tick the timers and divider by 4 T-cycles.
     pub fn tick(&mut self) {
         if let Some(ref mut cycles) = self.tima_reload {
@@ -134,3 +176,58 @@ impl Timer {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn internal_div_counts_t_cycles() {
+        let mut timer = Timer::new(Rc::new(RefCell::new(0)));
+
+        for _ in 0..1000 {
+            timer.tick();
+        }
+
+        assert_eq!(timer.internal_div(), 4000);
+        assert_eq!(timer.read_byte(0xFF04), (4000 >> 8) as u8);
+
+        timer.write_byte(0xFF04, 0x12);
+        timer.tick();
+        assert_eq!(timer.internal_div(), 4);
+    }
+
+    #[test]
+    fn tima_reads_zero_for_one_m_cycle_on_reload() {
+        let if_reg = Rc::new(RefCell::new(0));
+        let mut timer = Timer::new(if_reg.clone());
+
+        // Count every 16 T-cycles, overflowing after two increments.
+        timer.write_byte(0xFF06, 0xAB);
+        timer.write_byte(0xFF05, 0xFE);
+        timer.write_byte(0xFF07, 0x05);
+
+        let mut sequence = Vec::new();
+
+        for _ in 0..10 {
+            timer.tick();
+            sequence.push((timer.read_byte(0xFF05), *if_reg.borrow() & 0x04 != 0));
+        }
+
+        assert_eq!(
+            sequence,
+            [
+                (0xFE, false),
+                (0xFE, false),
+                (0xFE, false),
+                (0xFF, false),
+                (0xFF, false),
+                (0xFF, false),
+                (0xFF, false),
+                (0x00, false),
+                (0xAB, true),
+                (0xAB, true),
+            ]
+        );
+    }
+}